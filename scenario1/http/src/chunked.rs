@@ -0,0 +1,224 @@
+use std::str;
+
+use crate::headers::HeaderMap;
+use crate::parser::{next_line, parse_header, Limit, Limits, ParseError};
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+// Decoder for `Transfer-Encoding: chunked` bodies. Input is consumed from the
+// front of the buffer as far as it can be decoded; an incomplete chunk is
+// left in place until more bytes arrive. Trailers are held to the same
+// count as headers, and the whole trailer section to one header's size.
+#[derive(Debug)]
+pub struct ChunkedDecoder {
+    state: State,
+    max_line: usize,
+    max_body: usize,
+    max_trailers: usize,
+    trailer_bytes: usize,
+    trailers: HeaderMap,
+}
+
+impl ChunkedDecoder {
+    pub fn new(limits: &Limits) -> Self {
+        ChunkedDecoder {
+            state: State::Size,
+            max_line: limits.max_header_size,
+            max_body: limits.max_body_size,
+            max_trailers: limits.max_headers,
+            trailer_bytes: 0,
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    // Returns `true` once the last chunk and the trailer section are decoded.
+    pub fn decode(&mut self, input: &mut Vec<u8>, body: &mut Vec<u8>) -> Result<bool, ParseError> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let Some(line) = self.line(input)? else {
+                        return Ok(false);
+                    };
+                    let size = parse_chunk_size(&line)?;
+                    if size > self.max_body.saturating_sub(body.len()) {
+                        return Err(ParseError::TooLarge(Limit::Body));
+                    }
+                    self.state = if size == 0 {
+                        State::Trailers
+                    } else {
+                        State::Data(size)
+                    };
+                }
+                State::Data(remaining) => {
+                    if input.is_empty() {
+                        return Ok(false);
+                    }
+                    let take = remaining.min(input.len());
+                    body.extend(input.drain(..take));
+                    self.state = match remaining - take {
+                        0 => State::DataEnd,
+                        left => State::Data(left),
+                    };
+                }
+                State::DataEnd => {
+                    let Some(line) = self.line(input)? else {
+                        return Ok(false);
+                    };
+                    if !line.is_empty() {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    self.state = State::Size;
+                }
+                State::Trailers => {
+                    let Some(line) = self.line(input)? else {
+                        return Ok(false);
+                    };
+                    if line.is_empty() {
+                        self.state = State::Done;
+                        continue;
+                    }
+                    if self.trailers.len() >= self.max_trailers {
+                        return Err(ParseError::TooLarge(Limit::HeaderCount));
+                    }
+                    self.trailer_bytes += line.len();
+                    if self.trailer_bytes > self.max_line {
+                        return Err(ParseError::TooLarge(Limit::HeaderSize));
+                    }
+                    let line = str::from_utf8(&line).map_err(|_| ParseError::InvalidUtf8)?;
+                    let (key, value) = parse_header(line)?;
+                    self.trailers
+                        .try_append(key, value)
+                        .map_err(|_| ParseError::MalformedHeader)?;
+                }
+                State::Done => return Ok(true),
+            }
+        }
+    }
+
+    pub fn into_trailers(self) -> HeaderMap {
+        self.trailers
+    }
+
+    fn line(&self, input: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ParseError> {
+        next_line(input, self.max_line).map_err(|_| match self.state {
+            State::Trailers => ParseError::TooLarge(Limit::HeaderSize),
+            _ => ParseError::InvalidChunk,
+        })
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+    // Chunk extensions are allowed after the size and ignored.
+    let size = match line.iter().position(|&b| b == b';') {
+        Some(end) => &line[..end],
+        None => line,
+    };
+    let size = str::from_utf8(size)
+        .map_err(|_| ParseError::InvalidChunk)?
+        .trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidChunk);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| ParseError::InvalidChunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            max_header_size: 1024,
+            max_body_size: 1024,
+            ..Limits::default()
+        }
+    }
+
+    fn decode_all(input: &[u8]) -> Result<(Vec<u8>, HeaderMap), ParseError> {
+        let mut decoder = ChunkedDecoder::new(&limits());
+        let mut input = input.to_vec();
+        let mut body = Vec::new();
+        assert!(decoder.decode(&mut input, &mut body)?);
+        Ok((body, decoder.into_trailers()))
+    }
+
+    #[test]
+    fn test_decode_chunks() {
+        let (body, trailers) = decode_all(
+            b"4\r\nWiki\r\n6;name=value\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(b"Wikipedia in \r\n\r\nchunks.".to_vec(), body);
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn test_decode_trailers() {
+        let (body, trailers) =
+            decode_all(b"3\r\nabc\r\n0\r\nExpires: never\r\nX-Checksum: 42\r\n\r\n").unwrap();
+        assert_eq!(b"abc".to_vec(), body);
+        assert_eq!(Some("never"), trailers.get("Expires"));
+        assert_eq!(Some("42"), trailers.get("x-checksum"));
+    }
+
+    #[test]
+    fn test_decode_incrementally() {
+        let mut decoder = ChunkedDecoder::new(&limits());
+        let mut input = Vec::new();
+        let mut body = Vec::new();
+        for byte in b"5\r\nhello\r\n0\r\n" {
+            input.push(*byte);
+            assert!(!decoder.decode(&mut input, &mut body).unwrap());
+        }
+        input.extend_from_slice(b"\r\nGET");
+        assert!(decoder.decode(&mut input, &mut body).unwrap());
+        assert_eq!(b"hello".to_vec(), body);
+        assert_eq!(b"GET".to_vec(), input);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            ParseError::InvalidChunk,
+            decode_all(b"zz\r\nabc\r\n0\r\n\r\n").unwrap_err()
+        );
+        assert_eq!(
+            ParseError::InvalidChunk,
+            decode_all(b"3\r\nabcd\r\n0\r\n\r\n").unwrap_err()
+        );
+        assert_eq!(
+            ParseError::TooLarge(Limit::Body),
+            decode_all(b"FFFF\r\n").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_decode_trailer_limits() {
+        let limits = Limits {
+            max_headers: 2,
+            max_header_size: 32,
+            ..Limits::default()
+        };
+
+        let mut decoder = ChunkedDecoder::new(&limits);
+        let mut input = b"0\r\nA: 1\r\nB: 2\r\nC: 3\r\n".to_vec();
+        assert_eq!(
+            ParseError::TooLarge(Limit::HeaderCount),
+            decoder.decode(&mut input, &mut Vec::new()).unwrap_err()
+        );
+
+        let mut decoder = ChunkedDecoder::new(&limits);
+        let mut input = b"0\r\nX-One: 0123456789\r\nX-Two: 0123456789\r\n".to_vec();
+        assert_eq!(
+            ParseError::TooLarge(Limit::HeaderSize),
+            decoder.decode(&mut input, &mut Vec::new()).unwrap_err()
+        );
+    }
+}
@@ -0,0 +1,1379 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::mem;
+
+use crate::headers::HeaderMap;
+use crate::hpack::{Decoder, Encoder, HpackError};
+use crate::httprequest::{HttpRequest, Method, Version};
+use crate::httpresponse::HttpResponse;
+use crate::parser::Limits;
+use crate::status::StatusCode;
+use crate::uri::Resource;
+
+// What a client sends first on an HTTP/2 connection, see RFC 9113 3.4.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Headers tied to an HTTP/1 connection, which HTTP/2 messages must not carry.
+pub(crate) const CONNECTION_SPECIFIC: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const FRAME_HEADER_LEN: usize = 9;
+// The smallest maximum frame size, which is also the one we accept.
+const MIN_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_MAX_FRAME_SIZE: usize = (1 << 24) - 1;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+// How far streams the client resets may outnumber the responses completed
+// before the connection is closed with ENHANCE_YOUR_CALM.
+const MAX_RESETS: usize = 2 * MAX_CONCURRENT_STREAMS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError,
+    Protocol,
+    Internal,
+    FlowControl,
+    SettingsTimeout,
+    StreamClosed,
+    FrameSize,
+    RefusedStream,
+    Cancel,
+    Compression,
+    Connect,
+    EnhanceYourCalm,
+    InadequateSecurity,
+    Http11Required,
+    Unknown(u32),
+}
+
+impl ErrorCode {
+    fn as_u32(self) -> u32 {
+        match self {
+            ErrorCode::NoError => 0x0,
+            ErrorCode::Protocol => 0x1,
+            ErrorCode::Internal => 0x2,
+            ErrorCode::FlowControl => 0x3,
+            ErrorCode::SettingsTimeout => 0x4,
+            ErrorCode::StreamClosed => 0x5,
+            ErrorCode::FrameSize => 0x6,
+            ErrorCode::RefusedStream => 0x7,
+            ErrorCode::Cancel => 0x8,
+            ErrorCode::Compression => 0x9,
+            ErrorCode::Connect => 0xa,
+            ErrorCode::EnhanceYourCalm => 0xb,
+            ErrorCode::InadequateSecurity => 0xc,
+            ErrorCode::Http11Required => 0xd,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::NoError => "NO_ERROR",
+            ErrorCode::Protocol => "PROTOCOL_ERROR",
+            ErrorCode::Internal => "INTERNAL_ERROR",
+            ErrorCode::FlowControl => "FLOW_CONTROL_ERROR",
+            ErrorCode::SettingsTimeout => "SETTINGS_TIMEOUT",
+            ErrorCode::StreamClosed => "STREAM_CLOSED",
+            ErrorCode::FrameSize => "FRAME_SIZE_ERROR",
+            ErrorCode::RefusedStream => "REFUSED_STREAM",
+            ErrorCode::Cancel => "CANCEL",
+            ErrorCode::Compression => "COMPRESSION_ERROR",
+            ErrorCode::Connect => "CONNECT_ERROR",
+            ErrorCode::EnhanceYourCalm => "ENHANCE_YOUR_CALM",
+            ErrorCode::InadequateSecurity => "INADEQUATE_SECURITY",
+            ErrorCode::Http11Required => "HTTP_1_1_REQUIRED",
+            ErrorCode::Unknown(code) => return write!(f, "error code {:#x}", code),
+        };
+        f.write_str(name)
+    }
+}
+
+// An error that ends the whole connection. The GOAWAY telling the client
+// is already queued when `feed` returns it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionError {
+    pub code: ErrorCode,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP/2 {}: {}", self.code, self.reason)
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+fn error(code: ErrorCode, reason: &'static str) -> ConnectionError {
+    ConnectionError { code, reason }
+}
+
+// Why a request's header block can't become an `HttpRequest`.
+enum InvalidRequest {
+    Malformed,
+    TooLarge,
+}
+
+#[derive(Debug)]
+struct Stream {
+    // Built when the headers arrive, handed out once the body is complete.
+    request: Option<HttpRequest>,
+    // END_STREAM received.
+    remote_closed: bool,
+    recv_window: i64,
+    send_window: i64,
+    // The response body and how much of it has gone out.
+    body: Vec<u8>,
+    sent: usize,
+    // END_STREAM sent.
+    local_closed: bool,
+}
+
+impl Stream {
+    fn new(send_window: i64) -> Self {
+        Stream {
+            request: None,
+            remote_closed: false,
+            recv_window: DEFAULT_WINDOW,
+            send_window,
+            body: Vec::new(),
+            sent: 0,
+            local_closed: false,
+        }
+    }
+}
+
+// A header block split over HEADERS and CONTINUATION frames.
+struct PartialBlock {
+    stream_id: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+// The server side of an HTTP/2 connection without any I/O: bytes read from
+// the client go into `feed`, complete requests come out of `next_request`,
+// responses go into `send_response` and `take_output` returns what to write
+// back. Request bodies use the default 64 KiB windows, replenished as they
+// are consumed; responses respect the windows the client grants.
+pub struct ServerConnection {
+    limits: Limits,
+    decoder: Decoder,
+    encoder: Encoder,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    preface_received: bool,
+    settings_received: bool,
+    streams: BTreeMap<u32, Stream>,
+    ready: VecDeque<(u32, HttpRequest)>,
+    // Streams handed out by `next_request` whose response hasn't come back.
+    // They count against the stream limit even once the client resets them,
+    // as their handlers are still running.
+    handling: BTreeSet<u32>,
+    // Streams the client reset before their response was complete, less
+    // the responses completed since.
+    resets: usize,
+    continuation: Option<PartialBlock>,
+    // The highest stream the client has opened.
+    last_stream_id: u32,
+    recv_window: i64,
+    send_window: i64,
+    peer_initial_window: i64,
+    peer_max_frame_size: usize,
+    // The last stream our GOAWAY promised to serve.
+    going_away: Option<u32>,
+    peer_going_away: bool,
+    failed: bool,
+}
+
+impl ServerConnection {
+    pub fn new(limits: Limits) -> Self {
+        let mut connection = ServerConnection {
+            limits,
+            decoder: Decoder::default(),
+            encoder: Encoder::default(),
+            input: Vec::new(),
+            output: Vec::new(),
+            preface_received: false,
+            settings_received: false,
+            streams: BTreeMap::new(),
+            ready: VecDeque::new(),
+            handling: BTreeSet::new(),
+            resets: 0,
+            continuation: None,
+            last_stream_id: 0,
+            recv_window: DEFAULT_WINDOW,
+            send_window: DEFAULT_WINDOW,
+            peer_initial_window: DEFAULT_WINDOW,
+            peer_max_frame_size: MIN_MAX_FRAME_SIZE,
+            going_away: None,
+            peer_going_away: false,
+            failed: false,
+        };
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                connection.max_header_list_size() as u32,
+            ),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        connection.write_frame(SETTINGS, 0, 0, &settings);
+        connection
+    }
+
+    // Takes over from HTTP/1.1 after `Upgrade: h2c` (RFC 7540 3.2). `settings`
+    // is the request's HTTP2-Settings header and `req` becomes stream 1, whose
+    // response goes out over HTTP/2. The client still sends the preface.
+    pub fn upgrade(
+        limits: Limits,
+        settings: &str,
+        mut req: HttpRequest,
+    ) -> Result<Self, ConnectionError> {
+        let mut connection = ServerConnection::new(limits);
+        let settings = base64url_decode(settings)
+            .filter(|settings| settings.len().is_multiple_of(6))
+            .ok_or(error(ErrorCode::Protocol, "invalid HTTP2-Settings"))?;
+        connection.apply_settings(&settings)?;
+
+        for name in ["Connection", "Upgrade", "HTTP2-Settings"] {
+            req.headers.remove(name);
+        }
+        req.version = Version::V2_0;
+        let mut stream = Stream::new(connection.peer_initial_window);
+        stream.remote_closed = true;
+        connection.streams.insert(1, stream);
+        connection.ready.push_back((1, req));
+        connection.last_stream_id = 1;
+        Ok(connection)
+    }
+
+    // Processes whatever complete frames `data` finishes. After an error the
+    // connection only has its GOAWAY left to write.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), ConnectionError> {
+        if self.failed {
+            return Ok(());
+        }
+        self.input.extend_from_slice(data);
+        self.process().inspect_err(|e| {
+            let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+            payload.extend_from_slice(&e.code.as_u32().to_be_bytes());
+            payload.extend_from_slice(e.reason.as_bytes());
+            self.write_frame(GOAWAY, 0, 0, &payload);
+            self.failed = true;
+            self.input.clear();
+        })
+    }
+
+    // The next request whose headers and body have both arrived, with the
+    // stream its response goes to.
+    pub fn next_request(&mut self) -> Option<(u32, HttpRequest)> {
+        let (id, req) = self.ready.pop_front()?;
+        self.handling.insert(id);
+        Some((id, req))
+    }
+
+    // Queues `response` on stream `id`; a stream the client has since reset
+    // is skipped. The body goes out through `take_output` as windows allow.
+    pub fn send_response(&mut self, id: u32, response: &HttpResponse, head_only: bool) {
+        self.handling.remove(&id);
+        if self.failed || !self.streams.contains_key(&id) {
+            return;
+        }
+        let mut fields = vec![(
+            ":status".to_string(),
+            response.status().as_u16().to_string(),
+        )];
+        fields.extend(response.h2_fields());
+        let mut block = Vec::new();
+        self.encoder.encode(
+            fields.iter().map(|(n, v)| (n.as_str(), v.as_str())),
+            &mut block,
+        );
+
+        let body = if head_only || !response.allows_body() {
+            Vec::new()
+        } else {
+            response.body().to_vec()
+        };
+        let end_stream = body.is_empty();
+        self.write_headers(id, &block, end_stream);
+        let stream = self.streams.get_mut(&id).expect("stream exists");
+        stream.body = body;
+        stream.local_closed = end_stream;
+        self.close_if_done(id);
+    }
+
+    // Everything queued for the client so far, response bodies included as
+    // far as flow control allows.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.send_data();
+        mem::take(&mut self.output)
+    }
+
+    // Stops accepting streams, letting the ones already open finish.
+    pub fn go_away(&mut self) {
+        if self.going_away.is_some() || self.failed {
+            return;
+        }
+        self.going_away = Some(self.last_stream_id);
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&ErrorCode::NoError.as_u32().to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload);
+    }
+
+    // No stream is open and none is waiting for a response.
+    pub fn is_idle(&self) -> bool {
+        self.streams.is_empty() && self.ready.is_empty() && self.continuation.is_none()
+    }
+
+    // Nothing is left to do but write the remaining output and close.
+    pub fn is_done(&self) -> bool {
+        self.failed || ((self.going_away.is_some() || self.peer_going_away) && self.is_idle())
+    }
+
+    // Response bodies waiting on the client's flow-control windows.
+    pub fn has_pending_data(&self) -> bool {
+        self.streams
+            .values()
+            .any(|stream| stream.sent < stream.body.len())
+    }
+
+    fn max_header_list_size(&self) -> usize {
+        self.limits.max_request_line + self.limits.max_headers * self.limits.max_header_size
+    }
+
+    fn process(&mut self) -> Result<(), ConnectionError> {
+        if !self.preface_received {
+            let len = self.input.len().min(PREFACE.len());
+            if self.input[..len] != PREFACE[..len] {
+                return Err(error(ErrorCode::Protocol, "invalid connection preface"));
+            }
+            if len < PREFACE.len() {
+                return Ok(());
+            }
+            self.input.drain(..PREFACE.len());
+            self.preface_received = true;
+        }
+
+        let mut offset = 0;
+        let result = loop {
+            let input = &self.input[offset..];
+            if input.len() < FRAME_HEADER_LEN {
+                break Ok(());
+            }
+            let len = u32::from_be_bytes([0, input[0], input[1], input[2]]) as usize;
+            let (kind, flags) = (input[3], input[4]);
+            let id = u32::from_be_bytes([input[5], input[6], input[7], input[8]]) & 0x7fff_ffff;
+            if len > MIN_MAX_FRAME_SIZE {
+                break Err(error(
+                    ErrorCode::FrameSize,
+                    "frame larger than SETTINGS_MAX_FRAME_SIZE",
+                ));
+            }
+            if input.len() < FRAME_HEADER_LEN + len {
+                break Ok(());
+            }
+            let payload = input[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+            offset += FRAME_HEADER_LEN + len;
+            if let Err(e) = self.frame(kind, flags, id, payload) {
+                break Err(e);
+            }
+        };
+        self.input.drain(..offset);
+        result
+    }
+
+    fn frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        id: u32,
+        payload: Vec<u8>,
+    ) -> Result<(), ConnectionError> {
+        if !self.settings_received && kind != SETTINGS {
+            return Err(error(
+                ErrorCode::Protocol,
+                "expected SETTINGS after the preface",
+            ));
+        }
+        if let Some(partial) = &self.continuation {
+            if kind != CONTINUATION || id != partial.stream_id {
+                return Err(error(ErrorCode::Protocol, "expected CONTINUATION"));
+            }
+        }
+        match kind {
+            DATA => self.on_data(flags, id, payload),
+            HEADERS => self.on_headers(flags, id, payload),
+            PRIORITY => {
+                if id == 0 {
+                    return Err(error(ErrorCode::Protocol, "PRIORITY on stream 0"));
+                }
+                if payload.len() != 5 {
+                    self.reset(id, ErrorCode::FrameSize);
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if id == 0 || id > self.last_stream_id {
+                    return Err(error(ErrorCode::Protocol, "RST_STREAM on an idle stream"));
+                }
+                if payload.len() != 4 {
+                    return Err(error(ErrorCode::FrameSize, "invalid RST_STREAM"));
+                }
+                self.ready.retain(|(ready, _)| *ready != id);
+                if self.streams.remove(&id).is_none() {
+                    return Ok(());
+                }
+                self.resets += 1;
+                if self.resets > MAX_RESETS {
+                    return Err(error(ErrorCode::EnhanceYourCalm, "too many stream resets"));
+                }
+                Ok(())
+            }
+            SETTINGS => self.on_settings(flags, id, &payload),
+            PUSH_PROMISE => Err(error(ErrorCode::Protocol, "PUSH_PROMISE from a client")),
+            PING => {
+                if id != 0 {
+                    return Err(error(ErrorCode::Protocol, "PING on a stream"));
+                }
+                if payload.len() != 8 {
+                    return Err(error(ErrorCode::FrameSize, "invalid PING"));
+                }
+                if flags & ACK == 0 {
+                    self.write_frame(PING, ACK, 0, &payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if id != 0 {
+                    return Err(error(ErrorCode::Protocol, "GOAWAY on a stream"));
+                }
+                self.peer_going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(id, &payload),
+            CONTINUATION => self.on_continuation(flags, id, payload),
+            // Unknown frame types are ignored.
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, flags: u8, id: u32, payload: Vec<u8>) -> Result<(), ConnectionError> {
+        if id == 0 {
+            return Err(error(ErrorCode::Protocol, "DATA on stream 0"));
+        }
+        // Padding counts against the windows too.
+        let len = payload.len() as i64;
+        if len > self.recv_window {
+            return Err(error(
+                ErrorCode::FlowControl,
+                "DATA beyond the connection window",
+            ));
+        }
+        self.recv_window -= len;
+        if self.recv_window < DEFAULT_WINDOW / 2 {
+            let increment = (DEFAULT_WINDOW - self.recv_window) as u32;
+            self.write_frame(WINDOW_UPDATE, 0, 0, &increment.to_be_bytes());
+            self.recv_window = DEFAULT_WINDOW;
+        }
+        let data = strip_padding(flags, &payload)?;
+
+        let Some(stream) = self.streams.get_mut(&id) else {
+            if id > self.last_stream_id {
+                return Err(error(ErrorCode::Protocol, "DATA on an idle stream"));
+            }
+            // A stream we reset or finished; frames already in flight are
+            // dropped.
+            return Ok(());
+        };
+        if stream.remote_closed {
+            self.reset(id, ErrorCode::StreamClosed);
+            return Ok(());
+        }
+        if len > stream.recv_window {
+            self.reset(id, ErrorCode::FlowControl);
+            return Ok(());
+        }
+        stream.recv_window -= len;
+        let request = stream.request.as_mut().expect("open stream has a request");
+        request.body.extend_from_slice(data);
+        if request.body.len() > self.limits.max_body_size {
+            self.reject(id, StatusCode::CONTENT_TOO_LARGE);
+            return Ok(());
+        }
+        if flags & END_STREAM != 0 {
+            self.finish_request(id);
+        } else if stream.recv_window < DEFAULT_WINDOW / 2 {
+            let increment = (DEFAULT_WINDOW - stream.recv_window) as u32;
+            stream.recv_window = DEFAULT_WINDOW;
+            self.write_frame(WINDOW_UPDATE, 0, id, &increment.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, flags: u8, id: u32, payload: Vec<u8>) -> Result<(), ConnectionError> {
+        if id == 0 {
+            return Err(error(ErrorCode::Protocol, "HEADERS on stream 0"));
+        }
+        let mut block = strip_padding(flags, &payload)?;
+        if flags & PRIORITY_FLAG != 0 {
+            if block.len() < 5 {
+                return Err(error(ErrorCode::FrameSize, "invalid HEADERS priority"));
+            }
+            block = &block[5..];
+        }
+        let partial = PartialBlock {
+            stream_id: id,
+            end_stream: flags & END_STREAM != 0,
+            block: block.to_vec(),
+        };
+        if flags & END_HEADERS == 0 {
+            self.continuation = Some(partial);
+            return Ok(());
+        }
+        self.on_header_block(partial)
+    }
+
+    fn on_continuation(
+        &mut self,
+        flags: u8,
+        id: u32,
+        payload: Vec<u8>,
+    ) -> Result<(), ConnectionError> {
+        let Some(partial) = self.continuation.as_mut() else {
+            return Err(error(ErrorCode::Protocol, "unexpected CONTINUATION"));
+        };
+        debug_assert_eq!(partial.stream_id, id);
+        partial.block.extend_from_slice(&payload);
+        if partial.block.len() > self.max_header_list_size() {
+            return Err(error(ErrorCode::EnhanceYourCalm, "header block too large"));
+        }
+        if flags & END_HEADERS != 0 {
+            let partial = self.continuation.take().expect("continuation in progress");
+            return self.on_header_block(partial);
+        }
+        Ok(())
+    }
+
+    fn on_header_block(&mut self, partial: PartialBlock) -> Result<(), ConnectionError> {
+        let PartialBlock {
+            stream_id: id,
+            end_stream,
+            block,
+        } = partial;
+        // Every block updates the decoder's table, even on streams that are
+        // then ignored.
+        let fields = self
+            .decoder
+            .decode(&block, self.max_header_list_size())
+            .map_err(|e| match e {
+                HpackError::HeaderListTooLarge => {
+                    error(ErrorCode::EnhanceYourCalm, "header list too large")
+                }
+                _ => error(ErrorCode::Compression, "invalid header block"),
+            })?;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if stream.remote_closed {
+                self.reset(id, ErrorCode::StreamClosed);
+                return Ok(());
+            }
+            if !end_stream {
+                self.reset(id, ErrorCode::Protocol);
+                return Ok(());
+            }
+            let request = stream.request.as_mut().expect("open stream has a request");
+            match trailers(fields, &self.limits) {
+                Ok(trailers) => request.trailers = trailers,
+                Err(InvalidRequest::Malformed) => {
+                    self.reset(id, ErrorCode::Protocol);
+                    return Ok(());
+                }
+                Err(InvalidRequest::TooLarge) => {
+                    self.reject(id, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                    return Ok(());
+                }
+            }
+            self.finish_request(id);
+            return Ok(());
+        }
+
+        if id <= self.last_stream_id {
+            // Trailers racing our reset of the stream.
+            return Ok(());
+        }
+        if id.is_multiple_of(2) {
+            return Err(error(ErrorCode::Protocol, "client opened an even stream"));
+        }
+        self.last_stream_id = id;
+        if self.going_away.is_some_and(|last| id > last) {
+            return Ok(());
+        }
+        if self.active_streams() >= MAX_CONCURRENT_STREAMS {
+            self.reset(id, ErrorCode::RefusedStream);
+            return Ok(());
+        }
+
+        let mut stream = Stream::new(self.peer_initial_window);
+        match request(fields, &self.limits) {
+            Ok(request) => stream.request = Some(request),
+            Err(InvalidRequest::Malformed) => {
+                self.reset(id, ErrorCode::Protocol);
+                return Ok(());
+            }
+            Err(InvalidRequest::TooLarge) => {
+                self.streams.insert(id, stream);
+                self.reject(id, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                return Ok(());
+            }
+        }
+        self.streams.insert(id, stream);
+        if end_stream {
+            self.finish_request(id);
+        }
+        Ok(())
+    }
+
+    fn on_settings(&mut self, flags: u8, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id != 0 {
+            return Err(error(ErrorCode::Protocol, "SETTINGS on a stream"));
+        }
+        if flags & ACK != 0 {
+            if !payload.is_empty() {
+                return Err(error(ErrorCode::FrameSize, "SETTINGS ACK with a payload"));
+            }
+            return Ok(());
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(error(ErrorCode::FrameSize, "invalid SETTINGS"));
+        }
+        self.apply_settings(payload)?;
+        self.settings_received = true;
+        self.write_frame(SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), ConnectionError> {
+        for setting in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_HEADER_TABLE_SIZE => self.encoder.set_max_table_size(value as usize),
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(error(ErrorCode::Protocol, "invalid SETTINGS_ENABLE_PUSH"));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(error(
+                            ErrorCode::FlowControl,
+                            "invalid SETTINGS_INITIAL_WINDOW_SIZE",
+                        ));
+                    }
+                    // Open streams' windows move by the difference.
+                    let delta = value - self.peer_initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(error(ErrorCode::FlowControl, "stream window overflow"));
+                        }
+                    }
+                    self.peer_initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(MIN_MAX_FRAME_SIZE..=MAX_MAX_FRAME_SIZE).contains(&value) {
+                        return Err(error(
+                            ErrorCode::Protocol,
+                            "invalid SETTINGS_MAX_FRAME_SIZE",
+                        ));
+                    }
+                    self.peer_max_frame_size = value;
+                }
+                // Push is never used; the remaining settings limit what the
+                // client accepts from us and unknown ones are ignored.
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if payload.len() != 4 {
+            return Err(error(ErrorCode::FrameSize, "invalid WINDOW_UPDATE"));
+        }
+        let increment = (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+            & 0x7fff_ffff) as i64;
+        if id == 0 {
+            if increment == 0 {
+                return Err(error(ErrorCode::Protocol, "zero WINDOW_UPDATE"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(error(ErrorCode::FlowControl, "connection window overflow"));
+            }
+            return Ok(());
+        }
+        let Some(stream) = self.streams.get_mut(&id) else {
+            if id > self.last_stream_id {
+                return Err(error(
+                    ErrorCode::Protocol,
+                    "WINDOW_UPDATE on an idle stream",
+                ));
+            }
+            return Ok(());
+        };
+        if increment == 0 {
+            self.reset(id, ErrorCode::Protocol);
+            return Ok(());
+        }
+        stream.send_window += increment;
+        if stream.send_window > MAX_WINDOW {
+            self.reset(id, ErrorCode::FlowControl);
+        }
+        Ok(())
+    }
+
+    // The request on `id` is complete once its Content-Length, if any,
+    // matches the body received.
+    fn finish_request(&mut self, id: u32) {
+        let stream = self.streams.get_mut(&id).expect("stream exists");
+        stream.remote_closed = true;
+        let request = stream.request.take().expect("open stream has a request");
+        let length_matches = match request.headers.get("content-length") {
+            None => true,
+            Some(length) => length.parse::<usize>().ok() == Some(request.body.len()),
+        };
+        if !length_matches {
+            self.reset(id, ErrorCode::Protocol);
+            return;
+        }
+        self.ready.push_back((id, request));
+    }
+
+    // Answers with `status` before the request is complete, then stops the
+    // client sending the rest of it.
+    fn reject(&mut self, id: u32, status: StatusCode) {
+        let response = HttpResponse::new(status, None, None);
+        self.send_response(id, &response, false);
+    }
+
+    // Open streams plus the reset ones whose handlers haven't returned.
+    fn active_streams(&self) -> usize {
+        let orphaned = self
+            .handling
+            .iter()
+            .filter(|id| !self.streams.contains_key(id))
+            .count();
+        self.streams.len() + orphaned
+    }
+
+    fn reset(&mut self, id: u32, code: ErrorCode) {
+        self.streams.remove(&id);
+        self.write_frame(RST_STREAM, 0, id, &code.as_u32().to_be_bytes());
+    }
+
+    // Forgets a stream once both sides have ended it. A response completed
+    // before the request also resets the stream, so the client stops.
+    fn close_if_done(&mut self, id: u32) {
+        let Some(stream) = self.streams.get(&id) else {
+            return;
+        };
+        if !stream.local_closed {
+            return;
+        }
+        if stream.remote_closed {
+            self.streams.remove(&id);
+            self.resets = self.resets.saturating_sub(1);
+        } else {
+            self.reset(id, ErrorCode::NoError);
+        }
+    }
+
+    // Writes DATA frames a round at a time, one frame per stream and round,
+    // so a large response doesn't hold up the others.
+    fn send_data(&mut self) {
+        loop {
+            let mut progressed = false;
+            let ids: Vec<u32> = self.streams.keys().copied().collect();
+            for id in ids {
+                if self.send_window <= 0 {
+                    return;
+                }
+                let stream = self.streams.get_mut(&id).expect("stream exists");
+                let remaining = stream.body.len() - stream.sent;
+                if remaining == 0 || stream.local_closed || stream.send_window <= 0 {
+                    continue;
+                }
+                let len = remaining
+                    .min(self.peer_max_frame_size)
+                    .min(stream.send_window as usize)
+                    .min(self.send_window as usize);
+                let chunk = stream.body[stream.sent..stream.sent + len].to_vec();
+                stream.sent += len;
+                stream.send_window -= len as i64;
+                self.send_window -= len as i64;
+                let end_stream = stream.sent == stream.body.len();
+                if end_stream {
+                    stream.local_closed = true;
+                }
+                let flags = if end_stream { END_STREAM } else { 0 };
+                self.write_frame(DATA, flags, id, &chunk);
+                if end_stream {
+                    self.close_if_done(id);
+                }
+                progressed = true;
+            }
+            if !progressed {
+                return;
+            }
+        }
+    }
+
+    fn write_headers(&mut self, id: u32, block: &[u8], end_stream: bool) {
+        let mut chunks = block.chunks(self.peer_max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        loop {
+            let chunk = chunks.next().unwrap_or_default();
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, id, chunk);
+            if flags & END_HEADERS != 0 {
+                return;
+            }
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+        self.output
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.output.extend_from_slice(&[kind, flags]);
+        self.output.extend_from_slice(&id.to_be_bytes());
+        self.output.extend_from_slice(payload);
+    }
+}
+
+// The HTTP2-Settings header of a request asking to switch to h2c, or `None`
+// for any other request.
+pub fn h2c_upgrade(req: &HttpRequest) -> Option<&str> {
+    let upgrade = req
+        .headers
+        .get_all("Upgrade")
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"));
+    let mut settings = req.headers.get_all("HTTP2-Settings");
+    match (upgrade, settings.next(), settings.next()) {
+        (true, Some(settings), None) => Some(settings),
+        _ => None,
+    }
+}
+
+fn strip_padding(flags: u8, payload: &[u8]) -> Result<&[u8], ConnectionError> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    match payload.split_first() {
+        Some((&padding, rest)) if (padding as usize) <= rest.len() => {
+            Ok(&rest[..rest.len() - padding as usize])
+        }
+        _ => Err(error(ErrorCode::Protocol, "invalid padding")),
+    }
+}
+
+// Builds a request from its HEADERS, checking the rules of RFC 9113 8.2 and
+// 8.3. `:authority` stands in for Host and cookies are joined back up.
+fn request(
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+    limits: &Limits,
+) -> Result<HttpRequest, InvalidRequest> {
+    let (mut method, mut scheme, mut authority, mut path) = (None, None, None, None);
+    let mut headers = HeaderMap::new();
+    let mut cookies = Vec::new();
+    let mut regular_seen = false;
+
+    for (name, value) in fields {
+        let name = String::from_utf8(name).map_err(|_| InvalidRequest::Malformed)?;
+        let value = String::from_utf8(value).map_err(|_| InvalidRequest::Malformed)?;
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                "path" => &mut path,
+                _ => return Err(InvalidRequest::Malformed),
+            };
+            if regular_seen || slot.is_some() {
+                return Err(InvalidRequest::Malformed);
+            }
+            *slot = Some(value);
+            continue;
+        }
+        regular_seen = true;
+        check_field(&name, &value)?;
+        if name == "cookie" {
+            cookies.push(value);
+            continue;
+        }
+        if headers.len() >= limits.max_headers {
+            return Err(InvalidRequest::TooLarge);
+        }
+        headers
+            .try_append(name, value)
+            .map_err(|_| InvalidRequest::Malformed)?;
+    }
+
+    let method = Method::from(method.ok_or(InvalidRequest::Malformed)?.as_str());
+    // CONNECT has neither :scheme nor :path, which this server doesn't serve.
+    let (Some(_), Some(path)) = (scheme, path) else {
+        return Err(InvalidRequest::Malformed);
+    };
+    if path.is_empty() {
+        return Err(InvalidRequest::Malformed);
+    }
+    if path.len() > limits.max_request_line {
+        return Err(InvalidRequest::TooLarge);
+    }
+    let resource = Resource::parse(&path).map_err(|_| InvalidRequest::Malformed)?;
+    if let Some(authority) = authority {
+        if !headers.contains("host") {
+            headers
+                .try_insert("host", authority)
+                .map_err(|_| InvalidRequest::Malformed)?;
+        }
+    }
+    if !cookies.is_empty() {
+        headers
+            .try_append("cookie", cookies.join("; "))
+            .map_err(|_| InvalidRequest::Malformed)?;
+    }
+
+    Ok(HttpRequest {
+        method,
+        version: Version::V2_0,
+        resource,
+        headers,
+        trailers: HeaderMap::new(),
+        body: Vec::new(),
+    })
+}
+
+fn trailers(fields: Vec<(Vec<u8>, Vec<u8>)>, limits: &Limits) -> Result<HeaderMap, InvalidRequest> {
+    let mut trailers = HeaderMap::new();
+    for (name, value) in fields {
+        let name = String::from_utf8(name).map_err(|_| InvalidRequest::Malformed)?;
+        let value = String::from_utf8(value).map_err(|_| InvalidRequest::Malformed)?;
+        if name.starts_with(':') {
+            return Err(InvalidRequest::Malformed);
+        }
+        check_field(&name, &value)?;
+        if trailers.len() >= limits.max_headers {
+            return Err(InvalidRequest::TooLarge);
+        }
+        trailers
+            .try_append(name, value)
+            .map_err(|_| InvalidRequest::Malformed)?;
+    }
+    Ok(trailers)
+}
+
+fn check_field(name: &str, value: &str) -> Result<(), InvalidRequest> {
+    let malformed = name.bytes().any(|b| b.is_ascii_uppercase())
+        || CONNECTION_SPECIFIC.contains(&name)
+        || (name == "te" && value != "trailers");
+    if malformed {
+        return Err(InvalidRequest::Malformed);
+    }
+    Ok(())
+}
+
+// Unpadded base64url, as HTTP2-Settings is encoded.
+fn base64url_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in input.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            output.push((bits >> count) as u8);
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Frame = (u8, u8, u32, Vec<u8>);
+
+    fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.extend_from_slice(&[kind, flags]);
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn frames(mut bytes: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            let len = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize;
+            let id = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+            frames.push((bytes[3], bytes[4], id, bytes[9..9 + len].to_vec()));
+            bytes = &bytes[9 + len..];
+        }
+        frames
+    }
+
+    // A connection past the preface and SETTINGS exchange.
+    fn open(settings: &[u8]) -> ServerConnection {
+        let mut connection = ServerConnection::new(Limits::default());
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, settings));
+        connection.feed(&input).unwrap();
+        connection.take_output();
+        connection
+    }
+
+    fn headers(encoder: &mut Encoder, fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        encoder.encode(fields.iter().copied(), &mut block);
+        block
+    }
+
+    fn get(encoder: &mut Encoder, path: &str) -> Vec<u8> {
+        let fields = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":authority", "localhost"),
+            (":path", path),
+        ];
+        headers(encoder, &fields)
+    }
+
+    fn response_fields(decoder: &mut Decoder, block: &[u8]) -> Vec<(String, String)> {
+        decoder
+            .decode(block, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|(n, v)| (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_settings_exchange() {
+        let mut connection = ServerConnection::new(Limits::default());
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, &[]));
+        input.extend(frame(PING, 0, 0, b"12345678"));
+        connection.feed(&input).unwrap();
+        let output = frames(&connection.take_output());
+        assert_eq!(SETTINGS, output[0].0);
+        assert_eq!(0, output[0].1);
+        assert_eq!((SETTINGS, ACK, 0, vec![]), output[1]);
+        assert_eq!((PING, ACK, 0, b"12345678".to_vec()), output[2]);
+    }
+
+    #[test]
+    fn test_request_and_response() {
+        let mut connection = open(&[]);
+        let mut encoder = Encoder::default();
+        let block = get(&mut encoder, "/orders?id=1");
+        connection
+            .feed(&frame(HEADERS, END_HEADERS | END_STREAM, 1, &block))
+            .unwrap();
+        let (id, req) = connection.next_request().unwrap();
+        assert_eq!(1, id);
+        assert_eq!(Method::Get, req.method);
+        assert_eq!(Version::V2_0, req.version);
+        assert_eq!("/orders", req.path());
+        assert_eq!(Some("1"), req.query().get("id"));
+        assert_eq!(Some("localhost"), req.headers.host());
+
+        let mut response = HttpResponse::new(StatusCode::OK, None, Some(b"hello".to_vec()));
+        response.headers_mut().insert("Connection", "close");
+        connection.send_response(1, &response, false);
+        let output = frames(&connection.take_output());
+        assert_eq!(2, output.len());
+        assert_eq!(
+            (HEADERS, END_HEADERS, 1),
+            (output[0].0, output[0].1, output[0].2)
+        );
+        let fields = response_fields(&mut Decoder::default(), &output[0].3);
+        assert_eq!((":status".to_string(), "200".to_string()), fields[0]);
+        assert!(fields.contains(&("content-length".into(), "5".into())));
+        assert!(!fields.iter().any(|(name, _)| name == "connection"));
+        assert_eq!((DATA, END_STREAM, 1, b"hello".to_vec()), output[1]);
+        assert!(connection.is_idle());
+    }
+
+    #[test]
+    fn test_body_continuation_and_trailers() {
+        let mut connection = open(&[]);
+        let mut encoder = Encoder::default();
+        let block = headers(
+            &mut encoder,
+            &[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", "/orders"),
+                ("cookie", "a=1"),
+                ("cookie", "b=2"),
+                ("content-length", "6"),
+            ],
+        );
+        let (first, rest) = block.split_at(3);
+        let mut input = frame(HEADERS, 0, 1, first);
+        input.extend(frame(CONTINUATION, END_HEADERS, 1, rest));
+        input.extend(frame(DATA, PADDED, 1, b"\x02abc\0\0"));
+        input.extend(frame(DATA, 0, 1, b"def"));
+        let trailers = headers(&mut encoder, &[("checksum", "x")]);
+        input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 1, &trailers));
+        connection.feed(&input).unwrap();
+
+        let (_, req) = connection.next_request().unwrap();
+        assert_eq!(b"abcdef".to_vec(), req.body);
+        assert_eq!(Some("a=1; b=2"), req.headers.get("cookie"));
+        assert_eq!(Some("x"), req.trailers.get("checksum"));
+    }
+
+    #[test]
+    fn test_flow_control_and_multiplexing() {
+        // The client only grants 4 bytes per stream until it says otherwise.
+        let mut connection = open(&[0, 4, 0, 0, 0, 4]);
+        let mut encoder = Encoder::default();
+        for id in [1, 3] {
+            let block = get(&mut encoder, "/");
+            connection
+                .feed(&frame(HEADERS, END_HEADERS | END_STREAM, id, &block))
+                .unwrap();
+        }
+        let (first, _) = connection.next_request().unwrap();
+        let (second, _) = connection.next_request().unwrap();
+        let body = Some(b"abcdefgh".to_vec());
+        connection.send_response(
+            second,
+            &HttpResponse::new(StatusCode::OK, None, body.clone()),
+            false,
+        );
+        connection.send_response(first, &HttpResponse::new(StatusCode::OK, None, body), false);
+
+        let data: Vec<Frame> = frames(&connection.take_output())
+            .into_iter()
+            .filter(|frame| frame.0 == DATA)
+            .collect();
+        assert_eq!(
+            vec![
+                (DATA, 0, 1, b"abcd".to_vec()),
+                (DATA, 0, 3, b"abcd".to_vec())
+            ],
+            data
+        );
+        assert!(connection.has_pending_data());
+
+        connection
+            .feed(&frame(WINDOW_UPDATE, 0, 3, &10u32.to_be_bytes()))
+            .unwrap();
+        assert_eq!(
+            vec![(DATA, END_STREAM, 3, b"efgh".to_vec())],
+            frames(&connection.take_output())
+        );
+        assert!(!connection.is_idle());
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let mut connection = open(&[]);
+        let mut encoder = Encoder::default();
+        let malformed = headers(&mut encoder, &[(":method", "GET"), (":path", "/")]);
+        connection
+            .feed(&frame(HEADERS, END_HEADERS | END_STREAM, 1, &malformed))
+            .unwrap();
+        let length = headers(
+            &mut encoder,
+            &[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", "/"),
+                ("content-length", "3"),
+            ],
+        );
+        let mut input = frame(HEADERS, END_HEADERS, 3, &length);
+        input.extend(frame(DATA, END_STREAM, 3, b"ab"));
+        connection.feed(&input).unwrap();
+
+        let code = ErrorCode::Protocol.as_u32().to_be_bytes().to_vec();
+        assert_eq!(
+            vec![(RST_STREAM, 0, 1, code.clone()), (RST_STREAM, 0, 3, code)],
+            frames(&connection.take_output())
+        );
+        assert!(connection.next_request().is_none());
+        // The connection itself carries on.
+        let block = get(&mut encoder, "/");
+        connection
+            .feed(&frame(HEADERS, END_HEADERS | END_STREAM, 5, &block))
+            .unwrap();
+        assert_eq!(5, connection.next_request().unwrap().0);
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        let mut connection = ServerConnection::new(Limits::default());
+        let e = connection.feed(b"GET / HTTP/1.1\r\n\r\n").unwrap_err();
+        assert_eq!(ErrorCode::Protocol, e.code);
+
+        let mut connection = open(&[]);
+        let e = connection.feed(&frame(DATA, 0, 0, b"x")).unwrap_err();
+        assert_eq!(ErrorCode::Protocol, e.code);
+        let output = frames(&connection.take_output());
+        assert_eq!(GOAWAY, output[0].0);
+        assert_eq!(1, u32::from_be_bytes(output[0].3[4..8].try_into().unwrap()));
+        assert!(connection.is_done());
+
+        let mut connection = open(&[]);
+        let e = connection
+            .feed(&frame(WINDOW_UPDATE, 0, 0, &0x7fff_ffffu32.to_be_bytes()))
+            .unwrap_err();
+        assert_eq!(ErrorCode::FlowControl, e.code);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let req = HttpRequest::try_from(
+            "GET /health HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n",
+        )
+        .unwrap();
+        let settings = h2c_upgrade(&req).unwrap().to_string();
+        let mut connection = ServerConnection::upgrade(Limits::default(), &settings, req).unwrap();
+        let (id, req) = connection.next_request().unwrap();
+        assert_eq!(1, id);
+        assert_eq!(Version::V2_0, req.version);
+        assert!(!req.headers.contains("Upgrade"));
+
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, &[]));
+        connection.feed(&input).unwrap();
+        connection.send_response(1, &HttpResponse::new(StatusCode::OK, None, None), false);
+        let output = frames(&connection.take_output());
+        assert_eq!(
+            vec![SETTINGS, SETTINGS, HEADERS],
+            output.iter().map(|frame| frame.0).collect::<Vec<_>>()
+        );
+        assert!(connection.is_idle());
+
+        let plain = HttpRequest::try_from("GET / HTTP/1.1\r\nUpgrade: h2c\r\n\r\n").unwrap();
+        assert_eq!(None, h2c_upgrade(&plain));
+    }
+
+    #[test]
+    fn test_rapid_reset() {
+        let mut connection = open(&[]);
+        let mut encoder = Encoder::default();
+        let cancel = ErrorCode::Cancel.as_u32().to_be_bytes();
+        let mut id = 1;
+        let mut open_and_reset = |connection: &mut ServerConnection| {
+            let block = get(&mut encoder, "/");
+            connection
+                .feed(&frame(HEADERS, END_HEADERS | END_STREAM, id, &block))
+                .unwrap();
+            let handed_out = connection.next_request().map(|(id, _)| id);
+            let reset = connection.feed(&frame(RST_STREAM, 0, id, &cancel));
+            id += 2;
+            (handed_out, reset)
+        };
+
+        // Handlers still running on reset streams keep their place.
+        for _ in 0..MAX_CONCURRENT_STREAMS {
+            let (handed_out, reset) = open_and_reset(&mut connection);
+            assert!(handed_out.is_some());
+            reset.unwrap();
+        }
+        connection.take_output();
+        let (handed_out, _) = open_and_reset(&mut connection);
+        assert_eq!(None, handed_out);
+        let refused = ErrorCode::RefusedStream.as_u32().to_be_bytes().to_vec();
+        assert_eq!(
+            vec![(RST_STREAM, 0, 201, refused)],
+            frames(&connection.take_output())
+        );
+
+        // Their responses free the places up again.
+        for id in (1..201).step_by(2) {
+            let response = HttpResponse::new(StatusCode::OK, None, None);
+            connection.send_response(id, &response, false);
+        }
+        assert!(connection.take_output().is_empty());
+        let mut result = Ok(());
+        for _ in MAX_CONCURRENT_STREAMS..MAX_RESETS {
+            let (handed_out, reset) = open_and_reset(&mut connection);
+            result = reset;
+            let response = HttpResponse::new(StatusCode::OK, None, None);
+            connection.send_response(handed_out.unwrap(), &response, false);
+        }
+        result.unwrap();
+
+        // A client that keeps resetting is told to calm down.
+        let (_, reset) = open_and_reset(&mut connection);
+        assert_eq!(ErrorCode::EnhanceYourCalm, reset.unwrap_err().code);
+        let output = frames(&connection.take_output());
+        let go_away = output.last().unwrap();
+        assert_eq!(GOAWAY, go_away.0);
+        assert_eq!(
+            ErrorCode::EnhanceYourCalm.as_u32(),
+            u32::from_be_bytes(go_away.3[4..8].try_into().unwrap())
+        );
+        assert!(connection.is_done());
+    }
+}
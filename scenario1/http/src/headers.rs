@@ -0,0 +1,299 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvalidHeader {
+    Name,
+    Value,
+}
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidHeader::Name => f.write_str("invalid header name"),
+            InvalidHeader::Value => f.write_str("invalid header value"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityItem {
+    pub value: String,
+    pub quality: f32,
+}
+
+// Header fields in the order they were added. Names compare
+// case-insensitively and a name may carry several values.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        HeaderMap::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // Replaces every value of `name`. Panics on an invalid name or value,
+    // use `try_insert` for input that comes from the network.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.try_insert(name, value).expect("invalid header")
+    }
+
+    // Adds a value to `name`, keeping the values it already has. Panics on
+    // an invalid name or value, use `try_append` for untrusted input.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.try_append(name, value).expect("invalid header")
+    }
+
+    pub fn try_insert(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), InvalidHeader> {
+        let (name, value) = validate(name.into(), value.into())?;
+        match self
+            .entries
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(&name))
+        {
+            Some(index) => {
+                let mut position = 0;
+                self.entries.retain(|(key, _)| {
+                    let keep = position == index || !key.eq_ignore_ascii_case(&name);
+                    position += 1;
+                    keep
+                });
+                self.entries[index] = (name, value);
+            }
+            None => self.entries.push((name, value)),
+        }
+        Ok(())
+    }
+
+    pub fn try_append(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), InvalidHeader> {
+        let (name, value) = validate(name.into(), value.into())?;
+        self.entries.push((name, value));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.entries.retain_mut(|(key, value)| {
+            if !key.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if removed.is_none() {
+                removed = Some(std::mem::take(value));
+            }
+            false
+        });
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")
+    }
+
+    // The media type without parameters, lowercased, e.g. `application/json`
+    // for `Application/JSON; charset=utf-8`.
+    pub fn mime_type(&self) -> Option<String> {
+        self.content_type()
+            .and_then(|value| value.split(';').next())
+            .map(|mime| mime.trim().to_ascii_lowercase())
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        let value = self.get("Content-Length")?.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        value.parse().ok()
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.get("Host")
+    }
+
+    pub fn accept(&self) -> Vec<QualityItem> {
+        self.quality_values("Accept")
+    }
+
+    // Parses a comma separated list with optional `q` weights, as used by the
+    // Accept family of headers. Items are ordered by descending quality and
+    // keep their listed order otherwise.
+    pub fn quality_values(&self, name: &str) -> Vec<QualityItem> {
+        let mut items: Vec<QualityItem> = self
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .filter_map(|item| {
+                let mut params = item.split(';').map(|param| param.trim());
+                let value = params.next().filter(|value| !value.is_empty())?;
+                let quality = params
+                    .filter_map(|param| param.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                    .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+                Some(QualityItem {
+                    value: value.to_string(),
+                    quality: quality.clamp(0.0, 1.0),
+                })
+            })
+            .collect();
+        items.sort_by(|a, b| b.quality.total_cmp(&a.quality));
+        items
+    }
+}
+
+impl PartialEq for HeaderMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries.len() == other.entries.len()
+            && self
+                .entries
+                .iter()
+                .zip(&other.entries)
+                .all(|((k1, v1), (k2, v2))| k1.eq_ignore_ascii_case(k2) && v1 == v2)
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut headers = HeaderMap::new();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+impl<K: Into<String>, V: Into<String>, const N: usize> From<[(K, V); N]> for HeaderMap {
+    fn from(entries: [(K, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+pub(crate) fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn validate(name: String, value: String) -> Result<(String, String), InvalidHeader> {
+    if name.is_empty() || !name.bytes().all(is_token_char) {
+        return Err(InvalidHeader::Name);
+    }
+    // Visible characters, spaces and tabs; anything else could be used to
+    // smuggle extra lines into the message.
+    if value.bytes().any(|b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return Err(InvalidHeader::Value);
+    }
+    Ok((name, value.trim_matches([' ', '\t']).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_case_insensitive_lookup() {
+        let headers = HeaderMap::from([("Content-Type", "text/html")]);
+        assert_eq!(Some("text/html"), headers.get("content-type"));
+        assert_eq!(Some("text/html"), headers.get("CONTENT-TYPE"));
+        assert!(headers.contains("Content-type"));
+        assert_eq!(None, headers.get("Content-Length"));
+    }
+
+    #[test]
+    fn test_multiple_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Content-Type", "text/plain");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(
+            vec!["a=1", "b=2"],
+            headers.get_all("Set-Cookie").collect::<Vec<_>>()
+        );
+        assert_eq!(3, headers.len());
+
+        headers.insert("SET-COOKIE", "c=3");
+        assert_eq!(
+            vec!["c=3"],
+            headers.get_all("set-cookie").collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![("SET-COOKIE", "c=3"), ("Content-Type", "text/plain")],
+            headers.iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(Some("c=3".to_string()), headers.remove("Set-Cookie"));
+        assert!(!headers.contains("Set-Cookie"));
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            Err(InvalidHeader::Name),
+            headers.try_append("Bad Name", "x")
+        );
+        assert_eq!(Err(InvalidHeader::Name), headers.try_append("", "x"));
+        assert_eq!(
+            Err(InvalidHeader::Value),
+            headers.try_append("Location", "/\r\nSet-Cookie: evil=1")
+        );
+        assert_eq!(Ok(()), headers.try_append("X-Value", "  a: b\tc  "));
+        assert_eq!(Some("a: b\tc"), headers.get("x-value"));
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let headers = HeaderMap::from([
+            ("Host", "localhost:3000"),
+            ("Content-Type", "Application/JSON; charset=utf-8"),
+            ("Content-Length", "42"),
+            ("Accept", "text/html;q=0.8, application/json, */*;q=0.1"),
+        ]);
+        assert_eq!(Some("localhost:3000"), headers.host());
+        assert_eq!(Some("application/json".to_string()), headers.mime_type());
+        assert_eq!(Some(42), headers.content_length());
+        let accept: Vec<String> = headers.accept().into_iter().map(|i| i.value).collect();
+        assert_eq!(vec!["application/json", "text/html", "*/*"], accept);
+
+        let headers = HeaderMap::from([("Content-Length", "+4")]);
+        assert_eq!(None, headers.content_length());
+    }
+}
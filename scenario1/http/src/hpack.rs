@@ -0,0 +1,888 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+// HPACK (RFC 7541), the header compression of HTTP/2. Both ends keep a
+// table of recently sent fields so repeated ones cost a byte or two.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HpackError {
+    Truncated,
+    IntegerOverflow,
+    InvalidIndex,
+    InvalidHuffman,
+    InvalidTableSize,
+    HeaderListTooLarge,
+}
+
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            HpackError::Truncated => "truncated header block",
+            HpackError::IntegerOverflow => "integer overflow in header block",
+            HpackError::InvalidIndex => "invalid header table index",
+            HpackError::InvalidHuffman => "invalid Huffman-coded string",
+            HpackError::InvalidTableSize => "invalid header table size update",
+            HpackError::HeaderListTooLarge => "header list too large",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for HpackError {}
+
+// The size both ends start with and the most this implementation keeps.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+type Field = (Vec<u8>, Vec<u8>);
+
+// Turns header blocks back into fields. One per connection, fed every block
+// in the order they arrived.
+#[derive(Debug)]
+pub struct Decoder {
+    table: Table,
+    // What SETTINGS_HEADER_TABLE_SIZE allows the peer's encoder to use.
+    max_table_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            table: Table::new(DEFAULT_TABLE_SIZE),
+            max_table_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    // Fails once the fields add up to more than `max_list_size`, counted as
+    // in SETTINGS_MAX_HEADER_LIST_SIZE.
+    pub fn decode(&mut self, block: &[u8], max_list_size: usize) -> Result<Vec<Field>, HpackError> {
+        let mut input = block;
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        while let Some(&first) = input.first() {
+            let field = if first & 0x80 != 0 {
+                let index = decode_integer(&mut input, 7)?;
+                self.table.get(index)?
+            } else if first & 0xc0 == 0x40 {
+                let field = self.literal(&mut input, 6)?;
+                self.table.insert(field.clone());
+                field
+            } else if first & 0xe0 == 0x20 {
+                // Size updates only come before the first field.
+                let size = decode_integer(&mut input, 5)?;
+                if !fields.is_empty() || size > self.max_table_size {
+                    return Err(HpackError::InvalidTableSize);
+                }
+                self.table.set_max_size(size);
+                continue;
+            } else {
+                self.literal(&mut input, 4)?
+            };
+            list_size += field.0.len() + field.1.len() + 32;
+            if list_size > max_list_size {
+                return Err(HpackError::HeaderListTooLarge);
+            }
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    // A field whose name is either indexed or follows as a string.
+    fn literal(&self, input: &mut &[u8], prefix: u8) -> Result<Field, HpackError> {
+        let index = decode_integer(input, prefix)?;
+        let name = match index {
+            0 => decode_string(input)?,
+            index => self.table.get(index)?.0,
+        };
+        Ok((name, decode_string(input)?))
+    }
+}
+
+// Turns fields into header blocks for the peer's `Decoder`.
+#[derive(Debug)]
+pub struct Encoder {
+    table: Table,
+    // Announced at the start of the next block after the peer's
+    // SETTINGS_HEADER_TABLE_SIZE changes.
+    size_update: Option<usize>,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Encoder {
+            table: Table::new(DEFAULT_TABLE_SIZE),
+            size_update: None,
+        }
+    }
+}
+
+impl Encoder {
+    // Applies the peer's SETTINGS_HEADER_TABLE_SIZE, keeping to the default
+    // when it allows more.
+    pub fn set_max_table_size(&mut self, size: usize) {
+        let size = size.min(DEFAULT_TABLE_SIZE);
+        if size != self.table.max_size {
+            self.table.set_max_size(size);
+            self.size_update = Some(size);
+        }
+    }
+
+    // Names must already be lowercase, as HTTP/2 requires. Values that may
+    // be secret, cookies and credentials, never enter the table.
+    pub fn encode<'a>(
+        &mut self,
+        fields: impl IntoIterator<Item = (&'a str, &'a str)>,
+        out: &mut Vec<u8>,
+    ) {
+        if let Some(size) = self.size_update.take() {
+            encode_integer(size, 5, 0x20, out);
+        }
+        for (name, value) in fields {
+            let (name, value) = (name.as_bytes(), value.as_bytes());
+            let (exact, named) = self.table.find(name, value);
+            if let Some(index) = exact {
+                encode_integer(index, 7, 0x80, out);
+                continue;
+            }
+            let sensitive = matches!(name, b"authorization" | b"cookie" | b"set-cookie");
+            let (prefix, flags) = if sensitive { (4, 0x10) } else { (6, 0x40) };
+            match named {
+                Some(index) => encode_integer(index, prefix, flags, out),
+                None => {
+                    encode_integer(0, prefix, flags, out);
+                    encode_string(name, out);
+                }
+            }
+            encode_string(value, out);
+            if !sensitive {
+                self.table.insert((name.to_vec(), value.to_vec()));
+            }
+        }
+    }
+}
+
+// The static table followed by the dynamic one, newest entry first.
+#[derive(Debug)]
+struct Table {
+    entries: VecDeque<Field>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Table {
+    fn new(max_size: usize) -> Self {
+        Table {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn get(&self, index: usize) -> Result<Field, HpackError> {
+        if index == 0 {
+            return Err(HpackError::InvalidIndex);
+        }
+        if let Some(&(name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.entries
+            .get(index - STATIC_TABLE.len() - 1)
+            .cloned()
+            .ok_or(HpackError::InvalidIndex)
+    }
+
+    // The index of an entry with both `name` and `value` and of the first
+    // one with `name`.
+    fn find(&self, name: &[u8], value: &[u8]) -> (Option<usize>, Option<usize>) {
+        let statics = STATIC_TABLE
+            .iter()
+            .map(|&(n, v)| (n.as_bytes(), v.as_bytes()));
+        let dynamics = self.entries.iter().map(|(n, v)| (&n[..], &v[..]));
+        let mut named = None;
+        for (i, (n, v)) in statics.chain(dynamics).enumerate() {
+            if n == name {
+                if v == value {
+                    return (Some(i + 1), named.or(Some(i + 1)));
+                }
+                named = named.or(Some(i + 1));
+            }
+        }
+        (None, named)
+    }
+
+    // An entry larger than the whole table empties it.
+    fn insert(&mut self, field: Field) {
+        let size = entry_size(&field);
+        self.size += size;
+        self.entries.push_front(field);
+        self.evict();
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some(field) = self.entries.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&field);
+        }
+    }
+}
+
+fn entry_size((name, value): &Field) -> usize {
+    name.len() + value.len() + 32
+}
+
+// An integer in the low `prefix` bits of the first byte, continued in 7-bit
+// groups while it doesn't fit.
+fn decode_integer(input: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (&first, mut rest) = input.split_first().ok_or(HpackError::Truncated)?;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&byte, tail) = rest.split_first().ok_or(HpackError::Truncated)?;
+            rest = tail;
+            if shift > 28 {
+                return Err(HpackError::IntegerOverflow);
+            }
+            value += (byte as usize & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *input = rest;
+    Ok(value)
+}
+
+fn encode_integer(value: usize, prefix: u8, flags: u8, out: &mut Vec<u8>) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        out.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+fn decode_string(input: &mut &[u8]) -> Result<Vec<u8>, HpackError> {
+    let huffman = input.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_integer(input, 7)?;
+    if len > input.len() {
+        return Err(HpackError::Truncated);
+    }
+    let (data, rest) = input.split_at(len);
+    *input = rest;
+    if huffman {
+        huffman_decode(data)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+// Huffman-coded when that is shorter.
+fn encode_string(data: &[u8], out: &mut Vec<u8>) {
+    let bits: usize = data.iter().map(|&b| HUFFMAN[b as usize].1 as usize).sum();
+    let coded_len = bits.div_ceil(8);
+    if coded_len < data.len() {
+        encode_integer(coded_len, 7, 0x80, out);
+        huffman_encode(data, out);
+    } else {
+        encode_integer(data.len(), 7, 0, out);
+        out.extend_from_slice(data);
+    }
+}
+
+fn huffman_encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut bits: u64 = 0;
+    let mut pending = 0;
+    for &byte in data {
+        let (code, len) = HUFFMAN[byte as usize];
+        bits = (bits << len) | code as u64;
+        pending += len as u32;
+        while pending >= 8 {
+            pending -= 8;
+            out.push((bits >> pending) as u8);
+        }
+    }
+    // Padded with the most significant bits of EOS, all ones.
+    if pending > 0 {
+        out.push(((bits << (8 - pending)) as u8) | (0xff >> pending));
+    }
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let mut node = 0;
+    // Bits since the last symbol, and whether they were all ones.
+    let mut depth = 0;
+    let mut ones = true;
+    for &byte in data {
+        for shift in (0..8).rev() {
+            let bit = (byte >> shift) & 1;
+            depth += 1;
+            ones &= bit == 1;
+            match tree[node][bit as usize] {
+                Node::Branch(next) => node = next,
+                Node::Leaf(256) | Node::Empty => return Err(HpackError::InvalidHuffman),
+                Node::Leaf(symbol) => {
+                    decoded.push(symbol as u8);
+                    node = 0;
+                    depth = 0;
+                    ones = true;
+                }
+            }
+        }
+    }
+    // What is left must be padding: fewer than eight bits, all ones.
+    if depth > 7 || !ones {
+        return Err(HpackError::InvalidHuffman);
+    }
+    Ok(decoded)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Empty,
+    Branch(usize),
+    Leaf(u16),
+}
+
+fn huffman_tree() -> &'static [[Node; 2]] {
+    static TREE: OnceLock<Vec<[Node; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[Node::Empty; 2]];
+        for (symbol, &(code, len)) in HUFFMAN.iter().enumerate() {
+            let mut node = 0;
+            for shift in (0..len).rev() {
+                let bit = ((code >> shift) & 1) as usize;
+                if shift == 0 {
+                    tree[node][bit] = Node::Leaf(symbol as u16);
+                } else if let Node::Branch(next) = tree[node][bit] {
+                    node = next;
+                } else {
+                    tree.push([Node::Empty; 2]);
+                    tree[node][bit] = Node::Branch(tree.len() - 1);
+                    node = tree.len() - 1;
+                }
+            }
+        }
+        tree
+    })
+}
+
+// RFC 7541 appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// RFC 7541 appendix B: the code and its length in bits for every byte,
+// then EOS.
+const HUFFMAN: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn fields(decoded: Vec<Field>) -> Vec<(String, String)> {
+        decoded
+            .into_iter()
+            .map(|(n, v)| (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        // RFC 7541 C.1.
+        let mut out = Vec::new();
+        encode_integer(10, 5, 0, &mut out);
+        encode_integer(1337, 5, 0, &mut out);
+        encode_integer(42, 8, 0, &mut out);
+        assert_eq!(vec![10, 31, 154, 10, 42], out);
+
+        let mut input = &out[..];
+        assert_eq!(Ok(10), decode_integer(&mut input, 5));
+        assert_eq!(Ok(1337), decode_integer(&mut input, 5));
+        assert_eq!(Ok(42), decode_integer(&mut input, 8));
+        assert!(input.is_empty());
+
+        assert_eq!(
+            Err(HpackError::Truncated),
+            decode_integer(&mut &[31, 154][..], 5)
+        );
+        let huge = [31, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(
+            Err(HpackError::IntegerOverflow),
+            decode_integer(&mut &huge[..], 5)
+        );
+    }
+
+    #[test]
+    fn test_huffman() {
+        // RFC 7541 C.4.1.
+        let mut out = Vec::new();
+        huffman_encode(b"www.example.com", &mut out);
+        assert_eq!(hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff"), out);
+        assert_eq!(Ok(b"www.example.com".to_vec()), huffman_decode(&out));
+
+        let all: Vec<u8> = (0..=255).collect();
+        let mut out = Vec::new();
+        huffman_encode(&all, &mut out);
+        assert_eq!(Ok(all), huffman_decode(&out));
+
+        // Padding longer than seven bits, padding with a zero and EOS.
+        assert!(huffman_decode(&hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff ff")).is_err());
+        assert!(huffman_decode(&hex("f1e3 c2e5 f23a 6ba0 ab90 f4fe")).is_err());
+        assert!(huffman_decode(&hex("ffff fffc")).is_err());
+    }
+
+    #[test]
+    fn test_decode_requests() {
+        // RFC 7541 C.4, three requests on one connection with Huffman coding.
+        let mut decoder = Decoder::default();
+        let first = decoder
+            .decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"), 8192)
+            .unwrap();
+        assert_eq!(
+            vec![
+                (":method".into(), "GET".into()),
+                (":scheme".into(), "http".into()),
+                (":path".into(), "/".into()),
+                (":authority".into(), "www.example.com".into()),
+            ],
+            fields(first)
+        );
+        assert_eq!(57, decoder.table.size);
+
+        let second = decoder
+            .decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), 8192)
+            .unwrap();
+        assert_eq!(
+            ("cache-control".into(), "no-cache".into()),
+            fields(second)[4]
+        );
+
+        let third = decoder
+            .decode(
+                &hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+                8192,
+            )
+            .unwrap();
+        assert_eq!(
+            vec![
+                (":method".into(), "GET".into()),
+                (":scheme".into(), "https".into()),
+                (":path".into(), "/index.html".into()),
+                (":authority".into(), "www.example.com".into()),
+                ("custom-key".into(), "custom-value".into()),
+            ],
+            fields(third)
+        );
+        assert_eq!(164, decoder.table.size);
+
+        assert_eq!(
+            Err(HpackError::InvalidIndex),
+            decoder.decode(&[0xff, 0x00], 8192)
+        );
+        assert_eq!(
+            Err(HpackError::HeaderListTooLarge),
+            Decoder::default().decode(&hex("8286 84"), 80)
+        );
+        // A size update after a field, and one above the limit.
+        assert_eq!(
+            Err(HpackError::InvalidTableSize),
+            decoder.decode(&hex("82 20"), 8192)
+        );
+        assert_eq!(
+            Err(HpackError::InvalidTableSize),
+            decoder.decode(&hex("3fe2 1f"), 8192)
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut encoder = Encoder::default();
+        let mut decoder = Decoder::default();
+        let responses: [&[(&str, &str)]; 2] = [
+            &[
+                (":status", "200"),
+                ("content-type", "application/json"),
+                ("set-cookie", "session=1"),
+                ("x-request-id", "abc"),
+            ],
+            &[
+                (":status", "200"),
+                ("content-type", "application/json"),
+                ("set-cookie", "session=1"),
+                ("x-request-id", "abd"),
+            ],
+        ];
+        let mut sizes = Vec::new();
+        for response in responses {
+            let mut block = Vec::new();
+            encoder.encode(response.iter().copied(), &mut block);
+            sizes.push(block.len());
+            let decoded = fields(decoder.decode(&block, 8192).unwrap());
+            let expected: Vec<(String, String)> = response
+                .iter()
+                .map(|&(n, v)| (n.to_string(), v.to_string()))
+                .collect();
+            assert_eq!(expected, decoded);
+        }
+        // The repeated content-type is a single byte the second time.
+        assert!(sizes[1] < sizes[0] - 10, "{:?}", sizes);
+        assert!(!encoder
+            .table
+            .entries
+            .iter()
+            .any(|(n, _)| n == b"set-cookie"));
+
+        encoder.set_max_table_size(0);
+        let mut block = Vec::new();
+        encoder.encode([(":status", "404")], &mut block);
+        assert_eq!(0x20, block[0]);
+        decoder.decode(&block, 8192).unwrap();
+        assert_eq!(0, decoder.table.size);
+    }
+}
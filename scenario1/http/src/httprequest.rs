@@ -1,79 +1,125 @@
-use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
-pub enum Resource {
-    Path(String),
-}
+use crate::headers::HeaderMap;
+use crate::parser::{ParseError, RequestParser, Status};
+use crate::uri::Query;
+pub use crate::uri::Resource;
 
 #[derive(Debug)]
 pub struct HttpRequest {
     pub method: Method,
     pub version: Version,
     pub resource: Resource,
-    pub headers: HashMap<String, String>,
-    pub body: String,
+    pub headers: HeaderMap,
+    pub trailers: HeaderMap,
+    pub body: Vec<u8>,
 }
 
-impl From<&str> for HttpRequest {
-    fn from(value: &str) -> Self {
-        let mut lines = value.lines();
-
-        let request = lines.next().unwrap();
-        let (method, resource, version) = parse_request_line(request);
-
-        let headers = lines
-            .by_ref()
-            .take_while(|line| line.len() != 0)
-            .map(|header| parse_header(header))
-            .collect();
+impl HttpRequest {
+    pub fn path(&self) -> &str {
+        self.resource.path()
+    }
 
-        let body = lines.fold(String::new(), |a, b| a + b + "\r\n");
+    pub fn query(&self) -> &Query {
+        self.resource.query()
+    }
 
-        HttpRequest {
-            method,
-            version,
-            headers,
-            resource,
-            body,
+    // Whether the client wants the connection kept open after this request:
+    // the default for HTTP/1.1 unless it sent `Connection: close`, opt-in
+    // through `Connection: keep-alive` for HTTP/1.0.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.headers
+                .get_all("Connection")
+                .flat_map(|value| value.split(','))
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+        match self.version {
+            Version::V1_1 => !has_token("close"),
+            Version::V1_0 => has_token("keep-alive"),
+            _ => false,
         }
     }
 }
 
-fn parse_header(header: &str) -> (String, String) {
-    let header: Vec<&str> = header.split(": ").collect();
-    let key = header.get(0).unwrap_or(&"").to_string();
-    let value = header.get(1).unwrap_or(&"").to_string();
-    (key, value)
+impl TryFrom<&[u8]> for HttpRequest {
+    type Error = ParseError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let mut parser = RequestParser::default();
+        match parser.feed(value)? {
+            Status::Complete(request) => Ok(request),
+            Status::Partial => Err(ParseError::Incomplete),
+            Status::Http2 => Err(ParseError::UnsupportedVersion),
+        }
+    }
 }
 
-fn parse_request_line(request: &str) -> (Method, Resource, Version) {
-    let request: Vec<&str> = request.split_whitespace().collect();
-    let method: Method = request[0].into();
-    let resource = Resource::Path(request[1].into());
-    let version: Version = request[2].into();
+impl TryFrom<&str> for HttpRequest {
+    type Error = ParseError;
 
-    (method, resource, version)
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.as_bytes().try_into()
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Method {
     Get,
+    Head,
     Post,
-    Uninitialized,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Connect,
+    Trace,
+    Extension(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+            Method::Extension(method) => method,
+        }
+    }
 }
 
 impl From<&str> for Method {
     fn from(value: &str) -> Self {
         match value {
             "GET" => Method::Get,
+            "HEAD" => Method::Head,
             "POST" => Method::Post,
-            _ => Method::Uninitialized,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            "CONNECT" => Method::Connect,
+            "TRACE" => Method::Trace,
+            method => Method::Extension(method.to_string()),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Version {
+    V1_0,
     V1_1,
     V2_0,
     Uninitialized,
@@ -82,6 +128,7 @@ pub enum Version {
 impl From<&str> for Version {
     fn from(value: &str) -> Self {
         match value {
+            "HTTP/1.0" => Version::V1_0,
             "HTTP/1.1" => Version::V1_1,
             _ => Version::Uninitialized,
         }
@@ -98,6 +145,15 @@ mod tests {
         assert_eq!(m, Method::Get);
     }
 
+    #[test]
+    fn test_method_extension() {
+        let m: Method = "PROPFIND".into();
+        assert_eq!(m, Method::Extension("PROPFIND".into()));
+        assert_eq!("PROPFIND", m.to_string());
+        let m: Method = "delete".into();
+        assert_eq!(m, Method::Extension("delete".into()));
+    }
+
     #[test]
     fn test_version_into() {
         let v: Version = "HTTP/1.1".into();
@@ -106,20 +162,52 @@ mod tests {
 
     #[test]
     fn test_read_http() {
-        let request_string = "POST /greeting HTTP/1.1\r\nHost: localhost:3000\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n{\r\n\"id\":1\r\n}\r\n";
-        let headers_expected = HashMap::from([
-            ("Host".into(), "localhost:3000".into()),
-            ("Accept".into(), "*/*".into()),
-            ("User-Agent".into(), "curl/7.64.1".into()),
+        let request_string = "POST /greeting HTTP/1.1\r\nHost: localhost:3000\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\nContent-Length: 14\r\n\r\n{\r\n\"id\":1\r\n}\r\n";
+        let headers_expected = HeaderMap::from([
+            ("Host", "localhost:3000"),
+            ("User-Agent", "curl/7.64.1"),
+            ("Accept", "*/*"),
+            ("Content-Length", "14"),
         ]);
-        let request_message: HttpRequest = request_string.into();
+        let request_message = HttpRequest::try_from(request_string).unwrap();
         assert_eq!(Method::Post, request_message.method);
         assert_eq!(Version::V1_1, request_message.version);
+        assert_eq!("/greeting", request_message.path());
+        assert_eq!(headers_expected, request_message.headers);
+        assert_eq!(b"{\r\n\"id\":1\r\n}\r\n".to_vec(), request_message.body);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let cases = [
+            ("GET / HTTP/1.1\r\n\r\n", true),
+            ("GET / HTTP/1.1\r\nConnection: close\r\n\r\n", false),
+            (
+                "GET / HTTP/1.1\r\nConnection: Upgrade, Close\r\n\r\n",
+                false,
+            ),
+            ("GET / HTTP/1.0\r\n\r\n", false),
+            ("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", true),
+        ];
+        for (request, expected) in cases {
+            let request = HttpRequest::try_from(request).unwrap();
+            assert_eq!(expected, request.keep_alive());
+        }
+    }
+
+    #[test]
+    fn test_read_http_errors() {
         assert_eq!(
-            Resource::Path("/greeting".to_string()),
-            request_message.resource
+            ParseError::Incomplete,
+            HttpRequest::try_from("GET / HTTP/1.1\r\nHost: a\r\n").unwrap_err()
+        );
+        assert_eq!(
+            ParseError::UnsupportedVersion,
+            HttpRequest::try_from("GET / HTTP/2.0\r\n\r\n").unwrap_err()
+        );
+        assert_eq!(
+            ParseError::Incomplete,
+            HttpRequest::try_from("POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nab").unwrap_err()
         );
-        assert_eq!(headers_expected, request_message.headers);
-        assert_eq!("{\r\n\"id\":1\r\n}\r\n", request_message.body);
     }
 }
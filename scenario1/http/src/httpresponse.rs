@@ -1,121 +1,248 @@
-use std::{collections::HashMap, io::Write};
-
-#[derive(Debug, PartialEq, Clone)]
-pub struct HttpResponse<'a> {
-    version: &'a str,
-    status_code: &'a str,
-    status_text: &'a str,
-    headers: HashMap<&'a str, &'a str>,
-    body: Option<String>,
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::h2::CONNECTION_SPECIFIC;
+use crate::headers::HeaderMap;
+pub use crate::status::StatusCode;
+
+pub const SERVER: &str = concat!("rust-http/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct HttpResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Option<Body>,
+}
+
+// A body the response owns, or one it shares with a cache, which is sent
+// without being copied.
+#[derive(Debug, Clone)]
+enum Body {
+    Owned(Vec<u8>),
+    Shared(Arc<Vec<u8>>),
 }
 
-impl<'a> Default for HttpResponse<'a> {
-    fn default() -> Self {
-        Self {
-            version: "HTTP/1.1",
-            status_code: "200",
-            status_text: "OK",
-            headers: HashMap::new(),
-            body: None,
+impl Body {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Body::Owned(body) => body,
+            Body::Shared(body) => body,
         }
     }
 }
 
-impl<'a> HttpResponse<'a> {
-    pub fn new(
-        status_code: &'a str,
-        headers: Option<HashMap<&'a str, &'a str>>,
-        body: Option<String>,
-    ) -> Self {
+impl PartialEq for Body {
+    fn eq(&self, other: &Body) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl HttpResponse {
+    pub fn new(status: StatusCode, headers: Option<HeaderMap>, body: Option<Vec<u8>>) -> Self {
         let headers = match headers {
-            None => HashMap::from([("Content-Type", "text/html")]),
+            None => HeaderMap::from([("Content-Type", "text/html")]),
             Some(headers) => headers,
         };
 
-        let status_text = match status_code {
-            "200" => "OK",
-            "400" => "Bad Request",
-            "404" => "Not Found",
-            "500" => "Internal Server Error",
-            _ => "Not Found",
-        };
-
         HttpResponse {
-            version: "HTTP/1.1",
-            status_code,
-            status_text,
+            status,
             headers,
-            body,
+            body: body.map(Body::Owned),
         }
     }
 
-    pub fn send_response(&self, write_stream: &mut impl Write) {
-        let res = self.clone();
-        let response_string: String = String::from(res);
-        let _ = write!(write_stream, "{}", response_string);
+    pub fn builder() -> HttpResponseBuilder {
+        HttpResponseBuilder::default()
+    }
+
+    pub fn send_response(&self, write_stream: &mut impl Write) -> io::Result<()> {
+        write_stream.write_all(&self.serialize_head())?;
+        if self.allows_body() {
+            write_stream.write_all(self.body())?;
+        }
+        write_stream.flush()
     }
 
-    fn version(&self) -> &str {
-        self.version
+    // Sends the status line and headers only, as the answer to a HEAD request.
+    // Content-Length still describes the body a GET would have returned.
+    pub fn send_head(&self, write_stream: &mut impl Write) -> io::Result<()> {
+        write_stream.write_all(&self.serialize_head())?;
+        write_stream.flush()
     }
-    fn status_code(&self) -> &str {
-        self.status_code
+
+    // 1xx, 204 and 304 responses never carry content.
+    pub(crate) fn allows_body(&self) -> bool {
+        !(self.status.is_informational()
+            || self.status == StatusCode::NO_CONTENT
+            || self.status == StatusCode::NOT_MODIFIED)
     }
-    fn status_text(&self) -> &str {
-        self.status_text
+
+    // Status line, Date and Server unless the handler set them, the handler's
+    // headers in insertion order, then Content-Length computed from the body.
+    fn serialize_head(&self) -> Vec<u8> {
+        let mut head = Vec::with_capacity(256);
+        head.extend_from_slice(b"HTTP/1.1 ");
+        head.extend_from_slice(self.status.to_string().as_bytes());
+        head.push(b' ');
+        head.extend_from_slice(self.status.reason_phrase().unwrap_or_default().as_bytes());
+        head.extend_from_slice(b"\r\n");
+
+        if !self.headers.contains("Date") {
+            write_header(
+                &mut head,
+                "Date",
+                &httpdate::fmt_http_date(SystemTime::now()),
+            );
+        }
+        if !self.headers.contains("Server") {
+            write_header(&mut head, "Server", SERVER);
+        }
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                write_header(&mut head, name, value);
+            }
+        }
+        if self.allows_body() {
+            write_header(&mut head, "Content-Length", &self.body().len().to_string());
+        }
+        head.extend_from_slice(b"\r\n");
+        head
     }
-    fn headers(&self) -> String {
-        let map: HashMap<&str, &str> = self.headers.clone();
-        let mut header_string: String = "".into();
-        for (k, v) in map.iter() {
-            header_string = format!("{}{}:{}\n", header_string, k, v);
+
+    // The fields of an HTTP/2 HEADERS frame after `:status`: those of
+    // `serialize_head` in lowercase, without the ones HTTP/2 forbids.
+    pub(crate) fn h2_fields(&self) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        if !self.headers.contains("Date") {
+            let date = httpdate::fmt_http_date(SystemTime::now());
+            fields.push(("date".to_string(), date));
+        }
+        if !self.headers.contains("Server") {
+            fields.push(("server".to_string(), SERVER.to_string()));
         }
-        header_string
+        for (name, value) in self.headers.iter() {
+            let name = name.to_ascii_lowercase();
+            if !CONNECTION_SPECIFIC.contains(&name.as_str()) && name != "content-length" {
+                fields.push((name, value.to_string()));
+            }
+        }
+        if self.allows_body() {
+            fields.push(("content-length".to_string(), self.body().len().to_string()));
+        }
+        fields
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
     }
-    pub fn body(&self) -> &str {
+
+    pub fn set_status(&mut self, status: StatusCode) {
+        self.status = status;
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
         match &self.body {
-            Some(b) => b.as_str(),
-            None => "",
+            Some(b) => b.as_slice(),
+            None => &[],
         }
     }
+
+    pub fn set_body(&mut self, body: impl Into<Vec<u8>>) {
+        self.body = Some(Body::Owned(body.into()));
+    }
+
+    // A shared body is copied unless this was its last reference.
+    pub fn take_body(&mut self) -> Option<Vec<u8>> {
+        self.body.take().map(|body| match body {
+            Body::Owned(body) => body,
+            Body::Shared(body) => Arc::try_unwrap(body).unwrap_or_else(|body| body.to_vec()),
+        })
+    }
 }
 
-impl<'a> From<HttpResponse<'a>> for String {
+impl From<HttpResponse> for String {
     fn from(res: HttpResponse) -> String {
-        let length = if let Some(b) = &res.body { b.len() } else { 0 };
-
-        let res1 = res.clone();
-        format!(
-            "{} {} {}\n{}Content-Length: {}\n\n{}",
-            &res1.version(),
-            &res1.status_code(),
-            &res1.status_text(),
-            &res1.headers(),
-            length,
-            &res1.body()
-        )
+        let mut serialized = Vec::new();
+        let _ = res.send_response(&mut serialized);
+        String::from_utf8_lossy(&serialized).into_owned()
+    }
+}
+
+fn write_header(head: &mut Vec<u8>, name: &str, value: &str) {
+    head.extend_from_slice(name.as_bytes());
+    head.extend_from_slice(b": ");
+    head.extend_from_slice(value.as_bytes());
+    head.extend_from_slice(b"\r\n");
+}
+
+// Builds a response without any default headers:
+// `HttpResponse::builder().status(StatusCode::CREATED).header("Location", "/orders/3").body(json)`
+#[derive(Debug, Default)]
+pub struct HttpResponseBuilder {
+    response: HttpResponse,
+}
+
+impl HttpResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.response.status = status;
+        self
+    }
+
+    // Appends a header. Panics if the name or value is not a valid header,
+    // see `HeaderMap::try_append`.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.response.headers.append(name, value);
+        self
+    }
+
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        for (name, value) in headers.iter() {
+            self.response.headers.append(name, value);
+        }
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> HttpResponse {
+        self.response.body = Some(Body::Owned(body.into()));
+        self.response
+    }
+
+    // A body shared with others, e.g. a cached file, instead of a copy.
+    pub fn shared_body(mut self, body: Arc<Vec<u8>>) -> HttpResponse {
+        self.response.body = Some(Body::Shared(body));
+        self.response
+    }
+
+    pub fn build(self) -> HttpResponse {
+        self.response
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use indoc::indoc;
 
     #[test]
     fn test_response_struct_creation_200() {
         let response_actual = HttpResponse::new(
-            "200",
+            StatusCode::OK,
             None,
             Some("Item was shipped on 21st Dec 2020".into()),
         );
 
         let response_expected = HttpResponse {
-            version: "HTTP/1.1",
-            status_code: "200",
-            status_text: "OK",
-            headers: HashMap::from([("Content-Type", "text/html")]),
-            body: Some("Item was shipped on 21st Dec 2020".into()),
+            status: StatusCode::OK,
+            headers: HeaderMap::from([("Content-Type", "text/html")]),
+            body: Some(Body::Owned("Item was shipped on 21st Dec 2020".into())),
         };
         assert_eq!(response_actual, response_expected);
     }
@@ -123,16 +250,14 @@ mod tests {
     #[test]
     fn test_response_struct_creation_404() {
         let response_actual = HttpResponse::new(
-            "404",
+            StatusCode::NOT_FOUND,
             None,
             Some("Item was shipped on 21st Dec 2020".into()),
         );
         let response_expected = HttpResponse {
-            version: "HTTP/1.1",
-            status_code: "404",
-            status_text: "Not Found",
-            headers: HashMap::from([("Content-Type", "text/html")]),
-            body: Some("Item was shipped on 21st Dec 2020".into()),
+            status: StatusCode::NOT_FOUND,
+            headers: HeaderMap::from([("Content-Type", "text/html")]),
+            body: Some(Body::Owned("Item was shipped on 21st Dec 2020".into())),
         };
         assert_eq!(response_actual, response_expected);
     }
@@ -140,19 +265,73 @@ mod tests {
     #[test]
     fn test_http_response_creation() {
         let response_expected = HttpResponse {
-            version: "HTTP/1.1",
-            status_code: "404",
-            status_text: "Not Found",
-            headers: HashMap::from([("Content-Type", "text/html")]),
-            body: Some("Item was shipped on 21st Dec 2020".into()),
+            status: StatusCode::NOT_FOUND,
+            headers: HeaderMap::from([
+                ("Date", "Mon, 21 Dec 2020 09:00:00 GMT"),
+                ("Content-Type", "text/html"),
+            ]),
+            body: Some(Body::Owned("Item was shipped on 21st Dec 2020".into())),
         };
         let http_string: String = response_expected.into();
-        let response_actual = indoc! {"
-            HTTP/1.1 404 Not Found
-            Content-Type:text/html
-            Content-Length: 33
-            
-            Item was shipped on 21st Dec 2020"};
+        let response_actual = "HTTP/1.1 404 Not Found\r\n\
+            Server: rust-http/0.1.0\r\n\
+            Date: Mon, 21 Dec 2020 09:00:00 GMT\r\n\
+            Content-Type: text/html\r\n\
+            Content-Length: 33\r\n\
+            \r\n\
+            Item was shipped on 21st Dec 2020";
         assert_eq!(http_string, response_actual);
     }
+
+    #[test]
+    fn test_default_date_and_server() {
+        let serialized: String = HttpResponse::new(StatusCode::OK, None, None).into();
+        let mut lines = serialized.split("\r\n");
+        assert_eq!(Some("HTTP/1.1 200 OK"), lines.next());
+        let date = lines.next().unwrap().strip_prefix("Date: ").unwrap();
+        assert!(httpdate::parse_http_date(date).is_ok());
+        assert_eq!(Some("Server: rust-http/0.1.0"), lines.next());
+        assert_eq!(Some("Content-Type: text/html"), lines.next());
+        assert_eq!(Some("Content-Length: 0"), lines.next());
+    }
+
+    #[test]
+    fn test_no_content_length_without_body() {
+        let serialized: String = HttpResponse::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("Date", "Mon, 21 Dec 2020 09:00:00 GMT")
+            .header("ETag", "\"abc\"")
+            .body("ignored")
+            .into();
+        assert_eq!(
+            "HTTP/1.1 304 Not Modified\r\nServer: rust-http/0.1.0\r\n\
+             Date: Mon, 21 Dec 2020 09:00:00 GMT\r\nETag: \"abc\"\r\n\r\n",
+            serialized
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let response = HttpResponse::builder()
+            .status(StatusCode::CREATED)
+            .header("Content-Type", "application/octet-stream")
+            .header("Location", "/api/shipping/orders/3")
+            .body(vec![0u8, 159, 146, 150]);
+        assert_eq!(StatusCode::CREATED, response.status());
+        assert_eq!(
+            Some("/api/shipping/orders/3"),
+            response.headers().get("location")
+        );
+        assert_eq!(&[0u8, 159, 146, 150], response.body());
+
+        let mut written = Vec::new();
+        response.send_response(&mut written).unwrap();
+        assert!(written.ends_with(b"Content-Length: 4\r\n\r\n\x00\x9f\x92\x96"));
+
+        let empty = HttpResponse::builder()
+            .status(StatusCode::NO_CONTENT)
+            .build();
+        assert!(empty.headers().is_empty());
+        assert!(empty.body().is_empty());
+    }
 }
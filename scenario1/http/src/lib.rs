@@ -1,2 +1,10 @@
+pub mod chunked;
+pub mod h2;
+pub mod headers;
+pub mod hpack;
 pub mod httprequest;
 pub mod httpresponse;
+pub mod parser;
+pub mod status;
+pub mod uri;
+pub mod websocket;
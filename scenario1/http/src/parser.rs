@@ -0,0 +1,614 @@
+use std::fmt;
+use std::str;
+
+use crate::chunked::ChunkedDecoder;
+use crate::h2::PREFACE;
+use crate::headers::{is_token_char, HeaderMap};
+use crate::httprequest::{HttpRequest, Method, Version};
+use crate::status::StatusCode;
+use crate::uri::Resource;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_request_line: usize,
+    pub max_headers: usize,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_size: 8 * 1024,
+            max_body_size: 2 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    RequestLine,
+    HeaderCount,
+    HeaderSize,
+    Body,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    BadMethod,
+    BadTarget,
+    UnsupportedVersion,
+    MalformedRequestLine,
+    MalformedHeader,
+    InvalidContentLength,
+    InvalidChunk,
+    UnsupportedTransferEncoding,
+    InvalidUtf8,
+    Incomplete,
+    TooLarge(Limit),
+}
+
+impl ParseError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ParseError::TooLarge(Limit::RequestLine) => StatusCode::URI_TOO_LONG,
+            ParseError::TooLarge(Limit::Body) => StatusCode::CONTENT_TOO_LARGE,
+            ParseError::TooLarge(_) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ParseError::UnsupportedTransferEncoding => StatusCode::NOT_IMPLEMENTED,
+            ParseError::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ParseError::BadMethod => "invalid request method",
+            ParseError::BadTarget => "invalid request target",
+            ParseError::UnsupportedVersion => "unsupported HTTP version",
+            ParseError::MalformedRequestLine => "malformed request line",
+            ParseError::MalformedHeader => "malformed header",
+            ParseError::InvalidContentLength => "invalid Content-Length",
+            ParseError::InvalidChunk => "invalid chunked body",
+            ParseError::UnsupportedTransferEncoding => "unsupported Transfer-Encoding",
+            ParseError::InvalidUtf8 => "invalid UTF-8 in request",
+            ParseError::Incomplete => "incomplete request",
+            ParseError::TooLarge(Limit::RequestLine) => "request line too long",
+            ParseError::TooLarge(Limit::HeaderCount) => "too many headers",
+            ParseError::TooLarge(Limit::HeaderSize) => "header too large",
+            ParseError::TooLarge(Limit::Body) => "request body too large",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Returned once per request and matched right away, boxing would only add
+// an allocation.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Status {
+    Partial,
+    Complete(HttpRequest),
+    // The client opened with the HTTP/2 connection preface, knowing the
+    // server speaks it. `buffered` holds the preface and what followed.
+    Http2,
+}
+
+#[derive(Debug)]
+struct Head {
+    method: Method,
+    resource: Resource,
+    version: Version,
+    headers: HeaderMap,
+}
+
+#[derive(Debug)]
+enum Stage {
+    RequestLine,
+    Headers(Head),
+    Body {
+        head: Head,
+        remaining: usize,
+        body: Vec<u8>,
+    },
+    Chunked {
+        head: Head,
+        decoder: ChunkedDecoder,
+        body: Vec<u8>,
+    },
+}
+
+// Incremental parser for HTTP/1.x requests. Bytes are pushed with `feed` as
+// they arrive; whatever follows a complete request stays buffered and is
+// picked up by the next call to `parse`.
+#[derive(Debug)]
+pub struct RequestParser {
+    limits: Limits,
+    buffer: Vec<u8>,
+    stage: Stage,
+    continue_sent: bool,
+}
+
+impl Default for RequestParser {
+    fn default() -> Self {
+        Self::new(Limits::default())
+    }
+}
+
+impl RequestParser {
+    pub fn new(limits: Limits) -> Self {
+        RequestParser {
+            limits,
+            buffer: Vec::new(),
+            stage: Stage::RequestLine,
+            continue_sent: false,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Status, ParseError> {
+        self.buffer.extend_from_slice(chunk);
+        self.parse()
+    }
+
+    pub fn parse(&mut self) -> Result<Status, ParseError> {
+        loop {
+            match &mut self.stage {
+                Stage::RequestLine => {
+                    // The preface would parse as a request, so it is caught
+                    // before it's complete.
+                    if self.buffer.starts_with(PREFACE) {
+                        return Ok(Status::Http2);
+                    }
+                    if !self.buffer.is_empty() && PREFACE.starts_with(&self.buffer) {
+                        return Ok(Status::Partial);
+                    }
+                    let line = match next_line(&mut self.buffer, self.limits.max_request_line) {
+                        Ok(Some(line)) => line,
+                        Ok(None) => return Ok(Status::Partial),
+                        Err(()) => return Err(ParseError::TooLarge(Limit::RequestLine)),
+                    };
+                    // Robustness: ignore empty lines preceding the request line.
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let line = str::from_utf8(&line).map_err(|_| ParseError::InvalidUtf8)?;
+                    let (method, resource, version) = parse_request_line(line)?;
+                    self.stage = Stage::Headers(Head {
+                        method,
+                        resource,
+                        version,
+                        headers: HeaderMap::new(),
+                    });
+                }
+                Stage::Headers(head) => {
+                    let line = match next_line(&mut self.buffer, self.limits.max_header_size) {
+                        Ok(Some(line)) => line,
+                        Ok(None) => return Ok(Status::Partial),
+                        Err(()) => return Err(ParseError::TooLarge(Limit::HeaderSize)),
+                    };
+                    if line.is_empty() {
+                        self.start_body()?;
+                        continue;
+                    }
+                    if head.headers.len() >= self.limits.max_headers {
+                        return Err(ParseError::TooLarge(Limit::HeaderCount));
+                    }
+                    let line = str::from_utf8(&line).map_err(|_| ParseError::InvalidUtf8)?;
+                    let (key, value) = parse_header(line)?;
+                    head.headers
+                        .try_append(key, value)
+                        .map_err(|_| ParseError::MalformedHeader)?;
+                }
+                Stage::Body {
+                    remaining, body, ..
+                } => {
+                    let take = (*remaining).min(self.buffer.len());
+                    body.extend(self.buffer.drain(..take));
+                    *remaining -= take;
+                    if *remaining > 0 {
+                        return Ok(Status::Partial);
+                    }
+                    return Ok(Status::Complete(self.finish()));
+                }
+                Stage::Chunked { decoder, body, .. } => {
+                    if !decoder.decode(&mut self.buffer, body)? {
+                        return Ok(Status::Partial);
+                    }
+                    return Ok(Status::Complete(self.finish()));
+                }
+            }
+        }
+    }
+
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn is_idle(&self) -> bool {
+        matches!(self.stage, Stage::RequestLine) && self.buffer.is_empty()
+    }
+
+    // True once per request when the client sent `Expect: 100-continue` and
+    // is waiting for an interim response before it transmits the body.
+    pub fn expects_continue(&mut self) -> bool {
+        let head = match &self.stage {
+            Stage::Body { head, .. } | Stage::Chunked { head, .. } => head,
+            _ => return false,
+        };
+        let expects = head.version == Version::V1_1
+            && head
+                .headers
+                .get("Expect")
+                .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+        if expects && !self.continue_sent && self.buffer.is_empty() {
+            self.continue_sent = true;
+            return true;
+        }
+        false
+    }
+
+    fn start_body(&mut self) -> Result<(), ParseError> {
+        let Stage::Headers(head) = std::mem::replace(&mut self.stage, Stage::RequestLine) else {
+            unreachable!("start_body called outside of the header section");
+        };
+
+        let transfer_encoding = join_values(&head.headers, "Transfer-Encoding");
+        let content_length = join_values(&head.headers, "Content-Length");
+        self.stage = match (transfer_encoding, content_length) {
+            // A message carrying both is a request smuggling vector, reject it.
+            (Some(_), Some(_)) => return Err(ParseError::InvalidContentLength),
+            (Some(encoding), None) => {
+                let encoding = encoding.as_str();
+                let mut codings = encoding.split(',').map(|c| c.trim());
+                if codings.next_back().map(|c| c.to_ascii_lowercase()) != Some("chunked".into())
+                    || codings.next().is_some()
+                {
+                    return Err(ParseError::UnsupportedTransferEncoding);
+                }
+                Stage::Chunked {
+                    head,
+                    decoder: ChunkedDecoder::new(&self.limits),
+                    body: Vec::new(),
+                }
+            }
+            (None, Some(length)) => {
+                let length = parse_content_length(&length)?;
+                if length > self.limits.max_body_size {
+                    return Err(ParseError::TooLarge(Limit::Body));
+                }
+                Stage::Body {
+                    head,
+                    remaining: length,
+                    body: Vec::with_capacity(length),
+                }
+            }
+            (None, None) => Stage::Body {
+                head,
+                remaining: 0,
+                body: Vec::new(),
+            },
+        };
+        Ok(())
+    }
+
+    fn finish(&mut self) -> HttpRequest {
+        self.continue_sent = false;
+        let (head, body, trailers) = match std::mem::replace(&mut self.stage, Stage::RequestLine) {
+            Stage::Body { head, body, .. } => (head, body, HeaderMap::new()),
+            Stage::Chunked {
+                head,
+                decoder,
+                body,
+            } => (head, body, decoder.into_trailers()),
+            _ => unreachable!("finish called before the body was read"),
+        };
+        HttpRequest {
+            method: head.method,
+            version: head.version,
+            resource: head.resource,
+            headers: head.headers,
+            trailers,
+            body,
+        }
+    }
+}
+
+fn join_values(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers.get_all(name).collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+// Content-Length must be plain digits. Repeated fields are accepted as long
+// as they all carry the same value.
+fn parse_content_length(value: &str) -> Result<usize, ParseError> {
+    let mut values = value.split(',').map(|v| v.trim());
+    let first = values.next().unwrap_or_default();
+    if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) || values.any(|v| v != first)
+    {
+        return Err(ParseError::InvalidContentLength);
+    }
+    first.parse().map_err(|_| ParseError::InvalidContentLength)
+}
+
+// Takes the next line off the front of the buffer, without its terminator.
+// Lines are CRLF terminated, a bare LF is accepted as well.
+pub(crate) fn next_line(buffer: &mut Vec<u8>, limit: usize) -> Result<Option<Vec<u8>>, ()> {
+    match buffer.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let len = if end > 0 && buffer[end - 1] == b'\r' {
+                end - 1
+            } else {
+                end
+            };
+            if len > limit {
+                return Err(());
+            }
+            let mut line: Vec<u8> = buffer.drain(..=end).collect();
+            line.truncate(len);
+            Ok(Some(line))
+        }
+        None if buffer.len() > limit + 1 => Err(()),
+        None => Ok(None),
+    }
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, Resource, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::MalformedRequestLine);
+    };
+
+    if method.is_empty() || !method.bytes().all(is_token_char) {
+        return Err(ParseError::BadMethod);
+    }
+    let method: Method = method.into();
+    let resource = Resource::parse(target).map_err(|_| ParseError::BadTarget)?;
+    // Authority-form is only used by CONNECT and asterisk-form by OPTIONS.
+    let form_matches = match (&method, &resource) {
+        (Method::Connect, resource) => matches!(resource, Resource::Authority(_)),
+        (_, Resource::Authority(_)) => false,
+        (Method::Options, _) => true,
+        (_, Resource::Asterisk) => false,
+        _ => true,
+    };
+    if !form_matches {
+        return Err(ParseError::BadTarget);
+    }
+    let version = parse_version(version)?;
+
+    Ok((method, resource, version))
+}
+
+fn parse_version(version: &str) -> Result<Version, ParseError> {
+    let digits = version
+        .strip_prefix("HTTP/")
+        .ok_or(ParseError::MalformedRequestLine)?
+        .as_bytes();
+    match digits {
+        [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            match version.into() {
+                Version::Uninitialized | Version::V2_0 => Err(ParseError::UnsupportedVersion),
+                version => Ok(version),
+            }
+        }
+        _ => Err(ParseError::MalformedRequestLine),
+    }
+}
+
+pub(crate) fn parse_header(line: &str) -> Result<(String, String), ParseError> {
+    let (key, value) = line.split_once(':').ok_or(ParseError::MalformedHeader)?;
+    if key.is_empty() || !key.bytes().all(is_token_char) {
+        return Err(ParseError::MalformedHeader);
+    }
+    Ok((key.to_string(), value.trim_matches([' ', '\t']).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] =
+        b"GET /api/shipping/orders HTTP/1.1\r\nHost: localhost:3000\r\nAccept: */*\r\n\r\n";
+
+    #[test]
+    fn test_parse_complete_request() {
+        let mut parser = RequestParser::default();
+        let Status::Complete(req) = parser.feed(REQUEST).unwrap() else {
+            panic!("expected a complete request");
+        };
+        assert_eq!(Method::Get, req.method);
+        assert_eq!(Version::V1_1, req.version);
+        assert_eq!("/api/shipping/orders", req.path());
+        assert_eq!(Some("localhost:3000"), req.headers.get("host"));
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn test_parse_byte_by_byte() {
+        let mut parser = RequestParser::default();
+        let (last, head) = REQUEST.split_last().unwrap();
+        for byte in head {
+            assert!(matches!(parser.feed(&[*byte]).unwrap(), Status::Partial));
+        }
+        assert!(matches!(
+            parser.feed(&[*last]).unwrap(),
+            Status::Complete(_)
+        ));
+    }
+
+    #[test]
+    fn test_parse_leaves_pipelined_bytes() {
+        let mut parser = RequestParser::default();
+        let mut input = REQUEST.to_vec();
+        input.extend_from_slice(b"GET /health HTTP/1.1\r\n");
+        assert!(matches!(parser.feed(&input).unwrap(), Status::Complete(_)));
+        assert_eq!(b"GET /health HTTP/1.1\r\n", parser.buffered());
+        assert!(matches!(parser.parse().unwrap(), Status::Partial));
+        let Status::Complete(req) = parser.feed(b"\r\n").unwrap() else {
+            panic!("expected a complete request");
+        };
+        assert_eq!("/health", req.path());
+    }
+
+    #[test]
+    fn test_parse_bare_lf() {
+        let mut parser = RequestParser::default();
+        let status = parser.feed(b"GET / HTTP/1.1\nHost: a\n\n").unwrap();
+        assert!(matches!(status, Status::Complete(_)));
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let cases: [(&[u8], ParseError); 8] = [
+            (b"GET * HTTP/1.1\r\n", ParseError::BadTarget),
+            (b"CONNECT / HTTP/1.1\r\n", ParseError::BadTarget),
+            (b"GET /\r\n", ParseError::MalformedRequestLine),
+            (b"G(T / HTTP/1.1\r\n", ParseError::BadMethod),
+            (b"GET nowhere HTTP/1.1\r\n", ParseError::BadTarget),
+            (b"GET / HTTP/3.0\r\n", ParseError::UnsupportedVersion),
+            (b"GET / HTTQ/1.1\r\n", ParseError::MalformedRequestLine),
+            (
+                b"GET / HTTP/1.1\r\nBad Name: x\r\n",
+                ParseError::MalformedHeader,
+            ),
+        ];
+        for (input, expected) in cases {
+            let mut parser = RequestParser::default();
+            assert_eq!(expected, parser.feed(input).unwrap_err());
+        }
+        let mut parser = RequestParser::default();
+        assert_eq!(
+            ParseError::MalformedHeader,
+            parser
+                .feed(b"GET / HTTP/1.1\r\nno colon here\r\n")
+                .unwrap_err()
+        );
+        let mut parser = RequestParser::default();
+        assert_eq!(
+            ParseError::InvalidUtf8,
+            parser.feed(b"GET /\xff HTTP/1.1\r\n").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let limits = Limits {
+            max_request_line: 16,
+            max_headers: 1,
+            max_header_size: 16,
+            max_body_size: 4,
+        };
+
+        let mut parser = RequestParser::new(limits);
+        assert_eq!(
+            ParseError::TooLarge(Limit::RequestLine),
+            parser.feed(b"GET /a/very/long/path").unwrap_err()
+        );
+
+        let mut parser = RequestParser::new(limits);
+        assert_eq!(
+            ParseError::TooLarge(Limit::HeaderSize),
+            parser
+                .feed(b"GET / HTTP/1.1\r\nUser-Agent: something long")
+                .unwrap_err()
+        );
+
+        let mut parser = RequestParser::new(limits);
+        assert_eq!(
+            ParseError::TooLarge(Limit::HeaderCount),
+            parser
+                .feed(b"GET / HTTP/1.1\r\nHost: a\r\nAccept: b\r\n")
+                .unwrap_err()
+        );
+
+        let mut parser = RequestParser::new(limits);
+        assert_eq!(
+            ParseError::TooLarge(Limit::Body),
+            parser
+                .feed(b"POST / HTTP/1.1\r\nContent-Length:5\r\n\r\n")
+                .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_content_length_body() {
+        let mut parser = RequestParser::default();
+        let head = b"POST /api HTTP/1.1\r\ncontent-length: 7\r\n\r\n";
+        assert!(matches!(parser.feed(head).unwrap(), Status::Partial));
+        assert!(matches!(parser.feed(b"\x00\r\n").unwrap(), Status::Partial));
+        let Status::Complete(req) = parser.feed(b"\xffabcGET").unwrap() else {
+            panic!("expected a complete request");
+        };
+        assert_eq!(b"\x00\r\n\xffabc".to_vec(), req.body);
+        assert_eq!(b"GET", parser.buffered());
+    }
+
+    #[test]
+    fn test_parse_chunked_body() {
+        let mut parser = RequestParser::default();
+        let input = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: 1\r\n\r\n";
+        let Status::Complete(req) = parser.feed(input).unwrap() else {
+            panic!("expected a complete request");
+        };
+        assert_eq!(b"hello world".to_vec(), req.body);
+        assert_eq!(Some("1"), req.trailers.get("X-Checksum"));
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn test_parse_body_framing_errors() {
+        let cases: [(&[u8], ParseError); 4] = [
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                ParseError::InvalidContentLength,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+                ParseError::InvalidContentLength,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                ParseError::InvalidContentLength,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+                ParseError::UnsupportedTransferEncoding,
+            ),
+        ];
+        for (input, expected) in cases {
+            let mut parser = RequestParser::default();
+            assert_eq!(expected, parser.feed(input).unwrap_err());
+        }
+    }
+
+    #[test]
+    fn test_expects_continue() {
+        let mut parser = RequestParser::default();
+        let head = b"PUT /f HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n";
+        assert!(matches!(parser.feed(head).unwrap(), Status::Partial));
+        assert!(parser.expects_continue());
+        assert!(!parser.expects_continue());
+        assert!(matches!(parser.feed(b"ok").unwrap(), Status::Complete(_)));
+    }
+
+    #[test]
+    fn test_http2_preface() {
+        let mut parser = RequestParser::default();
+        assert!(matches!(parser.feed(&PREFACE[..10]).unwrap(), Status::Partial));
+        assert!(matches!(parser.feed(&PREFACE[10..]).unwrap(), Status::Http2));
+        assert_eq!(PREFACE, parser.buffered());
+
+        let mut parser = RequestParser::default();
+        assert!(matches!(parser.feed(b"P").unwrap(), Status::Partial));
+        let status = parser.feed(b"OST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert!(matches!(status.unwrap(), Status::Complete(_)));
+    }
+}
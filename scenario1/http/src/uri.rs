@@ -0,0 +1,371 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidUri;
+
+impl fmt::Display for InvalidUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid request target")
+    }
+}
+
+impl std::error::Error for InvalidUri {}
+
+// The four request-target forms of RFC 9112 section 3.2.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resource {
+    Origin(Uri),
+    Absolute(Uri),
+    Authority(String),
+    Asterisk,
+}
+
+impl Resource {
+    pub fn parse(target: &str) -> Result<Resource, InvalidUri> {
+        if target.is_empty() || !target.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(InvalidUri);
+        }
+        if target == "*" {
+            return Ok(Resource::Asterisk);
+        }
+        if target.starts_with('/') {
+            return Uri::parse(None, None, target).map(Resource::Origin);
+        }
+        if let Some((scheme, rest)) = target.split_once("://") {
+            let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b));
+            if !valid_scheme {
+                return Err(InvalidUri);
+            }
+            let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+            let (authority, rest) = rest.split_at(end);
+            if authority.is_empty() {
+                return Err(InvalidUri);
+            }
+            let rest = if rest.starts_with('/') {
+                rest.to_string()
+            } else {
+                format!("/{}", rest)
+            };
+            return Uri::parse(Some(scheme), Some(authority), &rest).map(Resource::Absolute);
+        }
+        match target.rsplit_once(':') {
+            Some((host, port))
+                if !host.is_empty()
+                    && !port.is_empty()
+                    && port.bytes().all(|b| b.is_ascii_digit())
+                    && !host.contains(['/', '?', '#', '@']) =>
+            {
+                Ok(Resource::Authority(target.to_string()))
+            }
+            _ => Err(InvalidUri),
+        }
+    }
+
+    pub fn uri(&self) -> Option<&Uri> {
+        match self {
+            Resource::Origin(uri) | Resource::Absolute(uri) => Some(uri),
+            Resource::Authority(_) | Resource::Asterisk => None,
+        }
+    }
+
+    // The normalized path; empty for authority-form and `*` for asterisk-form.
+    pub fn path(&self) -> &str {
+        match self {
+            Resource::Origin(uri) | Resource::Absolute(uri) => &uri.path,
+            Resource::Authority(_) => "",
+            Resource::Asterisk => "*",
+        }
+    }
+
+    pub fn query(&self) -> &Query {
+        static EMPTY: Query = Query { pairs: Vec::new() };
+        self.uri().map_or(&EMPTY, |uri| &uri.params)
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Origin(uri) | Resource::Absolute(uri) => uri.fmt(f),
+            Resource::Authority(authority) => f.write_str(authority),
+            Resource::Asterisk => f.write_str("*"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Uri {
+    pub scheme: Option<String>,
+    pub authority: Option<String>,
+    // Percent-decoded with dot segments removed.
+    pub path: String,
+    // The path exactly as it was sent.
+    pub raw_path: String,
+    pub query: Option<String>,
+    pub params: Query,
+    pub fragment: Option<String>,
+}
+
+impl Uri {
+    fn parse(
+        scheme: Option<&str>,
+        authority: Option<&str>,
+        target: &str,
+    ) -> Result<Uri, InvalidUri> {
+        let (target, fragment) = match target.split_once('#') {
+            Some((target, fragment)) => (target, Some(fragment.to_string())),
+            None => (target, None),
+        };
+        let (raw_path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+
+        let decoded = percent_decode(raw_path.as_bytes(), false).ok_or(InvalidUri)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| InvalidUri)?;
+        if decoded.contains('\0') {
+            return Err(InvalidUri);
+        }
+
+        Ok(Uri {
+            scheme: scheme.map(|s| s.to_ascii_lowercase()),
+            authority: authority.map(str::to_string),
+            path: remove_dot_segments(&decoded),
+            raw_path: raw_path.to_string(),
+            params: query.as_deref().map(Query::parse).unwrap_or_default(),
+            query,
+            fragment,
+        })
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(scheme), Some(authority)) = (&self.scheme, &self.authority) {
+            write!(f, "{}://{}", scheme, authority)?;
+        }
+        f.write_str(&self.raw_path)?;
+        if let Some(query) = &self.query {
+            write!(f, "?{}", query)?;
+        }
+        Ok(())
+    }
+}
+
+// Query parameters in the order they appear; a key may repeat.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Query {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_component(key), decode_component(value))
+            })
+            .collect();
+        Query { pairs }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+fn decode_component(component: &str) -> String {
+    match percent_decode(component.as_bytes(), true) {
+        Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        None => component.to_string(),
+    }
+}
+
+// Decodes `%XX` escapes, and `+` as a space when `plus_as_space` is set as
+// in form encoded query strings. A malformed escape yields `None`.
+pub fn percent_decode(input: &[u8], plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hi = hex_value(*bytes.next()?)?;
+                let lo = hex_value(*bytes.next()?)?;
+                decoded.push(hi << 4 | lo);
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+    Some(decoded)
+}
+
+// Encodes everything outside the RFC 3986 unreserved set, keeping `/` so a
+// path round-trips through `percent_decode`.
+pub fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+// RFC 3986 section 5.2.4. The result never climbs above the root.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        trailing_slash = false;
+        match segment {
+            "." => trailing_slash = true,
+            ".." => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_origin_form() {
+        let resource = Resource::parse("/api/shipping/orders?status=Shipped&page=2#top").unwrap();
+        let Resource::Origin(uri) = &resource else {
+            panic!("expected origin-form");
+        };
+        assert_eq!("/api/shipping/orders", uri.path);
+        assert_eq!(Some("status=Shipped&page=2"), uri.query.as_deref());
+        assert_eq!(Some("top"), uri.fragment.as_deref());
+        assert_eq!(Some("Shipped"), uri.params.get("status"));
+        assert_eq!(Some("2"), uri.params.get("page"));
+        assert_eq!(
+            "/api/shipping/orders?status=Shipped&page=2",
+            resource.to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_other_forms() {
+        let Resource::Absolute(uri) = Resource::parse("HTTP://localhost:3000?x=1").unwrap() else {
+            panic!("expected absolute-form");
+        };
+        assert_eq!(Some("http"), uri.scheme.as_deref());
+        assert_eq!(Some("localhost:3000"), uri.authority.as_deref());
+        assert_eq!("/", uri.path);
+        assert_eq!(Some("1"), uri.params.get("x"));
+
+        assert_eq!(
+            Resource::Authority("example.com:443".into()),
+            Resource::parse("example.com:443").unwrap()
+        );
+        assert_eq!(Resource::Asterisk, Resource::parse("*").unwrap());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for target in [
+            "",
+            "orders",
+            "example.com",
+            "/a b",
+            "/%zz",
+            "/%00",
+            "/%ff",
+            "1x://a/",
+        ] {
+            assert_eq!(Err(InvalidUri), Resource::parse(target), "{}", target);
+        }
+    }
+
+    #[test]
+    fn test_path_normalization() {
+        let cases = [
+            ("/", "/"),
+            ("/a/b/c/./../../g", "/a/g"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/%2e%2e/%2E%2E/etc/passwd", "/etc/passwd"),
+            ("/static/..%2f..%2fsecret", "/secret"),
+            ("/dir/", "/dir/"),
+            ("/dir/.", "/dir/"),
+            ("/dir/..", "/"),
+            ("/caf%C3%A9%20menu.html", "/café menu.html"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(expected, Resource::parse(raw).unwrap().path(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_query_multimap() {
+        let query = Query::parse("tag=a&tag=b+c&empty=&flag&name=J%C3%BCrgen&&");
+        assert_eq!(vec!["a", "b c"], query.get_all("tag").collect::<Vec<_>>());
+        assert_eq!(Some(""), query.get("empty"));
+        assert!(query.contains("flag"));
+        assert_eq!(Some("Jürgen"), query.get("name"));
+        assert_eq!(5, query.len());
+    }
+
+    #[test]
+    fn test_percent_encode_path() {
+        let path = "/café menu.html";
+        let encoded = percent_encode_path(path);
+        assert_eq!("/caf%C3%A9%20menu.html", encoded);
+        assert_eq!(
+            path.as_bytes(),
+            percent_decode(encoded.as_bytes(), false).unwrap()
+        );
+    }
+}
@@ -0,0 +1,620 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+
+use crate::httprequest::{HttpRequest, Method, Version};
+use crate::httpresponse::HttpResponse;
+use crate::status::StatusCode;
+
+// WebSocket (RFC 6455): the opening handshake, which is plain HTTP/1.1, and
+// the frame codec used once the connection has switched.
+
+// Appended to the client's key to prove the server understood the handshake.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const VERSION: &str = "13";
+
+// Close codes, see RFC 6455 7.4.1.
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+
+// Control frames carry at most this much, the close reason two bytes less.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeError {
+    // Not a GET with `Upgrade: websocket` and `Connection: Upgrade`.
+    NotUpgrade,
+    // Sec-WebSocket-Version is missing or isn't 13.
+    UnsupportedVersion,
+    // Sec-WebSocket-Key isn't 16 base64-encoded bytes.
+    InvalidKey,
+}
+
+impl HandshakeError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HandshakeError::NotUpgrade | HandshakeError::UnsupportedVersion => {
+                StatusCode::UPGRADE_REQUIRED
+            }
+            HandshakeError::InvalidKey => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            HandshakeError::NotUpgrade => "expected a WebSocket upgrade",
+            HandshakeError::UnsupportedVersion => "unsupported WebSocket version",
+            HandshakeError::InvalidKey => "invalid Sec-WebSocket-Key",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+// Whether `req` asks to switch to WebSocket. `handshake` says whether it
+// does so correctly.
+pub fn is_upgrade(req: &HttpRequest) -> bool {
+    let has_token = |name: &str, token: &str| {
+        req.headers
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+    has_token("Upgrade", "websocket") && has_token("Connection", "upgrade")
+}
+
+// The 101 response that completes the opening handshake of `req`.
+pub fn handshake(req: &HttpRequest) -> Result<HttpResponse, HandshakeError> {
+    if req.method != Method::Get || req.version != Version::V1_1 || !is_upgrade(req) {
+        return Err(HandshakeError::NotUpgrade);
+    }
+    if req.headers.get("Sec-WebSocket-Version") != Some(VERSION) {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+    let key = match req.headers.get("Sec-WebSocket-Key") {
+        Some(key) if base64_decode(key.trim()).is_some_and(|key| key.len() == 16) => key.trim(),
+        _ => return Err(HandshakeError::InvalidKey),
+    };
+    Ok(HttpResponse::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key(key))
+        .build())
+}
+
+// The Sec-WebSocket-Accept answering Sec-WebSocket-Key `key`.
+pub fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+// Why the peer's frames can't be read. The connection is over; `code` is
+// what to close it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolError {
+    pub code: u16,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WebSocket error {}: {}", self.code, self.reason)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+fn error(code: u16, reason: &'static str) -> ProtocolError {
+    ProtocolError { code, reason }
+}
+
+// Clients mask every frame they send and servers none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// Turns bytes read into messages and messages into bytes to write, without
+// doing any I/O. Fragmented messages are put back together, and outgoing
+// ones longer than the fragment size are split up.
+#[derive(Debug)]
+pub struct Codec {
+    role: Role,
+    max_message_size: usize,
+    fragment_size: usize,
+    buffer: Vec<u8>,
+    // The opcode and data so far of a fragmented message.
+    partial: Option<(u8, Vec<u8>)>,
+    mask_state: u64,
+}
+
+impl Codec {
+    pub fn new(role: Role) -> Self {
+        Codec {
+            role,
+            max_message_size: 1 << 20,
+            fragment_size: 64 * 1024,
+            buffer: Vec::new(),
+            partial: None,
+            mask_state: RandomState::new().hash_one(0u64) | 1,
+        }
+    }
+
+    // Longer messages from the peer fail with MESSAGE_TOO_BIG.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    pub fn fragment_size(mut self, size: usize) -> Self {
+        self.fragment_size = size.max(1);
+        self
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // The next complete message, `None` until more has been fed. Control
+    // messages can arrive between the fragments of another.
+    pub fn next_message(&mut self) -> Result<Option<Message>, ProtocolError> {
+        loop {
+            let Some((fin, opcode, payload)) = self.next_frame()? else {
+                return Ok(None);
+            };
+            let message = match opcode {
+                CONTINUATION => {
+                    let Some((_, data)) = self.partial.as_mut() else {
+                        return Err(error(PROTOCOL_ERROR, "unexpected continuation frame"));
+                    };
+                    data.extend_from_slice(&payload);
+                    if !fin {
+                        continue;
+                    }
+                    let (opcode, data) = self.partial.take().expect("fragmented message");
+                    data_message(opcode, data)?
+                }
+                TEXT | BINARY => {
+                    if self.partial.is_some() {
+                        return Err(error(PROTOCOL_ERROR, "expected a continuation frame"));
+                    }
+                    if !fin {
+                        self.partial = Some((opcode, payload));
+                        continue;
+                    }
+                    data_message(opcode, payload)?
+                }
+                CLOSE => Message::Close(close_frame(&payload)?),
+                PING => Message::Ping(payload),
+                PONG => Message::Pong(payload),
+                _ => return Err(error(PROTOCOL_ERROR, "unknown opcode")),
+            };
+            return Ok(Some(message));
+        }
+    }
+
+    pub fn encode(&mut self, message: &Message, out: &mut Vec<u8>) {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (TEXT, text.as_bytes()),
+            Message::Binary(data) => (BINARY, data.as_slice()),
+            Message::Ping(data) => (PING, truncate(data, MAX_CONTROL_PAYLOAD)),
+            Message::Pong(data) => (PONG, truncate(data, MAX_CONTROL_PAYLOAD)),
+            Message::Close(None) => (CLOSE, &[][..]),
+            Message::Close(Some(frame)) => {
+                let mut payload = frame.code.to_be_bytes().to_vec();
+                let mut reason = frame.reason.as_str();
+                while reason.len() > MAX_CONTROL_PAYLOAD - 2 {
+                    let mut end = reason.len() - 1;
+                    while !reason.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    reason = &reason[..end];
+                }
+                payload.extend_from_slice(reason.as_bytes());
+                self.write_frame(true, CLOSE, &payload, out);
+                return;
+            }
+        };
+        if opcode & 0x8 != 0 || payload.len() <= self.fragment_size {
+            self.write_frame(true, opcode, payload, out);
+            return;
+        }
+        let mut chunks = payload.chunks(self.fragment_size).peekable();
+        let mut opcode = opcode;
+        while let Some(chunk) = chunks.next() {
+            self.write_frame(chunks.peek().is_none(), opcode, chunk, out);
+            opcode = CONTINUATION;
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, ProtocolError> {
+        let buffer = &self.buffer;
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+        let (fin, opcode) = (buffer[0] & 0x80 != 0, buffer[0] & 0x0f);
+        if buffer[0] & 0x70 != 0 {
+            return Err(error(PROTOCOL_ERROR, "reserved bits set"));
+        }
+        let masked = buffer[1] & 0x80 != 0;
+        if masked != (self.role == Role::Server) {
+            return Err(error(PROTOCOL_ERROR, "wrong frame masking"));
+        }
+        let (len, mut header_len) = match buffer[1] & 0x7f {
+            126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+            127 if buffer.len() >= 10 => {
+                let len = u64::from_be_bytes(buffer[2..10].try_into().expect("8 bytes"));
+                (len, 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if opcode & 0x8 != 0 && (len > MAX_CONTROL_PAYLOAD as u64 || !fin) {
+            return Err(error(PROTOCOL_ERROR, "invalid control frame"));
+        }
+        let buffered = self.partial.as_ref().map_or(0, |(_, data)| data.len()) as u64;
+        if len.saturating_add(buffered) > self.max_message_size as u64 {
+            return Err(error(MESSAGE_TOO_BIG, "message too big"));
+        }
+        let len = len as usize;
+        let mask = if masked {
+            if buffer.len() < header_len + 4 {
+                return Ok(None);
+            }
+            header_len += 4;
+            Some([
+                buffer[header_len - 4],
+                buffer[header_len - 3],
+                buffer[header_len - 2],
+                buffer[header_len - 1],
+            ])
+        } else {
+            None
+        };
+        if buffer.len() < header_len + len {
+            return Ok(None);
+        }
+        let mut payload: Vec<u8> = self
+            .buffer
+            .drain(..header_len + len)
+            .skip(header_len)
+            .collect();
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+        out.push((if fin { 0x80 } else { 0 }) | opcode);
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if self.role == Role::Server {
+            out.extend_from_slice(payload);
+            return;
+        }
+        let mask = self.next_mask();
+        out.extend_from_slice(&mask);
+        let start = out.len();
+        out.extend_from_slice(payload);
+        apply_mask(&mut out[start..], mask);
+    }
+
+    // Masks only need to be unpredictable to scripts in the browser, which
+    // xorshift seeded per codec is for this purpose.
+    fn next_mask(&mut self) -> [u8; 4] {
+        self.mask_state ^= self.mask_state << 13;
+        self.mask_state ^= self.mask_state >> 7;
+        self.mask_state ^= self.mask_state << 17;
+        (self.mask_state as u32).to_be_bytes()
+    }
+}
+
+fn data_message(opcode: u8, data: Vec<u8>) -> Result<Message, ProtocolError> {
+    if opcode == BINARY {
+        return Ok(Message::Binary(data));
+    }
+    String::from_utf8(data)
+        .map(Message::Text)
+        .map_err(|_| error(INVALID_PAYLOAD, "text message is not UTF-8"))
+}
+
+fn close_frame(payload: &[u8]) -> Result<Option<CloseFrame>, ProtocolError> {
+    let (code, reason) = match payload {
+        [] => return Ok(None),
+        [_] => return Err(error(PROTOCOL_ERROR, "truncated close code")),
+        [high, low, reason @ ..] => (u16::from_be_bytes([*high, *low]), reason),
+    };
+    // Codes that are reserved or only for reporting locally can't be sent.
+    let valid = matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999);
+    if !valid {
+        return Err(error(PROTOCOL_ERROR, "invalid close code"));
+    }
+    let reason = String::from_utf8(reason.to_vec())
+        .map_err(|_| error(INVALID_PAYLOAD, "close reason is not UTF-8"))?;
+    Ok(Some(CloseFrame { code, reason }))
+}
+
+fn truncate(data: &[u8], len: usize) -> &[u8] {
+    &data[..data.len().min(len)]
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().expect("4 bytes"));
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, s) in digest.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let data = input.trim_end_matches('=');
+    if input.len() - data.len() > 2 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in data.bytes() {
+        let value = BASE64.iter().position(|&b| b == byte)? as u32;
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        // The example of RFC 6455 1.3.
+        let req = HttpRequest::try_from(
+            "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+        assert!(is_upgrade(&req));
+        let response = handshake(&req).unwrap();
+        assert_eq!(StatusCode::SWITCHING_PROTOCOLS, response.status());
+        assert_eq!(
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            response.headers().get("Sec-WebSocket-Accept")
+        );
+
+        let cases = [
+            ("GET / HTTP/1.1\r\n\r\n", HandshakeError::NotUpgrade),
+            (
+                "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 8\r\n\r\n",
+                HandshakeError::UnsupportedVersion,
+            ),
+            (
+                "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n\r\n",
+                HandshakeError::InvalidKey,
+            ),
+        ];
+        for (req, expected) in cases {
+            let req = HttpRequest::try_from(req).unwrap();
+            assert_eq!(expected, handshake(&req).unwrap_err());
+        }
+    }
+
+    #[test]
+    fn test_masked_frames() {
+        // A masked "Hello" from RFC 6455 5.7.
+        let mut server = Codec::new(Role::Server);
+        server.feed(&[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d]);
+        assert_eq!(None, server.next_message().unwrap());
+        server.feed(&[0x51, 0x58]);
+        assert_eq!(
+            Some(Message::Text("Hello".into())),
+            server.next_message().unwrap()
+        );
+
+        let mut out = Vec::new();
+        server.encode(&Message::Text("Hello".into()), &mut out);
+        assert_eq!(vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f], out);
+
+        // Unmasked frames from a client are refused.
+        let mut server = Codec::new(Role::Server);
+        server.feed(&out);
+        assert_eq!(PROTOCOL_ERROR, server.next_message().unwrap_err().code);
+    }
+
+    #[test]
+    fn test_fragmentation_and_control_frames() {
+        let mut client = Codec::new(Role::Client).fragment_size(4);
+        let mut server = Codec::new(Role::Server);
+        let mut wire = Vec::new();
+        client.encode(&Message::Binary(b"0123456789".to_vec()), &mut wire);
+        // A ping between the fragments is answered first.
+        let second_fragment = 2 + 4 + 4;
+        let mut ping = Vec::new();
+        client.encode(&Message::Ping(b"p".to_vec()), &mut ping);
+        wire.splice(second_fragment..second_fragment, ping);
+        client.encode(
+            &Message::Close(Some(CloseFrame {
+                code: 1000,
+                reason: "bye".into(),
+            })),
+            &mut wire,
+        );
+        server.feed(&wire);
+
+        assert_eq!(
+            Some(Message::Ping(b"p".to_vec())),
+            server.next_message().unwrap()
+        );
+        assert_eq!(
+            Some(Message::Binary(b"0123456789".to_vec())),
+            server.next_message().unwrap()
+        );
+        let close = CloseFrame {
+            code: 1000,
+            reason: "bye".into(),
+        };
+        assert_eq!(
+            Some(Message::Close(Some(close))),
+            server.next_message().unwrap()
+        );
+        assert_eq!(None, server.next_message().unwrap());
+
+        let mut large = Vec::new();
+        let mut server = Codec::new(Role::Server).fragment_size(1 << 20);
+        server.encode(&Message::Binary(vec![0; 70_000]), &mut large);
+        assert_eq!([0x82, 127], large[..2]);
+        let mut client = Codec::new(Role::Client);
+        client.feed(&large);
+        assert_eq!(
+            Some(Message::Binary(vec![0; 70_000])),
+            client.next_message().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let cases: [(&[u8], u16); 5] = [
+            // Continuation with nothing to continue.
+            (&[0x80, 0x80, 0, 0, 0, 0], PROTOCOL_ERROR),
+            // Fragmented ping.
+            (&[0x09, 0x80, 0, 0, 0, 0], PROTOCOL_ERROR),
+            // Reserved close code.
+            (&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xed], PROTOCOL_ERROR),
+            // Text that isn't UTF-8.
+            (&[0x81, 0x81, 0, 0, 0, 0, 0xff], INVALID_PAYLOAD),
+            // Longer than the limit.
+            (&[0x82, 0xfe, 0x10, 0x00], MESSAGE_TOO_BIG),
+        ];
+        for (input, code) in cases {
+            let mut server = Codec::new(Role::Server).max_message_size(1024);
+            server.feed(input);
+            assert_eq!(code, server.next_message().unwrap_err().code, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_sha1_and_base64() {
+        let digest = sha1(b"abc");
+        assert_eq!("qZk+NkcGgWq6PiVxeFDCbJzQ2J0=", base64_encode(&digest));
+        assert_eq!(Some(b"abc".to_vec()), base64_decode("YWJj"));
+        assert_eq!(Some(b"ab".to_vec()), base64_decode("YWI="));
+        assert_eq!(None, base64_decode("YWJ"));
+    }
+}
@@ -0,0 +1,160 @@
+// Load test comparing the blocking and async server modes on the same
+// handlers. Run with `cargo bench -p httpserver`; tune it with
+// BENCH_CONNECTIONS, BENCH_REQUESTS (per connection) and BENCH_IDLE (extra
+// keep-alive connections held open without sending anything).
+
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use http::{
+    httprequest::HttpRequest,
+    httpresponse::{HttpResponse, StatusCode},
+};
+use httpserver::{
+    handler::{HandlerError, RequestContext, WebServiceHandler},
+    router::Router,
+    server::{Backpressure, Mode, Server, ServerConfig},
+    static_files::StaticPageHandler,
+};
+
+const PATHS: [&str; 2] = ["/health", "/api/shipping/orders"];
+
+fn main() {
+    let connections = env_var("BENCH_CONNECTIONS", 50);
+    let requests = env_var("BENCH_REQUESTS", 200);
+    let idle = env_var("BENCH_IDLE", 0);
+    println!(
+        "{} connections x {} requests, {} idle connections",
+        connections, requests, idle
+    );
+
+    for mode in [Mode::Blocking, Mode::Async] {
+        let addr = start_server(mode, connections + idle);
+        // Held open for the whole run to show what idle clients cost.
+        let _idle: Vec<TcpStream> = (0..idle)
+            .map(|_| TcpStream::connect(&addr).expect("idle connection"))
+            .collect();
+
+        let started = Instant::now();
+        let clients: Vec<_> = (0..connections)
+            .map(|_| {
+                let addr = addr.clone();
+                thread::spawn(move || run_client(&addr, requests))
+            })
+            .collect();
+        let mut latencies: Vec<Duration> = clients
+            .into_iter()
+            .flat_map(|client| client.join().unwrap().expect("client failed"))
+            .collect();
+        let elapsed = started.elapsed();
+
+        latencies.sort();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "{:?}: {:.0} req/s, p50 {:?}, p99 {:?}",
+            mode,
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(50),
+            percentile(99)
+        );
+    }
+}
+
+fn env_var(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Runs a server on a free local port in the background and waits until it
+// accepts connections.
+fn start_server(mode: Mode, connections: usize) -> String {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("free port")
+        .port();
+    let addr = format!("127.0.0.1:{}", port);
+
+    let config = ServerConfig {
+        mode,
+        // Blocking mode parks a thread per open connection.
+        workers: connections,
+        backpressure: Backpressure::Block,
+        keep_alive_timeout: Duration::from_secs(60),
+        max_requests_per_connection: usize::MAX,
+        ..ServerConfig::default()
+    };
+    // The default routes minus the access log, which would dominate.
+    let mut router = Router::new();
+    router
+        .get("/health", health)
+        .get("/api/shipping/orders", WebServiceHandler::default())
+        .get("/{*path}", StaticPageHandler::default());
+
+    let server_addr = addr.clone();
+    thread::spawn(move || {
+        Server::with_config(&server_addr, config)
+            .router(router)
+            .run()
+    });
+
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server on {} did not start", addr);
+}
+
+// Stands in for a handler that never touches the disk.
+fn health(_: &HttpRequest, _: &RequestContext) -> Result<HttpResponse, HandlerError> {
+    Ok(HttpResponse::builder().status(StatusCode::OK).body("ok"))
+}
+
+// Sends `requests` requests over one keep-alive connection, returning the
+// latency of each.
+fn run_client(addr: &str, requests: usize) -> io::Result<Vec<Duration>> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut latencies = Vec::with_capacity(requests);
+    for i in 0..requests {
+        let path = PATHS[i % PATHS.len()];
+        let started = Instant::now();
+        write!(writer, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+        read_response(&mut reader)?;
+        latencies.push(started.elapsed());
+    }
+    Ok(latencies)
+}
+
+fn read_response(reader: &mut BufReader<TcpStream>) -> io::Result<()> {
+    let mut content_length = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)
+}
@@ -0,0 +1,391 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use http::{
+    h2::{self, ServerConnection},
+    httprequest::Method,
+    httpresponse::{HttpResponse, StatusCode},
+    parser::RequestParser,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime,
+    task::{self, JoinSet},
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::connection::{error_response, finalize, parsed, Next, SWITCHING_TO_H2C};
+use crate::router::Router;
+use crate::server::ServerConfig;
+use crate::shutdown::ShutdownHandle;
+use crate::websocket::Session;
+
+// Serves every connection as a task on a multi-threaded tokio runtime, so an
+// idle keep-alive connection costs a small task rather than a parked thread.
+pub fn run(
+    socket_addr: &str,
+    router: Arc<Router>,
+    config: ServerConfig,
+    shutdown: ShutdownHandle,
+    tls: Option<Arc<rustls::ServerConfig>>,
+) -> io::Result<()> {
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+    let tls = tls.map(TlsAcceptor::from);
+    let serve = accept_loop(socket_addr, router, Arc::new(config), shutdown, tls);
+    let result = runtime.block_on(serve);
+    // Connections still open after the drain timeout are simply dropped.
+    runtime.shutdown_background();
+    result
+}
+
+async fn accept_loop(
+    socket_addr: &str,
+    router: Arc<Router>,
+    config: Arc<ServerConfig>,
+    shutdown: ShutdownHandle,
+    tls: Option<TlsAcceptor>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(socket_addr).await?;
+    println!("Running on {} in async mode", socket_addr);
+
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            _ = shutdown.wait() => break,
+            // Reap finished connections so the set doesn't grow unbounded.
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = listener.accept() => accepted,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // Usually out of file descriptors; give connections a moment
+                // to close instead of spinning.
+                eprintln!("Accept error: {}", e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let router = Arc::clone(&router);
+        let config = Arc::clone(&config);
+        let shutdown = shutdown.clone();
+        let tls = tls.clone();
+        connections.spawn(async move {
+            if let Err(e) = serve(stream, router, &config, &shutdown, tls).await {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+
+    drop(listener);
+    println!("Shutting down, draining connections");
+    let drain = async { while connections.join_next().await.is_some() {} };
+    if time::timeout(config.drain_timeout, drain).await.is_err() {
+        eprintln!(
+            "Drain timeout expired with {} connections open",
+            connections.len()
+        );
+    }
+    Ok(())
+}
+
+async fn serve(
+    stream: TcpStream,
+    router: Arc<Router>,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+    tls: Option<TlsAcceptor>,
+) -> io::Result<()> {
+    let peer_addr = stream.peer_addr().ok();
+    let Some(tls) = tls else {
+        let h2c = config.http2;
+        return handle_connection(stream, peer_addr, router, config, shutdown, h2c).await;
+    };
+    // A client that stalls the handshake gets no longer than an idle one.
+    let mut stream = time::timeout(config.keep_alive_timeout, tls.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        let connection = ServerConnection::new(config.limits);
+        serve_h2(
+            &mut stream,
+            connection,
+            &[],
+            peer_addr,
+            router,
+            config,
+            shutdown,
+        )
+        .await?;
+    } else {
+        handle_connection(&mut stream, peer_addr, router, config, shutdown, false).await?;
+    }
+    // Sends close_notify so the client can tell the response is complete.
+    stream.shutdown().await
+}
+
+// The async counterpart of `connection::handle_connection`, with the same
+// keep-alive, pipelining, timeout, shutdown and HTTP/2 switching behaviour.
+async fn handle_connection(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    peer_addr: Option<SocketAddr>,
+    router: Arc<Router>,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+    h2c: bool,
+) -> io::Result<()> {
+    let mut parser = RequestParser::new(config.limits);
+    let mut served = 0;
+
+    loop {
+        let req = match next_request(&mut stream, &mut parser, config, shutdown).await? {
+            Next::Request(req) => req,
+            Next::Http2 => {
+                let connection = ServerConnection::new(config.limits);
+                let received = parser.buffered();
+                return serve_h2(
+                    &mut stream,
+                    connection,
+                    received,
+                    peer_addr,
+                    router,
+                    config,
+                    shutdown,
+                )
+                .await;
+            }
+            Next::Closed => return Ok(()),
+            Next::Reject(response) => return send(&mut stream, &response, false).await,
+        };
+        served += 1;
+
+        if let Some(settings) = h2::h2c_upgrade(&req).filter(|_| h2c) {
+            let settings = settings.to_string();
+            let connection = match ServerConnection::upgrade(config.limits, &settings, req) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    let response = error_response(StatusCode::BAD_REQUEST, e.reason);
+                    return send(&mut stream, &response, false).await;
+                }
+            };
+            stream.write_all(SWITCHING_TO_H2C).await?;
+            let received = parser.buffered();
+            return serve_h2(
+                &mut stream,
+                connection,
+                received,
+                peer_addr,
+                router,
+                config,
+                shutdown,
+            )
+            .await;
+        }
+
+        // Handlers read files synchronously; let the runtime move other tasks
+        // off this worker while they do.
+        let response = task::block_in_place(|| router.route(&req, peer_addr));
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            if let Some((handler, ctx)) = router.websocket_handler(&req, peer_addr) {
+                send(&mut stream, &response, false).await?;
+                let max_message_size = config.limits.max_body_size;
+                let received = parser.buffered();
+                let session = task::block_in_place(|| {
+                    Session::open(handler, &ctx, max_message_size, received)
+                });
+                return serve_websocket(&mut stream, session, config, shutdown).await;
+            }
+        }
+        let closing = shutdown.is_shutting_down();
+        let (response, keep_alive) = finalize(&req, response, served, config, closing);
+        send(&mut stream, &response, req.method == Method::Head).await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+async fn next_request(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    parser: &mut RequestParser,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<Next> {
+    if !parser.buffered().is_empty() {
+        if let Some(next) = parsed(parser.parse(), config) {
+            return Ok(next);
+        }
+    }
+
+    let mut read_buffer = [0; 4096];
+    loop {
+        let read = time::timeout(config.keep_alive_timeout, stream.read(&mut read_buffer));
+        // Only a connection between requests is closed on shutdown.
+        let read = if parser.is_idle() {
+            tokio::select! {
+                read = read => read,
+                _ = shutdown.wait() => return Ok(Next::Closed),
+            }
+        } else {
+            read.await
+        };
+        let read = match read {
+            Ok(Ok(0)) => return Ok(Next::Closed),
+            Ok(Ok(read)) => read,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Ok(Err(e)) => return Err(e),
+            Err(_) if parser.is_idle() => return Ok(Next::Closed),
+            Err(_) => {
+                let response = error_response(StatusCode::REQUEST_TIMEOUT, "request timeout");
+                return Ok(Next::Reject(response));
+            }
+        };
+        if let Some(next) = parsed(parser.feed(&read_buffer[..read]), config) {
+            return Ok(next);
+        }
+        if parser.expects_continue() {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+    }
+}
+
+// The async counterpart of `connection::serve_h2`, except that requests on
+// different streams are routed concurrently on the blocking pool.
+async fn serve_h2(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    mut connection: ServerConnection,
+    received: &[u8],
+    peer_addr: Option<SocketAddr>,
+    router: Arc<Router>,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<()> {
+    let mut result = connection.feed(received);
+    let mut handlers = JoinSet::new();
+    let mut going_away = false;
+    let mut read_buffer = vec![0; 16 * 1024];
+    loop {
+        while let Some((id, req)) = connection.next_request() {
+            let router = Arc::clone(&router);
+            handlers.spawn(async move {
+                let head_only = req.method == Method::Head;
+                let response = task::spawn_blocking(move || router.route(&req, peer_addr))
+                    .await
+                    .unwrap_or_else(|_| {
+                        error_response(StatusCode::INTERNAL_SERVER_ERROR, "handler failed")
+                    });
+                (id, response, head_only)
+            });
+        }
+        let output = connection.take_output();
+        if !output.is_empty() {
+            stream.write_all(&output).await?;
+            stream.flush().await?;
+        }
+        if let Err(e) = result {
+            eprintln!("Closing HTTP/2 connection: {}", e);
+            return Ok(());
+        }
+        if connection.is_done() && handlers.is_empty() {
+            return Ok(());
+        }
+
+        tokio::select! {
+            Some(handled) = handlers.join_next(), if !handlers.is_empty() => {
+                if let Ok((id, response, head_only)) = handled {
+                    connection.send_response(id, &response, head_only);
+                }
+            }
+            _ = shutdown.wait(), if !going_away => {
+                going_away = true;
+                connection.go_away();
+            }
+            read = time::timeout(config.keep_alive_timeout, stream.read(&mut read_buffer)) => {
+                match read {
+                    Ok(Ok(0)) => return Ok(()),
+                    Ok(Ok(read)) => result = connection.feed(&read_buffer[..read]),
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Ok(Err(e)) => return Err(e),
+                    // Waiting on handlers isn't idling.
+                    Err(_) if !handlers.is_empty() => {}
+                    Err(_) if connection.is_idle() => connection.go_away(),
+                    // A client that stops reading responses is dropped.
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// The async counterpart of `connection::serve_websocket`, writing messages
+// as soon as they are queued rather than polling for them.
+async fn serve_websocket(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    mut session: Session,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<()> {
+    let mut going_away = false;
+    let mut last_read = time::Instant::now();
+    let mut read_buffer = vec![0; 16 * 1024];
+    loop {
+        let output = session.take_output();
+        if !output.is_empty() {
+            let write = async {
+                stream.write_all(&output).await?;
+                stream.flush().await
+            };
+            match time::timeout(config.write_timeout, write).await {
+                Ok(written) => written?,
+                Err(_) => {
+                    eprintln!("Dropping WebSocket client that stopped reading");
+                    return Ok(());
+                }
+            }
+        }
+        if session.is_closed() || session.overflowed() {
+            return Ok(());
+        }
+
+        let deadline = last_read + config.keep_alive_timeout;
+        tokio::select! {
+            Some(message) = session.next_outgoing() => {
+                session.send(message);
+                session.flush_outgoing();
+            }
+            _ = shutdown.wait(), if !going_away => {
+                going_away = true;
+                session.going_away();
+            }
+            read = time::timeout_at(deadline, stream.read(&mut read_buffer)) => {
+                match read {
+                    Ok(Ok(0)) => return Ok(()),
+                    Ok(Ok(read)) => {
+                        last_read = time::Instant::now();
+                        task::block_in_place(|| session.received(&read_buffer[..read]));
+                    }
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) if session.idle() => last_read = time::Instant::now(),
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: &HttpResponse,
+    head_only: bool,
+) -> io::Result<()> {
+    // Serialized up front so the head and body go out in a single write.
+    let mut serialized = Vec::with_capacity(256 + response.body().len());
+    if head_only {
+        response.send_head(&mut serialized)?;
+    } else {
+        response.send_response(&mut serialized)?;
+    }
+    stream.write_all(&serialized).await
+}
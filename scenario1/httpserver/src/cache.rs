@@ -0,0 +1,272 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+// Values derived from files, their contents or parsed data, kept until the
+// file changes. Every lookup compares the file's current size and
+// modification time with those it was loaded at. Once there are more than
+// `max_entries` entries or their files add up to more than `max_bytes`, the
+// least recently used are dropped; larger files are never cached.
+pub struct FileCache<V> {
+    max_entries: usize,
+    max_bytes: u64,
+    lru: Mutex<Lru<V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+// Counters since the cache was created, for monitoring.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+impl<V> FileCache<V> {
+    pub fn new(max_entries: usize, max_bytes: u64) -> Self {
+        FileCache {
+            max_entries,
+            max_bytes,
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    // The value for the file at `path`, whose metadata is `meta`. Calls `load`
+    // if there is none yet or the file changed since.
+    pub fn get_or_load<E>(
+        &self,
+        path: &Path,
+        meta: &Metadata,
+        load: impl FnOnce() -> Result<V, E>,
+    ) -> Result<Arc<V>, E> {
+        let stamp = Stamp::of(meta);
+        if let Some(value) = self.lock().get(path, stamp) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Loaded without the lock so a slow file doesn't hold up the others.
+        let value = Arc::new(load()?);
+        if self.max_entries > 0 && stamp.len <= self.max_bytes {
+            let mut lru = self.lock();
+            lru.insert(path.to_path_buf(), stamp, Arc::clone(&value));
+            let evicted = lru.shrink(self.max_entries, self.max_bytes);
+            self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        }
+        Ok(value)
+    }
+
+    // Drops the entry for `path`, for writers that know the file changed.
+    pub fn invalidate(&self, path: &Path) {
+        self.lock().remove(path);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: lru.entries.len(),
+            bytes: lru.bytes,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru<V>> {
+        self.lru.lock().unwrap()
+    }
+}
+
+// What a file looked like when its entry was loaded. Writes within the
+// file system's timestamp granularity that keep the size go unnoticed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Stamp {
+    pub(crate) fn of(meta: &Metadata) -> Self {
+        Stamp {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        }
+    }
+}
+
+struct Entry<V> {
+    value: Arc<V>,
+    stamp: Stamp,
+    used: u64,
+}
+
+struct Lru<V> {
+    entries: HashMap<PathBuf, Entry<V>>,
+    // When each entry was last used, oldest first.
+    order: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl<V> Lru<V> {
+    fn get(&mut self, path: &Path, stamp: Stamp) -> Option<Arc<V>> {
+        let entry = self.entries.get_mut(path)?;
+        if entry.stamp != stamp {
+            self.remove(path);
+            return None;
+        }
+        self.tick += 1;
+        let path = self.order.remove(&entry.used)?;
+        entry.used = self.tick;
+        self.order.insert(self.tick, path);
+        Some(Arc::clone(&entry.value))
+    }
+
+    fn insert(&mut self, path: PathBuf, stamp: Stamp, value: Arc<V>) {
+        self.remove(&path);
+        self.tick += 1;
+        self.bytes += stamp.len;
+        self.order.insert(self.tick, path.clone());
+        let used = self.tick;
+        self.entries.insert(path, Entry { value, stamp, used });
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.stamp.len;
+        }
+    }
+
+    // Drops the least recently used entries until both limits hold. Returns
+    // how many were dropped.
+    fn shrink(&mut self, max_entries: usize, max_bytes: u64) -> u64 {
+        let mut evicted = 0;
+        while self.entries.len() > max_entries || self.bytes > max_bytes {
+            let Some((_, path)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&path) {
+                self.bytes -= entry.stamp.len;
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, fs, io};
+
+    fn read(cache: &FileCache<String>, path: &Path) -> String {
+        let meta = fs::metadata(path).unwrap();
+        let value = cache
+            .get_or_load(path, &meta, || fs::read_to_string(path))
+            .unwrap();
+        value.to_string()
+    }
+
+    #[test]
+    fn test_hits_and_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("orders.json");
+        fs::write(&path, "[]").unwrap();
+        let cache = FileCache::new(8, 1024);
+
+        assert_eq!("[]", read(&cache, &path));
+        assert_eq!("[]", read(&cache, &path));
+        let stats = cache.stats();
+        assert_eq!(
+            (1, 1, 1, 2),
+            (stats.hits, stats.misses, stats.entries, stats.bytes)
+        );
+
+        fs::write(&path, "[1]").unwrap();
+        assert_eq!("[1]", read(&cache, &path));
+        let stats = cache.stats();
+        assert_eq!(
+            (1, 2, 1, 3),
+            (stats.hits, stats.misses, stats.entries, stats.bytes)
+        );
+
+        // Failed loads are not cached.
+        let meta = fs::metadata(dir.path()).unwrap();
+        let failed: Result<_, io::Error> =
+            cache.get_or_load(dir.path(), &meta, || Err(io::ErrorKind::Other.into()));
+        assert!(failed.is_err());
+        assert_eq!(1, cache.stats().entries);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = (0..4)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.txt", i));
+                fs::write(&path, "0123456789").unwrap();
+                path
+            })
+            .collect();
+
+        let cache = FileCache::new(2, 1024);
+        read(&cache, &paths[0]);
+        read(&cache, &paths[1]);
+        read(&cache, &paths[0]);
+        read(&cache, &paths[2]);
+        let stats = cache.stats();
+        assert_eq!((2, 1, 20), (stats.entries, stats.evictions, stats.bytes));
+        // 1 was least recently used, 0 is still there.
+        read(&cache, &paths[0]);
+        assert_eq!(2, cache.stats().hits);
+        read(&cache, &paths[1]);
+        assert_eq!(2, cache.stats().hits);
+
+        let cache = FileCache::new(8, 25);
+        for path in &paths {
+            read(&cache, path);
+        }
+        let stats = cache.stats();
+        assert_eq!((2, 2, 20), (stats.entries, stats.evictions, stats.bytes));
+
+        fs::write(&paths[3], "x".repeat(30)).unwrap();
+        read(&cache, &paths[3]);
+        assert_eq!(1, cache.stats().entries);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let meta = fs::metadata(dir.path()).unwrap();
+        let cache = FileCache::new(0, 1024);
+        for _ in 0..2 {
+            let value = cache.get_or_load(dir.path(), &meta, || Ok::<_, Infallible>(1));
+            assert_eq!(Ok(Arc::new(1)), value);
+        }
+        assert_eq!(2, cache.stats().misses);
+        assert_eq!(0, cache.stats().entries);
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+// A content coding from `Accept-Encoding` and `Content-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    // The suffix of a precompressed sibling, `app.js.br` for `app.js`.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Gzip => Some(".gz"),
+            Encoding::Brotli => Some(".br"),
+            Encoding::Identity | Encoding::Deflate => None,
+        }
+    }
+
+    fn matches(&self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (*self == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+}
+
+// Picks the coding for a response out of `available`, which is in order of
+// preference. The client's highest `q` wins, ties go to the earlier entry.
+// Identity when there is no header or none of them is acceptable, unless
+// the client refuses that too with `identity;q=0` or `*;q=0`, which leaves
+// nothing to send (RFC 9110 section 12.5.3).
+pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+    let Some(header) = accept_encoding else {
+        return Some(Encoding::Identity);
+    };
+    let codings: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim();
+            let mut q = 1.0;
+            for param in parts {
+                if let Some((name, value)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        q = value
+                            .trim()
+                            .parse()
+                            .ok()
+                            .filter(|q| (0.0..=1.0).contains(q))?;
+                    }
+                }
+            }
+            (!coding.is_empty()).then_some((coding, q))
+        })
+        .collect();
+    let quality = |encoding: &Encoding| {
+        let explicit = codings.iter().find(|(c, _)| encoding.matches(c));
+        let wildcard = codings.iter().find(|(c, _)| *c == "*");
+        explicit.or(wildcard).map_or(0.0, |&(_, q)| q)
+    };
+
+    let mut best = None;
+    let mut best_q = 0.0;
+    for encoding in available {
+        let q = quality(encoding);
+        if q > best_q {
+            best = Some(*encoding);
+            best_q = q;
+        }
+    }
+    // Identity is acceptable unless it is refused, by name or through `*`.
+    let identity = codings
+        .iter()
+        .find(|(c, _)| Encoding::Identity.matches(c))
+        .or(codings.iter().find(|(c, _)| *c == "*"))
+        .is_none_or(|&(_, q)| q > 0.0);
+    best.or(identity.then_some(Encoding::Identity))
+}
+
+// Encodes `body`. Brotli uses a middling quality; the top levels cost far
+// more time than they save bytes for responses built per request.
+pub fn compress(encoding: Encoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(body.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut compressed = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                encoder.write_all(body)?;
+            }
+            Ok(compressed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use Encoding::*;
+
+    const ALL: &[Encoding] = &[Brotli, Gzip, Deflate];
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Some(Identity), negotiate(None, ALL));
+        assert_eq!(Some(Brotli), negotiate(Some("gzip, deflate, br"), ALL));
+        assert_eq!(
+            Some(Gzip),
+            negotiate(Some("gzip, deflate, br"), &[Gzip, Deflate])
+        );
+        assert_eq!(Some(Gzip), negotiate(Some("br;q=0.5, GZIP"), ALL));
+        assert_eq!(Some(Gzip), negotiate(Some("x-gzip"), ALL));
+        assert_eq!(
+            Some(Deflate),
+            negotiate(Some("*;q=0.1, deflate;q=0.8, br;q=0"), ALL)
+        );
+        assert_eq!(Some(Brotli), negotiate(Some("*"), ALL));
+        assert_eq!(Some(Identity), negotiate(Some("identity"), ALL));
+        assert_eq!(Some(Identity), negotiate(Some("gzip;q=0, br;q=2"), ALL));
+        assert_eq!(Some(Identity), negotiate(Some("gzip"), &[]));
+
+        // Identity refused.
+        assert_eq!(Some(Gzip), negotiate(Some("gzip, identity;q=0"), ALL));
+        assert_eq!(None, negotiate(Some("br, identity;q=0"), &[Gzip]));
+        assert_eq!(None, negotiate(Some("*;q=0"), ALL));
+        assert_eq!(Some(Identity), negotiate(Some("*;q=0, identity"), &[Gzip]));
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let body = br#"[{"order_id":1,"order_status":"Delivered"}]"#.repeat(20);
+
+        let mut decoded = Vec::new();
+        let gzip = compress(Gzip, &body).unwrap();
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(body, decoded);
+
+        decoded.clear();
+        let deflate = compress(Deflate, &body).unwrap();
+        flate2::read::ZlibDecoder::new(&deflate[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(body, decoded);
+
+        decoded.clear();
+        let br = compress(Brotli, &body).unwrap();
+        brotli::Decompressor::new(&br[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(body, decoded);
+        assert!(br.len() < body.len() / 4);
+    }
+}
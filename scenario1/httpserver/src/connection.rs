@@ -0,0 +1,654 @@
+use std::{
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use http::{
+    h2::{self, ServerConnection},
+    httprequest::{HttpRequest, Method, Version},
+    httpresponse::{HttpResponse, StatusCode},
+    parser::{ParseError, RequestParser, Status},
+};
+use serde_json::Map;
+
+use crate::content::Format;
+use crate::handler;
+use crate::router::Router;
+use crate::server::ServerConfig;
+use crate::shutdown::ShutdownHandle;
+use crate::websocket::Session;
+
+// How often a connection waiting for a request checks for shutdown.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(250);
+
+// How often a WebSocket connection checks for messages to send, which
+// other threads may queue at any time.
+const WEBSOCKET_POLL: Duration = Duration::from_millis(20);
+
+// What comes next from a client speaking HTTP/1.
+#[allow(clippy::large_enum_variant)]
+pub enum Next {
+    Request(HttpRequest),
+    // The HTTP/2 preface, which stays in the parser's buffer.
+    Http2,
+    Closed,
+    // The response that rejects what the client sent.
+    Reject(HttpResponse),
+}
+
+pub const SWITCHING_TO_H2C: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+// Limits how many connections may keep their worker after switching to
+// HTTP/2 or WebSocket, both of which hold it for as long as the client
+// stays, so that plain requests always find workers left.
+pub struct UpgradeSlots {
+    used: AtomicUsize,
+    max: usize,
+}
+
+impl UpgradeSlots {
+    pub fn new(max: usize) -> Self {
+        UpgradeSlots {
+            used: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    fn try_acquire(&self) -> Option<UpgradeSlot<'_>> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used < self.max).then_some(used + 1)
+            })
+            .ok()
+            .map(|_| UpgradeSlot(self))
+    }
+}
+
+// Held for the life of an upgraded connection.
+struct UpgradeSlot<'a>(&'a UpgradeSlots);
+
+impl Drop for UpgradeSlot<'_> {
+    fn drop(&mut self) {
+        self.0.used.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// The TLS settings of a blocking server, with a copy that doesn't offer h2
+// through ALPN for when no upgrade slot is free.
+pub struct TlsConfigs {
+    config: Arc<rustls::ServerConfig>,
+    http1: Arc<rustls::ServerConfig>,
+}
+
+impl TlsConfigs {
+    pub fn new(config: Arc<rustls::ServerConfig>) -> Self {
+        let mut http1 = (*config).clone();
+        http1.alpn_protocols.retain(|protocol| protocol != b"h2");
+        TlsConfigs {
+            config,
+            http1: Arc::new(http1),
+        }
+    }
+}
+
+// A client connection, plain or TLS.
+trait Transport: Read + Write {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Transport for &TcpStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+impl Transport for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.sock.set_read_timeout(Some(timeout))
+    }
+}
+
+// Serves requests from one client until either side closes the connection,
+// it sits idle for longer than the keep-alive timeout, the request limit is
+// reached or a write stalls past the write timeout. Pipelined requests are
+// answered in the order they arrived. Once shutdown starts, an idle
+// connection is closed and a busy one after its current response. With
+// `tls` the connection is HTTPS, and HTTP/2 when the client picks it
+// through ALPN. Switching to HTTP/2 or WebSocket takes one of `upgrades`;
+// with none free, the client stays on HTTP/1.1.
+pub fn handle_connection(
+    stream: TcpStream,
+    router: &Router,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+    tls: Option<&TlsConfigs>,
+    upgrades: &UpgradeSlots,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(config.keep_alive_timeout.min(SHUTDOWN_POLL)))?;
+    stream.set_write_timeout(Some(config.write_timeout))?;
+    let peer_addr = stream.peer_addr().ok();
+    let Some(tls) = tls else {
+        return serve(
+            &mut &stream,
+            peer_addr,
+            router,
+            config,
+            shutdown,
+            config.http2,
+            upgrades,
+        );
+    };
+    // Only offers h2 if the connection can keep its slot should the client
+    // pick it.
+    let slot = if config.http2 {
+        upgrades.try_acquire()
+    } else {
+        None
+    };
+    let tls = if slot.is_some() {
+        &tls.config
+    } else {
+        &tls.http1
+    };
+    let connection = rustls::ServerConnection::new(Arc::clone(tls)).map_err(io::Error::other)?;
+    let mut stream = rustls::StreamOwned::new(connection, stream);
+    // The protocol is only known once the handshake is over, which a client
+    // gets no longer than an idle connection to finish.
+    let deadline = Instant::now() + config.keep_alive_timeout;
+    while stream.conn.is_handshaking() {
+        match stream.conn.complete_io(&mut stream.sock) {
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) && Instant::now() < deadline => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if stream.conn.alpn_protocol() == Some(b"h2") {
+        let connection = ServerConnection::new(config.limits);
+        serve_h2(
+            &mut stream,
+            connection,
+            &[],
+            peer_addr,
+            router,
+            config,
+            shutdown,
+        )?;
+    } else {
+        drop(slot);
+        serve(
+            &mut stream,
+            peer_addr,
+            router,
+            config,
+            shutdown,
+            false,
+            upgrades,
+        )?;
+    }
+    // Lets the client tell a complete response from a truncated one.
+    stream.conn.send_close_notify();
+    stream.flush()
+}
+
+// `h2c` allows switching to HTTP/2 through `Upgrade: h2c`, which only
+// applies to plain TCP.
+fn serve(
+    stream: &mut impl Transport,
+    peer_addr: Option<SocketAddr>,
+    router: &Router,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+    h2c: bool,
+    upgrades: &UpgradeSlots,
+) -> io::Result<()> {
+    let mut parser = RequestParser::new(config.limits);
+    let mut served = 0;
+
+    loop {
+        let req = match next_request(stream, &mut parser, config, shutdown)? {
+            Next::Request(req) => req,
+            Next::Http2 => {
+                let mut connection = ServerConnection::new(config.limits);
+                let Some(_slot) = upgrades.try_acquire() else {
+                    // Refuses before any stream is processed, so the client
+                    // can safely retry.
+                    connection.go_away();
+                    return stream.write_all(&connection.take_output());
+                };
+                let received = parser.buffered();
+                return serve_h2(
+                    stream, connection, received, peer_addr, router, config, shutdown,
+                );
+            }
+            Next::Closed => return Ok(()),
+            Next::Reject(response) => return send(stream, &response, false),
+        };
+        served += 1;
+
+        // The Upgrade is ignored, as the client allows, without a free slot.
+        let upgrade = h2::h2c_upgrade(&req)
+            .filter(|_| h2c)
+            .and_then(|settings| Some((settings.to_string(), upgrades.try_acquire()?)));
+        if let Some((settings, _slot)) = upgrade {
+            let connection = match ServerConnection::upgrade(config.limits, &settings, req) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    let response = error_response(StatusCode::BAD_REQUEST, e.reason);
+                    return send(stream, &response, false);
+                }
+            };
+            stream.write_all(SWITCHING_TO_H2C)?;
+            let received = parser.buffered();
+            return serve_h2(
+                stream, connection, received, peer_addr, router, config, shutdown,
+            );
+        }
+
+        let response = router.route(&req, peer_addr);
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            if let Some((handler, ctx)) = router.websocket_handler(&req, peer_addr) {
+                let Some(_slot) = upgrades.try_acquire() else {
+                    let mut busy =
+                        error_response(StatusCode::SERVICE_UNAVAILABLE, "too many WebSockets");
+                    busy.headers_mut().insert("Retry-After", "1");
+                    return send(stream, &busy, false);
+                };
+                send(stream, &response, false)?;
+                let max_message_size = config.limits.max_body_size;
+                let session = Session::open(handler, &ctx, max_message_size, parser.buffered());
+                return serve_websocket(stream, session, config, shutdown);
+            }
+        }
+        let closing = shutdown.is_shutting_down();
+        let (response, keep_alive) = finalize(&req, response, served, config, closing);
+        send(stream, &response, req.method == Method::Head)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Serves an HTTP/2 connection, `received` being what was read before
+// switching to it. Requests are routed one at a time, in the order they
+// complete, while flow control interleaves the responses. Once shutdown
+// starts or the connection sits idle past the keep-alive timeout, a GOAWAY
+// lets the client finish its open streams and go.
+fn serve_h2(
+    stream: &mut (impl Read + Write),
+    mut connection: ServerConnection,
+    received: &[u8],
+    peer_addr: Option<SocketAddr>,
+    router: &Router,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<()> {
+    let mut result = connection.feed(received);
+    let mut last_read = Instant::now();
+    let mut read_buffer = [0; 16 * 1024];
+    loop {
+        while let Some((id, req)) = connection.next_request() {
+            let response = router.route(&req, peer_addr);
+            connection.send_response(id, &response, req.method == Method::Head);
+        }
+        if shutdown.is_shutting_down() {
+            connection.go_away();
+        }
+        let output = connection.take_output();
+        if !output.is_empty() {
+            stream.write_all(&output)?;
+            stream.flush()?;
+        }
+        if let Err(e) = result {
+            eprintln!("Closing HTTP/2 connection: {}", e);
+            return Ok(());
+        }
+        if connection.is_done() {
+            return Ok(());
+        }
+
+        match stream.read(&mut read_buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => {
+                last_read = Instant::now();
+                result = connection.feed(&read_buffer[..read]);
+            }
+            Err(e) if is_timeout(&e) => {
+                if last_read.elapsed() < config.keep_alive_timeout {
+                    continue;
+                }
+                // A client that stops reading responses is dropped.
+                if !connection.is_idle() {
+                    return Ok(());
+                }
+                connection.go_away();
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Serves a WebSocket connection after the handshake, keeping the thread
+// for as long as it is open. A quiet client is pinged after the keep-alive
+// timeout and dropped if it stays quiet; once shutdown starts, the client
+// is told the server is going away. A client that stops reading is dropped
+// when a write times out or its queue overflows.
+fn serve_websocket(
+    stream: &mut impl Transport,
+    mut session: Session,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<()> {
+    stream.set_read_timeout(WEBSOCKET_POLL)?;
+    let mut last_read = Instant::now();
+    let mut read_buffer = [0; 16 * 1024];
+    loop {
+        session.flush_outgoing();
+        if shutdown.is_shutting_down() {
+            session.going_away();
+        }
+        let output = session.take_output();
+        if !output.is_empty() {
+            match stream.write_all(&output).and_then(|()| stream.flush()) {
+                Ok(()) => {}
+                Err(e) if is_timeout(&e) => {
+                    eprintln!("Dropping WebSocket client that stopped reading");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if session.is_closed() || session.overflowed() {
+            return Ok(());
+        }
+
+        match stream.read(&mut read_buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => {
+                last_read = Instant::now();
+                session.received(&read_buffer[..read]);
+            }
+            Err(e) if is_timeout(&e) => {
+                if last_read.elapsed() < config.keep_alive_timeout {
+                    continue;
+                }
+                if !session.idle() {
+                    return Ok(());
+                }
+                last_read = Instant::now();
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Decides whether the connection stays open after `req` and marks the
+// response accordingly. `closing` forces the connection shut.
+pub fn finalize(
+    req: &HttpRequest,
+    mut response: HttpResponse,
+    served: usize,
+    config: &ServerConfig,
+    closing: bool,
+) -> (HttpResponse, bool) {
+    let keep_alive = !closing
+        && req.keep_alive()
+        && served < config.max_requests_per_connection
+        && !response
+            .headers()
+            .get("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+
+    if !keep_alive {
+        response.headers_mut().insert("Connection", "close");
+    } else if req.version == Version::V1_0 {
+        response.headers_mut().insert("Connection", "keep-alive");
+    }
+    (response, keep_alive)
+}
+
+// Without a request to negotiate with, these are always JSON.
+pub fn error_response(status: StatusCode, message: &str) -> HttpResponse {
+    let mut response = handler::error_response(status, message, Map::new(), Format::Json);
+    response.headers_mut().insert("Connection", "close");
+    response
+}
+
+pub fn parse_error_response(e: &ParseError) -> HttpResponse {
+    eprintln!("Rejecting malformed request: {}", e);
+    error_response(e.status_code(), &e.to_string())
+}
+
+fn next_request(
+    stream: &mut (impl Read + Write),
+    parser: &mut RequestParser,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+) -> io::Result<Next> {
+    // Pipelined requests may already be sitting in the parser's buffer.
+    if !parser.buffered().is_empty() {
+        if let Some(next) = parsed(parser.parse(), config) {
+            return Ok(next);
+        }
+    }
+
+    // The socket times out every `SHUTDOWN_POLL`, the keep-alive timeout
+    // counts from the last data received.
+    let mut last_read = Instant::now();
+    let mut read_buffer = [0; 4096];
+    loop {
+        let read = match stream.read(&mut read_buffer) {
+            Ok(0) => return Ok(Next::Closed),
+            Ok(read) => read,
+            Err(e) if is_timeout(&e) => {
+                if parser.is_idle() && shutdown.is_shutting_down() {
+                    return Ok(Next::Closed);
+                }
+                if last_read.elapsed() < config.keep_alive_timeout {
+                    continue;
+                }
+                if parser.is_idle() {
+                    return Ok(Next::Closed);
+                }
+                let response = error_response(StatusCode::REQUEST_TIMEOUT, "request timeout");
+                return Ok(Next::Reject(response));
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        last_read = Instant::now();
+        if let Some(next) = parsed(parser.feed(&read_buffer[..read]), config) {
+            return Ok(next);
+        }
+        if parser.expects_continue() {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+    }
+}
+
+// What a parse result means for the connection, `None` until the parser
+// has seen enough.
+pub fn parsed(result: Result<Status, ParseError>, config: &ServerConfig) -> Option<Next> {
+    match result {
+        Ok(Status::Complete(req)) => Some(Next::Request(req)),
+        Ok(Status::Partial) => None,
+        Ok(Status::Http2) if config.http2 => Some(Next::Http2),
+        Ok(Status::Http2) => {
+            let response = parse_error_response(&ParseError::UnsupportedVersion);
+            Some(Next::Reject(response))
+        }
+        Err(e) => Some(Next::Reject(parse_error_response(&e))),
+    }
+}
+
+fn send(stream: &mut impl Write, response: &HttpResponse, head_only: bool) -> io::Result<()> {
+    // Buffered so the head and body leave in as few segments as possible.
+    let mut writer = BufWriter::new(stream);
+    if head_only {
+        response.send_head(&mut writer)
+    } else {
+        response.send_response(&mut writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    use crate::handler::RequestContext;
+    use crate::websocket::{Message, WebSocket, WebSocketHandler, MAX_QUEUED_MESSAGES};
+
+    #[test]
+    fn test_upgrade_slots() {
+        let slots = UpgradeSlots::new(1);
+        let slot = slots.try_acquire();
+        assert!(slot.is_some());
+        assert!(slots.try_acquire().is_none());
+        drop(slot);
+        assert!(slots.try_acquire().is_some());
+
+        // Without a free slot, clients stay on HTTP/1.1.
+        struct Feed;
+        impl WebSocketHandler for Feed {}
+        let mut router = Router::new();
+        router
+            .websocket("/live", Feed)
+            .get("/", |_: &HttpRequest, _: &RequestContext| {
+                Ok(HttpResponse::builder().status(StatusCode::OK).body("ok"))
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (config, shutdown) = (ServerConfig::default(), ShutdownHandle::new());
+            let upgrades = UpgradeSlots::new(0);
+            for stream in listener.incoming().take(3) {
+                let stream = stream.unwrap();
+                handle_connection(stream, &router, &config, &shutdown, None, &upgrades).unwrap();
+            }
+        });
+        let exchange = |request: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        };
+
+        let websocket = exchange(
+            b"GET /live HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        );
+        assert!(websocket.starts_with(b"HTTP/1.1 503 "));
+
+        let h2c = exchange(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: h2c\r\n\
+              Connection: Upgrade, HTTP2-Settings, close\r\nHTTP2-Settings: \r\n\r\n",
+        );
+        assert!(h2c.starts_with(b"HTTP/1.1 200 OK"));
+        assert!(h2c.ends_with(b"\r\n\r\nok"));
+
+        // The preface gets SETTINGS, then a GOAWAY before any stream.
+        let mut preface = h2::PREFACE.to_vec();
+        preface.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+        let frames = exchange(&preface);
+        let settings_len = u32::from_be_bytes([0, frames[0], frames[1], frames[2]]) as usize;
+        let go_away = &frames[9 + settings_len..];
+        assert_eq!(0x7, go_away[3]);
+        assert_eq!([0; 8], go_away[9..17]);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_timeout() {
+        // Far more than the socket buffers on both ends can hold.
+        let body = vec![b'x'; 64 * 1024 * 1024];
+        let mut router = Router::new();
+        router.get("/big", move |_: &HttpRequest, _: &RequestContext| {
+            Ok(HttpResponse::builder()
+                .status(StatusCode::OK)
+                .body(body.clone()))
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let config = ServerConfig {
+                write_timeout: Duration::from_millis(200),
+                ..ServerConfig::default()
+            };
+            let (shutdown, upgrades) = (ShutdownHandle::new(), UpgradeSlots::new(0));
+            let stream = listener.accept().unwrap().0;
+            let started = Instant::now();
+            let served = handle_connection(stream, &router, &config, &shutdown, None, &upgrades);
+            (served, started.elapsed())
+        });
+
+        // Never reads the response.
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let (served, elapsed) = server.join().unwrap();
+        assert!(is_timeout(&served.unwrap_err()));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_websocket_slow_reader() {
+        // Queues `count` messages of `size` bytes as soon as a client joins.
+        struct Flood(usize, usize);
+        impl WebSocketHandler for Flood {
+            fn on_open(&self, socket: &WebSocket, _: &RequestContext) {
+                for _ in 0..self.0 {
+                    socket.send(Message::Binary(vec![0; self.1]));
+                }
+            }
+        }
+
+        // Far more than the socket buffers hold, then more than the queue.
+        for flood in [Flood(32, 1024 * 1024), Flood(MAX_QUEUED_MESSAGES + 1, 1)] {
+            let mut router = Router::new();
+            router.websocket("/live", flood);
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let config = ServerConfig {
+                    write_timeout: Duration::from_millis(200),
+                    ..ServerConfig::default()
+                };
+                let (shutdown, upgrades) = (ShutdownHandle::new(), UpgradeSlots::new(1));
+                let stream = listener.accept().unwrap().0;
+                let started = Instant::now();
+                handle_connection(stream, &router, &config, &shutdown, None, &upgrades).unwrap();
+                let slot_freed = upgrades.try_acquire().is_some();
+                (started.elapsed(), slot_freed)
+            });
+
+            // Never reads what the server sends.
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(
+                    b"GET /live HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                      Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                )
+                .unwrap();
+            let (elapsed, slot_freed) = server.join().unwrap();
+            assert!(elapsed < Duration::from_secs(5));
+            assert!(slot_freed);
+        }
+    }
+}
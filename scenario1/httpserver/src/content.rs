@@ -0,0 +1,457 @@
+use http::{
+    httprequest::HttpRequest,
+    httpresponse::{HttpResponse, StatusCode},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::handler::HandlerError;
+use crate::static_files::escape_html;
+
+// A JSON request or response body.
+//
+//     let Json(order): Json<NewOrder> = Json::from_request(req)?;
+//     Ok(Json(order).into())
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Json<T> {
+    // Fails with 415 unless the body is declared as JSON, and with 400 and
+    // the position of the problem unless it parses as a `T`.
+    pub fn from_request(req: &HttpRequest) -> Result<Self, HandlerError> {
+        let content_type = req.headers.get("Content-Type").unwrap_or_default();
+        if !is_json(media_type(content_type)) {
+            return Err(HandlerError::UnsupportedMediaType(
+                "expected a body of type application/json".to_string(),
+            ));
+        }
+        serde_json::from_slice(&req.body).map(Json).map_err(|e| {
+            let location = format!(" at line {} column {}", e.line(), e.column());
+            let message = e.to_string();
+            HandlerError::InvalidJson {
+                message: message
+                    .strip_suffix(&location)
+                    .unwrap_or(&message)
+                    .to_string(),
+                line: e.line(),
+                column: e.column(),
+            }
+        })
+    }
+}
+
+impl<T: Serialize> From<Json<T>> for HttpResponse {
+    fn from(json: Json<T>) -> Self {
+        match serde_json::to_vec(&json.0) {
+            Ok(body) => HttpResponse::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", Format::Json.media_type())
+                .body(body),
+            Err(e) => HandlerError::from(e).into_response(),
+        }
+    }
+}
+
+fn media_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+fn is_json(media_type: &str) -> bool {
+    let media_type = media_type.to_ascii_lowercase();
+    media_type == "application/json"
+        || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
+
+// A representation `respond` can render. `Html` is only offered for
+// error pages, see `HandlerError::respond`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    Xml,
+    Html,
+}
+
+impl Format {
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Xml => "application/xml; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    // How specifically `range`, a media range from `Accept`, names this
+    // format: 3 for the type itself, 2 for `type/*`, 1 for `*/*`.
+    fn specificity(&self, range: &str) -> Option<u8> {
+        let exact: &[&str] = match self {
+            Format::Json => &["application/json"],
+            Format::Csv => &["text/csv"],
+            Format::Xml => &["application/xml", "text/xml"],
+            Format::Html => &["text/html", "application/xhtml+xml"],
+        };
+        if exact.iter().any(|t| t.eq_ignore_ascii_case(range)) {
+            return Some(3);
+        }
+        let (kind, subtype) = range.split_once('/')?;
+        match (kind, subtype) {
+            ("*", "*") => Some(1),
+            (kind, "*") if exact.iter().any(|t| t.split('/').next() == Some(kind)) => Some(2),
+            _ => None,
+        }
+    }
+
+    // Picks the format for a response out of `available`, in order of
+    // preference, using the quality the client gave its most specific
+    // matching media range. The first one if there is no header, `None` if
+    // none of them is acceptable.
+    pub fn negotiate(accept: Option<&str>, available: &[Format]) -> Option<Format> {
+        let Some(accept) = accept.filter(|a| !a.trim().is_empty()) else {
+            return available.first().copied();
+        };
+        let ranges: Vec<(String, f32)> = accept
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let range = parts.next()?.trim().to_ascii_lowercase();
+                let mut q = 1.0;
+                for param in parts {
+                    if let Some((name, value)) = param.split_once('=') {
+                        if name.trim().eq_ignore_ascii_case("q") {
+                            q = value
+                                .trim()
+                                .parse()
+                                .ok()
+                                .filter(|q| (0.0..=1.0).contains(q))?;
+                        }
+                    }
+                }
+                range.contains('/').then_some((range, q))
+            })
+            .collect();
+
+        let mut best: Option<(Format, f32)> = None;
+        for format in available {
+            let q = ranges
+                .iter()
+                .filter_map(|(range, q)| Some((format.specificity(range)?, *q)))
+                .max_by_key(|(specificity, _)| *specificity)
+                .map_or(0.0, |(_, q)| q);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*format, q));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+// Renders `value` as JSON, CSV or XML, whichever the request's `Accept`
+// prefers, failing with 406 if it takes none of them.
+//
+// CSV has a column per field of the objects in a list, or of a single
+// object. XML names elements after `name`: a list becomes `<{name}s>` with
+// an `<{name}>` per item, anything else a single `<{name}>`.
+pub fn respond<T: Serialize>(
+    req: &HttpRequest,
+    value: &T,
+    name: &str,
+) -> Result<HttpResponse, HandlerError> {
+    let available = [Format::Json, Format::Csv, Format::Xml];
+    let format = Format::negotiate(req.headers.get("Accept"), &available)
+        .ok_or(HandlerError::NotAcceptable)?;
+    let value = serde_json::to_value(value)?;
+    let body = match format {
+        Format::Json => value.to_string(),
+        Format::Csv => to_csv(&value),
+        Format::Xml => to_xml(&value, name),
+        Format::Html => unreachable!("HTML is not offered"),
+    };
+    Ok(HttpResponse::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.media_type())
+        .header("Vary", "Accept")
+        .body(body))
+}
+
+fn to_csv(value: &Value) -> String {
+    let rows: Vec<&Value> = match value {
+        Value::Array(items) => items.iter().collect(),
+        other => vec![other],
+    };
+    // Every key of every object, in order of first appearance.
+    let mut columns: Vec<&str> = Vec::new();
+    for row in &rows {
+        for key in row.as_object().into_iter().flat_map(|map| map.keys()) {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+
+    let mut csv = String::new();
+    if columns.is_empty() {
+        write_record(&mut csv, ["value".to_string()]);
+        for row in rows {
+            write_record(&mut csv, [cell(row)]);
+        }
+    } else {
+        write_record(&mut csv, columns.iter().map(|c| c.to_string()));
+        for row in rows {
+            let fields = columns
+                .iter()
+                .map(|c| row.get(c).map(cell).unwrap_or_default());
+            write_record(&mut csv, fields);
+        }
+    }
+    csv
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        // Numbers and booleans as they are, nested values as JSON.
+        other => other.to_string(),
+    }
+}
+
+// One RFC 4180 record, quoting fields that need it.
+fn write_record(csv: &mut String, fields: impl IntoIterator<Item = String>) {
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            csv.push(',');
+        }
+        if field.contains([',', '"', '\r', '\n']) {
+            csv.push('"');
+            csv.push_str(&field.replace('"', "\"\""));
+            csv.push('"');
+        } else {
+            csv.push_str(&field);
+        }
+    }
+    csv.push_str("\r\n");
+}
+
+fn to_xml(value: &Value, name: &str) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    match value {
+        Value::Array(items) => {
+            let list = xml_name(&format!("{}s", name));
+            xml.push_str(&format!("<{}>", list));
+            for item in items {
+                write_element(&mut xml, name, item);
+            }
+            xml.push_str(&format!("</{}>", list));
+        }
+        other => write_element(&mut xml, name, other),
+    }
+    xml.push('\n');
+    xml
+}
+
+fn write_element(xml: &mut String, name: &str, value: &Value) {
+    let name = xml_name(name);
+    match value {
+        Value::Null => xml.push_str(&format!("<{}/>", name)),
+        Value::Object(map) => {
+            xml.push_str(&format!("<{}>", name));
+            for (key, value) in map {
+                write_element(xml, key, value);
+            }
+            xml.push_str(&format!("</{}>", name));
+        }
+        Value::Array(items) => {
+            xml.push_str(&format!("<{}>", name));
+            for item in items {
+                write_element(xml, "item", item);
+            }
+            xml.push_str(&format!("</{}>", name));
+        }
+        Value::String(s) => xml.push_str(&format!("<{0}>{1}</{0}>", name, escape_html(s))),
+        other => xml.push_str(&format!("<{0}>{1}</{0}>", name, other)),
+    }
+}
+
+// `name` as a valid XML element name, other characters replaced by `_`.
+fn xml_name(name: &str) -> String {
+    let mut xml_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_-.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !xml_name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        xml_name.insert(0, '_');
+    }
+    xml_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        id: u32,
+        name: String,
+        tags: Vec<String>,
+        note: Option<String>,
+    }
+
+    fn request(head: &str, body: &str) -> HttpRequest {
+        let raw = format!("{}\r\nContent-Length: {}\r\n\r\n{}", head, body.len(), body);
+        HttpRequest::try_from(raw.as_str()).unwrap()
+    }
+
+    fn items() -> Vec<Item> {
+        vec![
+            Item {
+                id: 1,
+                name: "plain".to_string(),
+                tags: vec![],
+                note: None,
+            },
+            Item {
+                id: 2,
+                name: "Tom \"Tea\", <b>&co</b>".to_string(),
+                tags: vec!["a".to_string(), "b".to_string()],
+                note: Some("line\nbreak".to_string()),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_json_extractor() {
+        let body = r#"{"id": 1, "name": "x", "tags": [], "note": null}"#;
+        for content_type in [
+            "application/json",
+            "Application/JSON; charset=utf-8",
+            "application/merge-patch+json",
+        ] {
+            let req = request(
+                &format!("POST / HTTP/1.1\r\nContent-Type: {}", content_type),
+                body,
+            );
+            let Json(item): Json<Item> = Json::from_request(&req).unwrap();
+            assert_eq!(1, item.id);
+        }
+
+        for head in [
+            "POST / HTTP/1.1",
+            "POST / HTTP/1.1\r\nContent-Type: text/plain",
+        ] {
+            let result = Json::<Item>::from_request(&request(head, body));
+            assert!(matches!(result, Err(HandlerError::UnsupportedMediaType(_))));
+        }
+
+        let req = request(
+            "POST / HTTP/1.1\r\nContent-Type: application/json",
+            "{\n  \"id\": \"one\"}",
+        );
+        let Err(HandlerError::InvalidJson {
+            message,
+            line,
+            column,
+        }) = Json::<Item>::from_request(&req)
+        else {
+            panic!("expected invalid JSON");
+        };
+        assert_eq!((2, 13), (line, column));
+        assert!(
+            message.starts_with("invalid type: string \"one\""),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_json_responder() {
+        let response: HttpResponse = Json(&items()[0]).into();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            Some("application/json"),
+            response.headers().get("Content-Type")
+        );
+        assert_eq!(
+            br#"{"id":1,"name":"plain","tags":[],"note":null}"#,
+            response.body()
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        use Format::*;
+        let all = &[Json, Csv, Xml];
+        assert_eq!(Some(Json), Format::negotiate(None, all));
+        assert_eq!(Some(Json), Format::negotiate(Some("*/*"), all));
+        assert_eq!(Some(Csv), Format::negotiate(Some("text/csv"), all));
+        assert_eq!(
+            Some(Xml),
+            Format::negotiate(Some("text/html, text/xml;q=0.9"), all)
+        );
+        assert_eq!(
+            Some(Xml),
+            Format::negotiate(Some("application/json;q=0.5, application/xml"), all)
+        );
+        assert_eq!(Some(Csv), Format::negotiate(Some("text/*, */*;q=0.1"), all));
+        assert_eq!(
+            Some(Csv),
+            Format::negotiate(Some("*/*, application/*;q=0"), all)
+        );
+        assert_eq!(None, Format::negotiate(Some("text/html"), all));
+        assert_eq!(
+            None,
+            Format::negotiate(Some("application/json;q=0"), &[Json])
+        );
+    }
+
+    #[test]
+    fn test_respond() {
+        let get = |accept: &str| {
+            let req = request(&format!("GET / HTTP/1.1\r\nAccept: {}", accept), "");
+            respond(&req, &items(), "item")
+        };
+
+        let json = get("application/json").unwrap();
+        let parsed: Vec<Item> = serde_json::from_slice(json.body()).unwrap();
+        assert_eq!(items(), parsed);
+        assert_eq!(Some("Accept"), json.headers().get("Vary"));
+
+        let csv = get("text/csv").unwrap();
+        assert_eq!(
+            Some("text/csv; charset=utf-8"),
+            csv.headers().get("Content-Type")
+        );
+        assert_eq!(
+            "id,name,tags,note\r\n\
+             1,plain,[],\r\n\
+             2,\"Tom \"\"Tea\"\", <b>&co</b>\",\"[\"\"a\"\",\"\"b\"\"]\",\"line\nbreak\"\r\n",
+            String::from_utf8(csv.body().to_vec()).unwrap()
+        );
+
+        let xml = get("application/xml").unwrap();
+        assert_eq!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<items>\
+             <item><id>1</id><name>plain</name><tags></tags><note/></item>\
+             <item><id>2</id><name>Tom &quot;Tea&quot;, &lt;b&gt;&amp;co&lt;/b&gt;</name>\
+             <tags><item>a</item><item>b</item></tags><note>line\nbreak</note></item>\
+             </items>\n",
+            String::from_utf8(xml.body().to_vec()).unwrap()
+        );
+
+        assert!(matches!(get("image/png"), Err(HandlerError::NotAcceptable)));
+    }
+
+    #[test]
+    fn test_xml_name() {
+        assert_eq!("order_id", xml_name("order_id"));
+        assert_eq!("_1st", xml_name("1st"));
+        assert_eq!("a_b", xml_name("a b"));
+    }
+}
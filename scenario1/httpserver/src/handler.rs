@@ -1,86 +1,795 @@
-use std::{collections::HashMap, env, fs};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    env, fmt, io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
 
 use http::{
-    httprequest::{HttpRequest, Resource},
-    httpresponse::HttpResponse,
+    httprequest::{HttpRequest, Method},
+    httpresponse::{HttpResponse, StatusCode},
 };
-use serde::{Deserialize, Serialize};
-
-pub trait Handler {
-    fn handle(req: &HttpRequest) -> HttpResponse;
-    fn load_file(file_name: &str) -> Option<String> {
-        let default_path = format!("{}/public", env!("CARGO_MANIFEST_DIR"));
-        let public_path = env::var("PUBLIC_PATH").unwrap_or(default_path);
-        let full_path = format!("{}/{}", public_path, file_name);
-
-        let contents = fs::read_to_string(full_path);
-        contents.ok()
-    }
-}
-#[derive(Serialize, Deserialize)]
-pub struct OrderStatus {
-    order_id: i32,
-    order_date: String,
-    order_status: String,
-}
-
-pub struct StaticPageHandler;
-impl Handler for StaticPageHandler {
-    fn handle(req: &HttpRequest) -> HttpResponse {
-        let Resource::Path(s) = &req.resource;
-        let route: Vec<&str> = s.split("/").collect();
-
-        match route[1] {
-            "" => HttpResponse::new("200", None, Self::load_file("index.html")),
-            "health" => HttpResponse::new("200", None, Self::load_file("health.html")),
-            path => match Self::load_file(path) {
-                Some(content) => {
-                    let content_type = if path.ends_with(".css") {
-                        "text/css"
-                    } else if path.ends_with(".js") {
-                        "text/javascript"
-                    } else {
-                        "text/html"
-                    };
-                    let headers = HashMap::from([("Content-Type", content_type)]);
-                    HttpResponse::new("202", Some(headers), Some(content))
-                }
-                None => HttpResponse::new("404", None, Self::load_file("404,html")),
-            },
-        }
-    }
-}
-pub struct PageNotFoundHandler;
-impl Handler for PageNotFoundHandler {
-    fn handle(req: &HttpRequest) -> HttpResponse {
-        HttpResponse::new("404", None, Self::load_file("404.html"))
-    }
-}
-pub struct WebServiceHandler;
+
+use crate::cache::FileCache;
+use crate::content::{self, Format, Json};
+use crate::middleware::{AccessLog, CatchPanic, Compress, RequestId, Timing};
+pub use crate::orders::OrderStatus;
+use crate::orders::{Date, OrderFilter, OrderStore};
+use crate::router::{Params, Router};
+use crate::static_files::{escape_html, StaticPageHandler};
+use crate::websocket::{Broadcast, Message, WebSocket, WebSocketHandler};
+
+// The routes served by the httpserver binary.
+pub fn routes() -> Router {
+    let files = Arc::new(FileCache::new(1024, 64 << 20));
+    let data = Arc::new(FileCache::new(16, 16 << 20));
+    let orders = WebServiceHandler::default().cache(Arc::clone(&data));
+    let cache_stats = {
+        let files = Arc::clone(&files);
+        move |_: &HttpRequest, _: &RequestContext| {
+            let stats = serde_json::json!({ "files": files.stats(), "data": data.stats() });
+            Ok(Json(stats).into())
+        }
+    };
+    let mut router = Router::new();
+    router
+        .wrap(AccessLog)
+        .wrap(RequestId::new())
+        .wrap(CatchPanic)
+        .group("/api", |api| {
+            api.wrap(Timing)
+                .wrap(Compress::new())
+                .get("/shipping/orders", orders.clone())
+                .post("/shipping/orders", orders.clone())
+                .get("/shipping/orders/{id}", orders.clone())
+                .put("/shipping/orders/{id}", orders.clone())
+                .patch("/shipping/orders/{id}", orders.clone())
+                .delete("/shipping/orders/{id}", orders.clone())
+                .websocket("/shipping/orders/live", orders.feed())
+                .get("/cache/stats", cache_stats);
+        })
+        .get("/{*path}", StaticPageHandler::default().cache(files));
+    router
+}
+
+// Serves the requests a route matched. Handlers live in the route table and
+// are shared by every connection, so they take `&self`. Any
+// `Fn(&HttpRequest, &RequestContext) -> Result<HttpResponse, HandlerError>`
+// is a handler too.
+pub trait Handler: Send + Sync {
+    fn handle(&self, req: &HttpRequest, ctx: &RequestContext)
+        -> Result<HttpResponse, HandlerError>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&HttpRequest, &RequestContext) -> Result<HttpResponse, HandlerError> + Send + Sync,
+{
+    fn handle(
+        &self,
+        req: &HttpRequest,
+        ctx: &RequestContext,
+    ) -> Result<HttpResponse, HandlerError> {
+        self(req, ctx)
+    }
+}
+
+// Why a handler could not produce a response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerError {
+    NotFound,
+    Forbidden,
+    // No route for the path takes the request's method.
+    MethodNotAllowed,
+    BadRequest(String),
+    // The request clashes with the current state, e.g. a duplicate ID.
+    Conflict(String),
+    // The body is in a format the handler doesn't read.
+    UnsupportedMediaType(String),
+    // The body isn't the JSON the handler expected, see `content::Json`.
+    InvalidJson {
+        message: String,
+        line: usize,
+        column: usize,
+    },
+    // None of the representations the handler has is in `Accept`.
+    NotAcceptable,
+    // An extension method no route takes.
+    NotImplemented,
+    // The route only speaks another protocol, e.g. WebSocket.
+    UpgradeRequired(String),
+    Internal(String),
+}
+
+impl HandlerError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HandlerError::NotFound => StatusCode::NOT_FOUND,
+            HandlerError::Forbidden => StatusCode::FORBIDDEN,
+            HandlerError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            HandlerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HandlerError::Conflict(_) => StatusCode::CONFLICT,
+            HandlerError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            HandlerError::InvalidJson { .. } => StatusCode::BAD_REQUEST,
+            HandlerError::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            HandlerError::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            HandlerError::UpgradeRequired(_) => StatusCode::UPGRADE_REQUIRED,
+            HandlerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // The error as JSON, for when there is no request to negotiate with.
+    pub fn into_response(self) -> HttpResponse {
+        self.render(Format::Json)
+    }
+
+    // The error as an HTML page for clients that prefer one, browsers
+    // mostly, and as JSON for everyone else.
+    pub fn respond(self, req: &HttpRequest) -> HttpResponse {
+        let available = [Format::Json, Format::Html];
+        let format = Format::negotiate(req.headers.get("Accept"), &available);
+        self.render(format.unwrap_or(Format::Json))
+    }
+
+    // Internal details are logged rather than sent to the client.
+    fn render(self, format: Format) -> HttpResponse {
+        let mut details = serde_json::Map::new();
+        let message = match self {
+            HandlerError::BadRequest(ref message)
+            | HandlerError::Conflict(ref message)
+            | HandlerError::UpgradeRequired(ref message)
+            | HandlerError::UnsupportedMediaType(ref message) => message.clone(),
+            HandlerError::InvalidJson {
+                ref message,
+                line,
+                column,
+            } => {
+                details.insert("line".to_string(), line.into());
+                details.insert("column".to_string(), column.into());
+                format!("invalid JSON: {}", message)
+            }
+            HandlerError::NotAcceptable => "not acceptable".to_string(),
+            HandlerError::NotImplemented => "not implemented".to_string(),
+            HandlerError::Internal(ref message) => {
+                eprintln!("Handler failed: {}", message);
+                "internal server error".to_string()
+            }
+            HandlerError::NotFound => "not found".to_string(),
+            HandlerError::Forbidden => "forbidden".to_string(),
+            HandlerError::MethodNotAllowed => "method not allowed".to_string(),
+        };
+        error_response(self.status_code(), &message, details, format)
+    }
+}
+
+// The body every error response has: `status`, its reason phrase as
+// `error`, a `message` and any `details` as a JSON object, or the same as a
+// small HTML page.
+pub(crate) fn error_response(
+    status: StatusCode,
+    message: &str,
+    details: serde_json::Map<String, serde_json::Value>,
+    format: Format,
+) -> HttpResponse {
+    let reason = status.reason_phrase().unwrap_or("Error");
+    let (format, body) = match format {
+        Format::Html => {
+            let mut html = format!(
+                "<!DOCTYPE html>\n<html>\n<head><title>{code} {reason}</title></head>\n\
+                 <body>\n<h1>{code} {reason}</h1>\n<p>{}</p>\n",
+                escape_html(message),
+                code = status.as_u16(),
+                reason = reason,
+            );
+            for (name, value) in &details {
+                let value = escape_html(&value.to_string());
+                html.push_str(&format!("<p>{}: {}</p>\n", escape_html(name), value));
+            }
+            html.push_str("</body>\n</html>\n");
+            (Format::Html, html)
+        }
+        _ => {
+            let mut body = serde_json::Map::new();
+            body.insert("status".to_string(), status.as_u16().into());
+            body.insert("error".to_string(), reason.into());
+            body.insert("message".to_string(), message.into());
+            body.extend(details);
+            (Format::Json, serde_json::Value::Object(body).to_string())
+        }
+    };
+    HttpResponse::builder()
+        .status(status)
+        .header("Content-Type", format.media_type())
+        .body(body)
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::NotFound => f.write_str("not found"),
+            HandlerError::Forbidden => f.write_str("forbidden"),
+            HandlerError::MethodNotAllowed => f.write_str("method not allowed"),
+            HandlerError::BadRequest(message) => write!(f, "bad request: {}", message),
+            HandlerError::Conflict(message) => write!(f, "conflict: {}", message),
+            HandlerError::UnsupportedMediaType(message) => {
+                write!(f, "unsupported media type: {}", message)
+            }
+            HandlerError::InvalidJson {
+                message,
+                line,
+                column,
+            } => write!(
+                f,
+                "invalid JSON at line {} column {}: {}",
+                line, column, message
+            ),
+            HandlerError::NotAcceptable => f.write_str("not acceptable"),
+            HandlerError::NotImplemented => f.write_str("not implemented"),
+            HandlerError::UpgradeRequired(message) => write!(f, "upgrade required: {}", message),
+            HandlerError::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+// Missing and unreadable files are the client's problem, anything else the
+// server's.
+impl From<io::Error> for HandlerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => HandlerError::NotFound,
+            io::ErrorKind::PermissionDenied => HandlerError::Forbidden,
+            _ => HandlerError::Internal(e.to_string()),
+        }
+    }
+}
+
+// Request bodies go through `content::Json`, which reports their mistakes
+// as `InvalidJson`. JSON that fails anywhere else is the server's own.
+impl From<serde_json::Error> for HandlerError {
+    fn from(e: serde_json::Error) -> Self {
+        HandlerError::Internal(e.to_string())
+    }
+}
+
+// Shared values handlers look up by type, e.g. a database pool or a cache.
+#[derive(Default)]
+pub struct AppState {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    // Stores `value`, replacing any earlier value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+}
+
+// What a handler knows about a request beyond the request itself.
+pub struct RequestContext<'a> {
+    params: Params,
+    peer_addr: Option<SocketAddr>,
+    state: &'a AppState,
+}
+
+impl<'a> RequestContext<'a> {
+    pub fn new(params: Params, peer_addr: Option<SocketAddr>, state: &'a AppState) -> Self {
+        RequestContext {
+            params,
+            peer_addr,
+            state,
+        }
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn state<T: Any>(&self) -> Option<&'a T> {
+        self.state.get()
+    }
+}
+
+// Serves the orders in `orders.json` under `DATA_PATH`:
+//
+// - `GET /api/shipping/orders` lists them, filtered by `?status=`, `?from=`
+//   and `?to=` dates and paged by `?page=` and `?per_page=`, with the number
+//   of matches in `X-Total-Count`,
+// - `POST` to it creates one,
+// - `GET`, `PUT`, `PATCH` and `DELETE` on `/api/shipping/orders/{id}` read,
+//   replace, update and remove one,
+// - the WebSocket at `/api/shipping/orders/live`, see `feed`, pushes every
+//   change to the browsers watching.
+#[derive(Clone)]
+pub struct WebServiceHandler {
+    store: OrderStore,
+    changes: Broadcast,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
 impl WebServiceHandler {
-    fn load_json() -> Vec<OrderStatus> {
+    pub fn new(data_path: impl Into<PathBuf>) -> Self {
+        WebServiceHandler {
+            store: OrderStore::new(data_path.into().join("orders.json")),
+            changes: Broadcast::default(),
+        }
+    }
+
+    // The WebSocket handler sending each change made through this handler,
+    // or a clone of it, as a JSON text message: `{"event": "created",
+    // "order": {...}}`, the same with "updated", or `{"event": "deleted",
+    // "order_id": 3}`.
+    pub fn feed(&self) -> OrderFeed {
+        OrderFeed {
+            changes: self.changes.clone(),
+        }
+    }
+
+    fn publish(&self, change: serde_json::Value) {
+        self.changes.send(Message::Text(change.to_string()));
+    }
+
+    // Keeps the parsed orders in `cache` until `orders.json` changes.
+    pub fn cache(mut self, cache: Arc<FileCache<Vec<OrderStatus>>>) -> Self {
+        self.store = self.store.cache(cache);
+        self
+    }
+
+    fn list(&self, req: &HttpRequest) -> Result<HttpResponse, HandlerError> {
+        let query = req.query();
+        let date = |name: &str| match query.get(name) {
+            None => Ok(None),
+            Some(value) => Date::parse(value)
+                .map(Some)
+                .ok_or_else(|| HandlerError::BadRequest(format!("invalid {} date", name))),
+        };
+        let number = |name: &str, default: usize, max: usize| match query.get(name) {
+            None => Ok(default),
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|n| (1..=max).contains(n))
+                .ok_or_else(|| {
+                    HandlerError::BadRequest(format!("{} must be between 1 and {}", name, max))
+                }),
+        };
+        let filter = OrderFilter {
+            status: query.get("status").map(str::to_string),
+            from: date("from")?,
+            to: date("to")?,
+        };
+        let page = number("page", 1, usize::MAX)?;
+        let per_page = number("per_page", DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE)?;
+
+        let orders = self.store.list()?;
+        let matching: Vec<&OrderStatus> = orders.iter().filter(|o| filter.matches(o)).collect();
+        let total = matching.len();
+        let start = (page - 1).saturating_mul(per_page);
+        let page: Vec<&OrderStatus> = matching.into_iter().skip(start).take(per_page).collect();
+        let mut response = content::respond(req, &page, "order")?;
+        response
+            .headers_mut()
+            .insert("X-Total-Count", total.to_string());
+        Ok(response)
+    }
+}
+
+impl Default for WebServiceHandler {
+    fn default() -> Self {
         let default_path = format!("{}/data", env!("CARGO_MANIFEST_DIR"));
-        let data_path = env::var("DATA_PATH").unwrap_or(default_path);
-        let full_path = format!("{}/{}", data_path, "orders.json");
-        let json_contents = fs::read_to_string(full_path);
-        let orders: Vec<OrderStatus> =
-            serde_json::from_str(json_contents.unwrap().as_str()).unwrap();
-        orders
+        Self::new(env::var("DATA_PATH").unwrap_or(default_path))
     }
 }
+
 impl Handler for WebServiceHandler {
-    fn handle(req: &HttpRequest) -> HttpResponse {
-        let Resource::Path(s) = &req.resource;
-
-        let route: Vec<&str> = s.split("/").collect();
-        match route[2] {
-            "shipping" if route.len() > 2 && route[3] == "orders" => {
-                let body = Some(serde_json::to_string(&Self::load_json()).unwrap());
-                let headers: HashMap<&str, &str> =
-                    HashMap::from([("Content-Type", "application/json")]);
-                HttpResponse::new("200", Some(headers), body)
+    fn handle(
+        &self,
+        req: &HttpRequest,
+        ctx: &RequestContext,
+    ) -> Result<HttpResponse, HandlerError> {
+        let id = match ctx.params().get("id") {
+            None => None,
+            Some(_) => Some(ctx.params().parse("id").ok_or(HandlerError::NotFound)?),
+        };
+        match (&req.method, id) {
+            (Method::Get | Method::Head, None) => self.list(req),
+            (Method::Post, None) => {
+                let Json(new) = Json::from_request(req)?;
+                let order = self.store.create(new)?;
+                self.publish(serde_json::json!({ "event": "created", "order": order }));
+                let mut response = content::respond(req, &order, "order")?;
+                response.set_status(StatusCode::CREATED);
+                let location = format!("{}/{}", req.path().trim_end_matches('/'), order.order_id);
+                response.headers_mut().insert("Location", location);
+                Ok(response)
+            }
+            (Method::Get | Method::Head, Some(id)) => {
+                content::respond(req, &self.store.get(id)?, "order")
+            }
+            (Method::Put, Some(id)) => {
+                let Json(new) = Json::from_request(req)?;
+                let order = self.store.replace(id, new)?;
+                self.publish(serde_json::json!({ "event": "updated", "order": order }));
+                content::respond(req, &order, "order")
+            }
+            (Method::Patch, Some(id)) => {
+                let Json(update) = Json::from_request(req)?;
+                let order = self.store.update(id, update)?;
+                self.publish(serde_json::json!({ "event": "updated", "order": order }));
+                content::respond(req, &order, "order")
+            }
+            (Method::Delete, Some(id)) => {
+                self.store.delete(id)?;
+                self.publish(serde_json::json!({ "event": "deleted", "order_id": id }));
+                Ok(HttpResponse::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .build())
+            }
+            _ => Err(HandlerError::NotFound),
+        }
+    }
+}
+
+// Subscribes browsers to the order changes of a `WebServiceHandler`, see
+// `WebServiceHandler::feed`. What they send is ignored.
+pub struct OrderFeed {
+    changes: Broadcast,
+}
+
+impl WebSocketHandler for OrderFeed {
+    fn on_open(&self, socket: &WebSocket, _ctx: &RequestContext) {
+        self.changes.subscribe(socket);
+    }
+
+    fn on_close(&self, socket: &WebSocket) {
+        self.changes.unsubscribe(socket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Api {
+        _dir: tempfile::TempDir,
+        router: Router,
+        orders: WebServiceHandler,
+    }
+
+    impl Api {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let orders: Vec<OrderStatus> = (1..=5)
+                .map(|id| OrderStatus {
+                    order_id: id,
+                    order_date: format!("{} Jan 2020", id),
+                    order_status: if id % 2 == 0 { "Pending" } else { "Delivered" }.to_string(),
+                })
+                .collect();
+            std::fs::write(
+                dir.path().join("orders.json"),
+                serde_json::to_vec(&orders).unwrap(),
+            )
+            .unwrap();
+
+            let orders = WebServiceHandler::new(dir.path());
+            let mut router = Router::new();
+            router
+                .get("/orders", orders.clone())
+                .post("/orders", orders.clone())
+                .get("/orders/{id}", orders.clone())
+                .put("/orders/{id}", orders.clone())
+                .patch("/orders/{id}", orders.clone())
+                .delete("/orders/{id}", orders.clone());
+            Api {
+                _dir: dir,
+                router,
+                orders,
             }
-            _ => HttpResponse::new("404", None, Self::load_file("404.html")),
         }
+
+        fn send(&self, method: &str, target: &str, body: &str) -> HttpResponse {
+            self.send_with(method, target, "Content-Type: application/json", body)
+        }
+
+        fn send_with(&self, method: &str, target: &str, header: &str, body: &str) -> HttpResponse {
+            let raw = format!(
+                "{} {} HTTP/1.1\r\n{}\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                target,
+                header,
+                body.len(),
+                body
+            );
+            self.router
+                .route(&HttpRequest::try_from(raw.as_str()).unwrap(), None)
+        }
+
+        fn ids(&self, target: &str) -> Vec<i32> {
+            let response = self.send("GET", target, "");
+            assert_eq!(StatusCode::OK, response.status(), "{}", target);
+            let orders: Vec<OrderStatus> = serde_json::from_slice(response.body()).unwrap();
+            orders.iter().map(|order| order.order_id).collect()
+        }
+    }
+
+    #[test]
+    fn test_list_filters_and_pages() {
+        let api = Api::new();
+        assert_eq!(vec![1, 2, 3, 4, 5], api.ids("/orders"));
+        assert_eq!(vec![2, 4], api.ids("/orders?status=pending"));
+        assert_eq!(
+            vec![2, 3],
+            api.ids("/orders?from=2020-01-02&to=3%20Jan%202020")
+        );
+        assert_eq!(vec![3, 4], api.ids("/orders?per_page=2&page=2"));
+        assert_eq!(Vec::<i32>::new(), api.ids("/orders?per_page=2&page=4"));
+
+        let page = api.send("GET", "/orders?status=Delivered&per_page=1", "");
+        assert_eq!(Some("3"), page.headers().get("X-Total-Count"));
+
+        for bad in ["page=0", "per_page=101", "per_page=x", "from=2020-02-30"] {
+            let response = api.send("GET", &format!("/orders?{}", bad), "");
+            assert_eq!(StatusCode::BAD_REQUEST, response.status(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_create_update_delete() {
+        let api = Api::new();
+
+        let created = api.send(
+            "POST",
+            "/orders",
+            r#"{"order_date": "2020-02-01", "order_status": "Pending"}"#,
+        );
+        assert_eq!(StatusCode::CREATED, created.status());
+        assert_eq!(Some("/orders/6"), created.headers().get("Location"));
+        let order: OrderStatus = serde_json::from_slice(created.body()).unwrap();
+        assert_eq!("1 Feb 2020", order.order_date);
+
+        let patched = api.send("PATCH", "/orders/6", r#"{"order_status": "Shipped"}"#);
+        let order: OrderStatus = serde_json::from_slice(patched.body()).unwrap();
+        assert_eq!("Shipped", order.order_status);
+        let put = api.send(
+            "PUT",
+            "/orders/6",
+            r#"{"order_id": 6, "order_date": "3 Feb 2020", "order_status": "Delivered"}"#,
+        );
+        assert_eq!(StatusCode::OK, put.status());
+        let fetched = api.send("GET", "/orders/6", "");
+        assert_eq!(put.body(), fetched.body());
+
+        let deleted = api.send("DELETE", "/orders/6", "");
+        assert_eq!(StatusCode::NO_CONTENT, deleted.status());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            api.send("GET", "/orders/6", "").status()
+        );
+        assert_eq!(vec![1, 2, 3, 4, 5], api.ids("/orders"));
+    }
+
+    #[test]
+    fn test_order_feed() {
+        use crate::websocket::Session;
+        use http::websocket::{Codec, Role};
+
+        let api = Api::new();
+        let state = AppState::default();
+        let ctx = RequestContext::new(Params::default(), None, &state);
+        let mut session = Session::open(Arc::new(api.orders.feed()), &ctx, 1024, &[]);
+
+        api.send("PATCH", "/orders/2", r#"{"order_status": "Shipped"}"#);
+        api.send("PATCH", "/orders/9", r#"{"order_status": "Shipped"}"#);
+        api.send("DELETE", "/orders/3", "");
+        session.flush_outgoing();
+
+        let mut client = Codec::new(Role::Client);
+        client.feed(&session.take_output());
+        let events: Vec<serde_json::Value> = std::iter::from_fn(|| client.next_message().unwrap())
+            .map(|message| match message {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                message => panic!("unexpected {:?}", message),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                serde_json::json!({"event": "updated", "order": {
+                    "order_id": 2, "order_date": "2 Jan 2020", "order_status": "Shipped"
+                }}),
+                serde_json::json!({"event": "deleted", "order_id": 3}),
+            ],
+            events
+        );
+
+        drop(session);
+        assert!(api.orders.changes.is_empty());
+    }
+
+    #[test]
+    fn test_error_statuses() {
+        let api = Api::new();
+        let duplicate = r#"{"order_id": 1, "order_date": "1 Feb 2020", "order_status": "Pending"}"#;
+        let cases = [
+            ("POST", "/orders", duplicate, StatusCode::CONFLICT),
+            ("POST", "/orders", "{not json", StatusCode::BAD_REQUEST),
+            (
+                "POST",
+                "/orders",
+                r#"{"order_status": "Pending"}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "POST",
+                "/orders",
+                r#"{"order_date": "1 Feb 2020", "order_status": "Pending", "x": 1}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            ("PATCH", "/orders/1", "{}", StatusCode::BAD_REQUEST),
+            (
+                "PATCH",
+                "/orders/1",
+                r#"{"order_date": "someday"}"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "PATCH",
+                "/orders/9",
+                r#"{"order_status": "Lost"}"#,
+                StatusCode::NOT_FOUND,
+            ),
+            ("PUT", "/orders/2", duplicate, StatusCode::BAD_REQUEST),
+            ("GET", "/orders/abc", "", StatusCode::NOT_FOUND),
+            ("DELETE", "/orders/9", "", StatusCode::NOT_FOUND),
+        ];
+        for (method, target, body, status) in cases {
+            let response = api.send(method, target, body);
+            assert_eq!(status, response.status(), "{} {} {}", method, target, body);
+        }
+
+        let conflict = api.send("POST", "/orders", duplicate);
+        let error: serde_json::Value = serde_json::from_slice(conflict.body()).unwrap();
+        assert_eq!("order 1 already exists", error["message"]);
+        assert_eq!(vec![1, 2, 3, 4, 5], api.ids("/orders"));
+    }
+
+    #[test]
+    fn test_order_ids_exhausted() {
+        let api = Api::new();
+        let last = format!(
+            r#"{{"order_id": {}, "order_date": "1 Feb 2020", "order_status": "Pending"}}"#,
+            i32::MAX
+        );
+        assert_eq!(
+            StatusCode::CREATED,
+            api.send("POST", "/orders", &last).status()
+        );
+
+        let next = r#"{"order_date": "2 Feb 2020", "order_status": "Pending"}"#;
+        let exhausted = api.send("POST", "/orders", next);
+        assert_eq!(StatusCode::CONFLICT, exhausted.status());
+        // The store still takes writes afterwards.
+        let explicit = r#"{"order_id": 6, "order_date": "2 Feb 2020", "order_status": "Pending"}"#;
+        assert_eq!(
+            StatusCode::CREATED,
+            api.send("POST", "/orders", explicit).status()
+        );
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            api.send("DELETE", "/orders/6", "").status()
+        );
+    }
+
+    #[test]
+    fn test_json_bodies() {
+        let api = Api::new();
+        let order = r#"{"order_date": "1 Feb 2020", "order_status": "Pending"}"#;
+
+        let form = api.send_with("POST", "/orders", "Content-Type: text/plain", order);
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, form.status());
+        let missing = api.send_with("PATCH", "/orders/1", "X-Other: 1", order);
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, missing.status());
+
+        let invalid = api.send("POST", "/orders", "{\"order_date\": 5}");
+        assert_eq!(StatusCode::BAD_REQUEST, invalid.status());
+        assert_eq!(
+            Some("application/json"),
+            invalid.headers().get("Content-Type")
+        );
+        let error: serde_json::Value = serde_json::from_slice(invalid.body()).unwrap();
+        assert_eq!("Bad Request", error["error"]);
+        assert_eq!(1, error["line"]);
+        assert_eq!(16, error["column"]);
+        assert!(error["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid JSON: invalid type"));
+    }
+
+    #[test]
+    fn test_error_bodies() {
+        let api = Api::new();
+
+        let json = api.send_with("GET", "/orders/9", "Accept: */*", "");
+        assert_eq!(Some("application/json"), json.headers().get("Content-Type"));
+        let error: serde_json::Value = serde_json::from_slice(json.body()).unwrap();
+        assert_eq!(
+            serde_json::json!({"status": 404, "error": "Not Found", "message": "not found"}),
+            error
+        );
+
+        let accept = "Accept: text/html,application/xhtml+xml,*/*;q=0.8";
+        let html = api.send_with("POST", "/orders", accept, "{}");
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, html.status());
+        assert_eq!(
+            Some("text/html; charset=utf-8"),
+            html.headers().get("Content-Type")
+        );
+        let page = String::from_utf8(html.body().to_vec()).unwrap();
+        assert!(page.contains("<h1>415 Unsupported Media Type</h1>"));
+        assert!(page.contains("<p>expected a body of type application/json</p>"));
+
+        // Internal details stay in the log.
+        let internal = HandlerError::Internal("/secret/orders.json: EIO".into());
+        let page = String::from_utf8(internal.into_response().body().to_vec()).unwrap();
+        assert!(!page.contains("secret"));
+
+        let io = |kind: io::ErrorKind| HandlerError::from(io::Error::from(kind));
+        assert_eq!(HandlerError::NotFound, io(io::ErrorKind::NotFound));
+        assert_eq!(HandlerError::Forbidden, io(io::ErrorKind::PermissionDenied));
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            io(io::ErrorKind::UnexpectedEof).status_code()
+        );
+    }
+
+    #[test]
+    fn test_accept_negotiation() {
+        let api = Api::new();
+
+        let csv = api.send_with("GET", "/orders?per_page=2", "Accept: text/csv", "");
+        assert_eq!(
+            Some("text/csv; charset=utf-8"),
+            csv.headers().get("Content-Type")
+        );
+        assert_eq!(
+            "order_id,order_date,order_status\r\n1,1 Jan 2020,Delivered\r\n2,2 Jan 2020,Pending\r\n",
+            String::from_utf8(csv.body().to_vec()).unwrap()
+        );
+
+        let xml = api.send_with("GET", "/orders/2", "Accept: application/xml", "");
+        assert_eq!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<order><order_id>2</order_id>\
+             <order_date>2 Jan 2020</order_date><order_status>Pending</order_status></order>\n",
+            String::from_utf8(xml.body().to_vec()).unwrap()
+        );
+
+        let created = api.send_with(
+            "POST",
+            "/orders",
+            "Content-Type: application/json\r\nAccept: text/csv",
+            r#"{"order_date": "1 Feb 2020", "order_status": "Pending"}"#,
+        );
+        assert_eq!(StatusCode::CREATED, created.status());
+        assert_eq!(
+            Some("text/csv; charset=utf-8"),
+            created.headers().get("Content-Type")
+        );
+
+        let image = api.send_with("GET", "/orders", "Accept: image/png", "");
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, image.status());
     }
 }
@@ -0,0 +1,17 @@
+mod async_server;
+pub mod cache;
+pub mod compression;
+mod connection;
+pub mod content;
+pub mod handler;
+pub mod middleware;
+pub mod mime;
+pub mod orders;
+pub mod router;
+pub mod sandbox;
+pub mod server;
+pub mod shutdown;
+pub mod static_files;
+mod threadpool;
+pub mod tls;
+pub mod websocket;
@@ -1,10 +1,14 @@
-mod handler;
-mod server;
-mod router;
-use server::Server;
+use httpserver::server::Server;
+use std::process;
 fn main() {
     // Start a server
     let server = Server::new("localhost:3000");
+    // Stop accepting and drain connections on SIGINT or SIGTERM
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.shutdown()).expect("failed to install signal handler");
     //Run the server
-    server.run();
-}
\ No newline at end of file
+    if let Err(e) = server.run() {
+        eprintln!("Server failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,670 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http::{
+    httprequest::{HttpRequest, Method},
+    httpresponse::{HttpResponse, StatusCode},
+};
+
+use crate::compression::{self, Encoding};
+use crate::handler::{Handler, HandlerError, RequestContext};
+
+// Code that runs around the handlers of a router or a route group. It can
+// inspect the request, answer it directly, or call `next.run` and adjust
+// the response on its way out. Any
+// `Fn(&HttpRequest, &RequestContext, Next) -> HttpResponse` is a middleware.
+pub trait Middleware: Send + Sync {
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&HttpRequest, &RequestContext, Next) -> HttpResponse + Send + Sync,
+{
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse {
+        self(req, ctx, next)
+    }
+}
+
+// The rest of the chain: the remaining middleware, then the endpoint.
+pub struct Next<'a> {
+    middleware: &'a [&'a dyn Middleware],
+    endpoint: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middleware: &'a [&'a dyn Middleware], endpoint: &'a dyn Handler) -> Self {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    pub fn run(self, req: &HttpRequest, ctx: &RequestContext) -> HttpResponse {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.call(req, ctx, Next::new(rest, self.endpoint)),
+            None => self
+                .endpoint
+                .handle(req, ctx)
+                .unwrap_or_else(|e| e.respond(req)),
+        }
+    }
+}
+
+// Logs one line per request: peer, method, target, status, body size,
+// duration and request ID. Register it before `RequestId` so the ID is set
+// by the time the line is written.
+#[derive(Debug, Default)]
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse {
+        let started = Instant::now();
+        let response = next.run(req, ctx);
+        println!(
+            "{} \"{} {}\" {} {} {:.1}ms {}",
+            ctx.peer_addr()
+                .map_or_else(|| "-".to_string(), |addr| addr.to_string()),
+            req.method,
+            req.resource,
+            response.status(),
+            response.body().len(),
+            started.elapsed().as_secs_f64() * 1000.0,
+            response.headers().get(REQUEST_ID).unwrap_or("-")
+        );
+        response
+    }
+}
+
+const REQUEST_ID: &str = "X-Request-Id";
+
+// Tags every response with an `X-Request-Id`, reusing the client's when it
+// sent a sensible one so a request can be traced across services.
+#[derive(Debug)]
+pub struct RequestId {
+    prefix: String,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        // Distinct across restarts without needing a random number source.
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        RequestId {
+            prefix: format!("{:x}", started.as_millis()),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn next_id(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{:06x}", self.prefix, n)
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse {
+        let id = match req.headers.get(REQUEST_ID) {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= 128
+                    && id
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b)) =>
+            {
+                id.to_string()
+            }
+            _ => self.next_id(),
+        };
+        let mut response = next.run(req, ctx);
+        response.headers_mut().insert(REQUEST_ID, id);
+        response
+    }
+}
+
+// Reports how long the rest of the chain took in a `Server-Timing` header.
+#[derive(Debug, Default)]
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse {
+        let started = Instant::now();
+        let mut response = next.run(req, ctx);
+        let millis = started.elapsed().as_secs_f64() * 1000.0;
+        response
+            .headers_mut()
+            .append("Server-Timing", format!("app;dur={:.3}", millis));
+        response
+    }
+}
+
+// Cross-origin resource sharing. Preflight requests from an allowed origin
+// are answered directly; other requests from it get
+// `Access-Control-Allow-Origin` added. Requests from other origins pass
+// through untouched and the browser blocks them.
+//
+//     Cors::new()
+//         .allow_origin("https://shop.example.com")
+//         .allow_methods([Method::Get, Method::Post])
+//         .allow_headers(["Content-Type"])
+#[derive(Debug, Clone)]
+pub struct Cors {
+    // Empty allows any origin.
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    max_age: Option<u64>,
+}
+
+impl Cors {
+    // Any origin, simple methods and no extra request headers.
+    pub fn new() -> Self {
+        Cors {
+            origins: Vec::new(),
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: Vec::new(),
+            max_age: None,
+        }
+    }
+
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        self.origins.push(origin.into());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    pub fn allow_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // How long browsers may cache a preflight answer, in seconds.
+    pub fn max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|o| o == origin)
+    }
+
+    fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+        if self.origins.is_empty() {
+            "*"
+        } else {
+            origin
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse {
+        let Some(origin) = req.headers.get("Origin").filter(|o| self.allows(o)) else {
+            return next.run(req, ctx);
+        };
+
+        let preflight =
+            req.method == Method::Options && req.headers.contains("Access-Control-Request-Method");
+        let mut response = if preflight {
+            let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+            let mut builder = HttpResponse::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header("Access-Control-Allow-Methods", methods.join(", "));
+            if !self.headers.is_empty() {
+                builder = builder.header("Access-Control-Allow-Headers", self.headers.join(", "));
+            }
+            if let Some(max_age) = self.max_age {
+                builder = builder.header("Access-Control-Max-Age", max_age.to_string());
+            }
+            builder.build()
+        } else {
+            next.run(req, ctx)
+        };
+
+        let headers = response.headers_mut();
+        headers.insert(
+            "Access-Control-Allow-Origin",
+            self.allow_origin_value(origin),
+        );
+        if !self.origins.is_empty() {
+            headers.append("Vary", "Origin");
+        }
+        response
+    }
+}
+
+// Compresses bodies of the listed media types, at least `min_size` bytes
+// long, with the best coding the client accepts. By default that is JSON,
+// CSV or XML of 1 KiB or more; smaller bodies gain less than the encoding
+// costs.
+//
+//     Compress::new().min_size(256).types(["application/json", "text/csv"])
+#[derive(Debug, Clone)]
+pub struct Compress {
+    min_size: usize,
+    types: Vec<String>,
+}
+
+impl Compress {
+    pub fn new() -> Self {
+        Compress {
+            min_size: 1024,
+            types: ["application/json", "text/csv", "application/xml"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    // Media types without parameters, e.g. `text/csv`.
+    pub fn types<S: Into<String>>(mut self, types: impl IntoIterator<Item = S>) -> Self {
+        self.types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    fn compressible(&self, response: &HttpResponse) -> bool {
+        let Some(content_type) = response.headers().get("Content-Type") else {
+            return false;
+        };
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        self.types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(media_type))
+            && !response.headers().contains("Content-Encoding")
+            && response.status() != StatusCode::PARTIAL_CONTENT
+    }
+}
+
+impl Default for Compress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Compress {
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse {
+        let mut response = next.run(req, ctx);
+        if !self.compressible(&response) {
+            return response;
+        }
+        // Whether or not this body is compressed, another one could be.
+        let headers = response.headers_mut();
+        if !headers
+            .get_all("Vary")
+            .any(|v| v.eq_ignore_ascii_case("Accept-Encoding"))
+        {
+            headers.append("Vary", "Accept-Encoding");
+        }
+        let accept_encoding = req.headers.get("Accept-Encoding");
+        // Small bodies are sent as they are, if the client takes them so.
+        if response.body().len() < self.min_size
+            && compression::negotiate(accept_encoding, &[]).is_some()
+        {
+            return response;
+        }
+
+        let available = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+        let encoding = match compression::negotiate(accept_encoding, &available) {
+            Some(Encoding::Identity) => return response,
+            Some(encoding) => encoding,
+            None => {
+                let mut refused = HandlerError::NotAcceptable.respond(req);
+                refused.headers_mut().insert("Vary", "Accept-Encoding");
+                return refused;
+            }
+        };
+        match compression::compress(encoding, response.body()) {
+            Ok(body) => {
+                response.set_body(body);
+                let headers = response.headers_mut();
+                headers.insert("Content-Encoding", encoding.as_str());
+                // The compressed bytes differ, so the tag can no longer be strong.
+                if let Some(etag) = headers.get("ETag").filter(|e| !e.starts_with("W/")) {
+                    let weak = format!("W/{}", etag);
+                    headers.insert("ETag", weak);
+                }
+            }
+            Err(e) => eprintln!("Compressing {} {}: {}", req.method, req.resource, e),
+        }
+        response
+    }
+}
+
+// Turns a panicking handler into a 500 response instead of a dropped
+// connection.
+#[derive(Debug, Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn call(&self, req: &HttpRequest, ctx: &RequestContext, next: Next) -> HttpResponse {
+        match panic::catch_unwind(AssertUnwindSafe(|| next.run(req, ctx))) {
+            Ok(response) => response,
+            Err(_) => {
+                let message = format!("panicked serving {} {}", req.method, req.resource);
+                HandlerError::Internal(message).respond(req)
+            }
+        }
+    }
+}
+
+// Answers every request with a permanent redirect to the same URL over
+// HTTPS, for the plain listener of `TlsConfig::redirect_from`. 308 keeps the
+// method and body of non-GET requests.
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+    port: u16,
+}
+
+impl HttpsRedirect {
+    // `port` is where HTTPS is served; 443 is left out of the URL.
+    pub fn new(port: u16) -> Self {
+        HttpsRedirect { port }
+    }
+}
+
+impl Middleware for HttpsRedirect {
+    fn call(&self, req: &HttpRequest, _: &RequestContext, _: Next) -> HttpResponse {
+        // An absolute-form target names the host itself, taking precedence
+        // over Host; authority and asterisk forms redirect to the root.
+        let (authority, target) = match req.resource.uri() {
+            Some(uri) => {
+                let target = match &uri.query {
+                    Some(query) => format!("{}?{}", uri.raw_path, query),
+                    None => uri.raw_path.clone(),
+                };
+                (uri.authority.as_deref(), target)
+            }
+            None => (None, "/".to_string()),
+        };
+        let host = authority
+            .map(|authority| authority.rsplit_once('@').map_or(authority, |(_, host)| host))
+            .or_else(|| req.headers.get("Host"))
+            .map(strip_port);
+        let Some(host) = host else {
+            return HandlerError::BadRequest("missing Host header".to_string()).respond(req);
+        };
+        let location = match self.port {
+            443 => format!("https://{}{}", host, target),
+            port => format!("https://{}:{}{}", host, port, target),
+        };
+        HttpResponse::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header("Location", location)
+            .build()
+    }
+}
+
+// `example.com` for `example.com:80`, `[::1]` for `[::1]:80`.
+fn strip_port(host: &str) -> &str {
+    let end = match host.rfind(']') {
+        Some(bracket) => bracket + 1,
+        None => host.find(':').unwrap_or(host.len()),
+    };
+    &host[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use http::headers::HeaderMap;
+    use std::io::Read;
+
+    fn request(raw: &str) -> HttpRequest {
+        HttpRequest::try_from(raw).unwrap()
+    }
+
+    fn hello(_: &HttpRequest, _: &RequestContext) -> Result<HttpResponse, HandlerError> {
+        Ok(HttpResponse::new(
+            StatusCode::OK,
+            None,
+            Some("hello".into()),
+        ))
+    }
+
+    #[test]
+    fn test_order_and_short_circuit() {
+        let mut router = Router::new();
+        router
+            .wrap(|req: &HttpRequest, ctx: &RequestContext, next: Next| {
+                let mut response = next.run(req, ctx);
+                response.headers_mut().append("X-Trace", "outer");
+                response
+            })
+            .wrap(|req: &HttpRequest, ctx: &RequestContext, next: Next| {
+                if req.headers.contains("X-Block") {
+                    return HttpResponse::new(StatusCode::FORBIDDEN, None, None);
+                }
+                let mut response = next.run(req, ctx);
+                response.headers_mut().append("X-Trace", "inner");
+                response
+            })
+            .get("/hello", hello);
+
+        let response = router.route(&request("GET /hello HTTP/1.1\r\n\r\n"), None);
+        let trace: Vec<&str> = response.headers().get_all("X-Trace").collect();
+        assert_eq!(vec!["inner", "outer"], trace);
+
+        // Middleware also sees requests no route matched.
+        let response = router.route(&request("GET /nothing HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!(2, response.headers().get_all("X-Trace").count());
+
+        let blocked = request("GET /hello HTTP/1.1\r\nX-Block: 1\r\n\r\n");
+        let response = router.route(&blocked, None);
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert_eq!(
+            vec!["outer"],
+            response.headers().get_all("X-Trace").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_group_middleware() {
+        let mut router = Router::new();
+        router.get("/hello", hello).group("/api", |api| {
+            api.wrap(Timing).get("/hello", hello);
+        });
+
+        let response = router.route(&request("GET /api/hello HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(b"hello", response.body());
+        assert!(response.headers().contains("Server-Timing"));
+
+        let response = router.route(&request("GET /hello HTTP/1.1\r\n\r\n"), None);
+        assert!(!response.headers().contains("Server-Timing"));
+        let response = router.route(&request("GET /apiary HTTP/1.1\r\n\r\n"), None);
+        assert!(!response.headers().contains("Server-Timing"));
+    }
+
+    #[test]
+    fn test_request_id() {
+        let mut router = Router::new();
+        router.wrap(RequestId::new()).get("/hello", hello);
+
+        let first = router.route(&request("GET /hello HTTP/1.1\r\n\r\n"), None);
+        let second = router.route(&request("GET /hello HTTP/1.1\r\n\r\n"), None);
+        let first = first.headers().get(REQUEST_ID).unwrap();
+        assert_ne!(first, second.headers().get(REQUEST_ID).unwrap());
+
+        let traced = request("GET /hello HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n");
+        let response = router.route(&traced, None);
+        assert_eq!(Some("abc-123"), response.headers().get(REQUEST_ID));
+
+        let bogus = request("GET /hello HTTP/1.1\r\nX-Request-Id: a b\r\n\r\n");
+        let response = router.route(&bogus, None);
+        assert_ne!(Some("a b"), response.headers().get(REQUEST_ID));
+    }
+
+    #[test]
+    fn test_cors() {
+        let cors = Cors::new()
+            .allow_origin("https://shop.example.com")
+            .allow_methods([Method::Get, Method::Delete])
+            .allow_headers(["Content-Type"])
+            .max_age(600);
+        let mut router = Router::new();
+        router.wrap(cors).get("/hello", hello);
+
+        let preflight = request(
+            "OPTIONS /hello HTTP/1.1\r\nOrigin: https://shop.example.com\r\n\
+             Access-Control-Request-Method: DELETE\r\n\r\n",
+        );
+        let response = router.route(&preflight, None);
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let headers = response.headers();
+        assert_eq!(
+            Some("https://shop.example.com"),
+            headers.get("Access-Control-Allow-Origin")
+        );
+        assert_eq!(
+            Some("GET, DELETE"),
+            headers.get("Access-Control-Allow-Methods")
+        );
+        assert_eq!(
+            Some("Content-Type"),
+            headers.get("Access-Control-Allow-Headers")
+        );
+        assert_eq!(Some("600"), headers.get("Access-Control-Max-Age"));
+
+        let simple = request("GET /hello HTTP/1.1\r\nOrigin: https://shop.example.com\r\n\r\n");
+        let response = router.route(&simple, None);
+        assert_eq!(b"hello", response.body());
+        assert_eq!(Some("Origin"), response.headers().get("Vary"));
+
+        let foreign = request("GET /hello HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n");
+        let response = router.route(&foreign, None);
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn test_compress() {
+        let orders = |_: &HttpRequest, ctx: &RequestContext| {
+            let count: usize = ctx.params().parse("count").unwrap_or(0);
+            let body = format!("[{}]", vec!["{\"order_id\":1}"; count].join(","));
+            let headers =
+                HeaderMap::from([("Content-Type", "application/json"), ("ETag", "\"v1\"")]);
+            Ok(HttpResponse::new(
+                StatusCode::OK,
+                Some(headers),
+                Some(body.into()),
+            ))
+        };
+        let mut router = Router::new();
+        router
+            .wrap(Compress::new().min_size(100))
+            .get("/orders/{count}", orders)
+            .get("/hello", hello);
+
+        let gzip = request("GET /orders/50 HTTP/1.1\r\nAccept-Encoding: gzip, deflate\r\n\r\n");
+        let response = router.route(&gzip, None);
+        assert_eq!(Some("gzip"), response.headers().get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+        assert_eq!(Some("W/\"v1\""), response.headers().get("ETag"));
+        let mut body = String::new();
+        flate2::read::GzDecoder::new(response.body())
+            .read_to_string(&mut body)
+            .unwrap();
+        assert!(body.starts_with("[{\"order_id\":1},"));
+
+        let br = request("GET /orders/50 HTTP/1.1\r\nAccept-Encoding: gzip, br\r\n\r\n");
+        let response = router.route(&br, None);
+        assert_eq!(Some("br"), response.headers().get("Content-Encoding"));
+
+        // Too small, not accepted, or not JSON: sent as is.
+        let small = request("GET /orders/2 HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let response = router.route(&small, None);
+        assert_eq!(None, response.headers().get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+        let plain = router.route(&request("GET /orders/50 HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(None, plain.headers().get("Content-Encoding"));
+        assert_eq!(Some("\"v1\""), plain.headers().get("ETag"));
+        let html = request("GET /hello HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let response = router.route(&html, None);
+        assert_eq!(None, response.headers().get("Vary"));
+        assert_eq!(b"hello", response.body());
+
+        // Without identity, even small bodies are compressed, or refused.
+        let small =
+            request("GET /orders/2 HTTP/1.1\r\nAccept-Encoding: gzip, identity;q=0\r\n\r\n");
+        let response = router.route(&small, None);
+        assert_eq!(Some("gzip"), response.headers().get("Content-Encoding"));
+        let zstd = request("GET /orders/50 HTTP/1.1\r\nAccept-Encoding: zstd, *;q=0\r\n\r\n");
+        let response = router.route(&zstd, None);
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, response.status());
+        assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let mut router = Router::new();
+        router.wrap(CatchPanic).get(
+            "/boom",
+            |_: &HttpRequest, _: &RequestContext| -> Result<HttpResponse, HandlerError> {
+                panic!("boom")
+            },
+        );
+        let response = router.route(&request("GET /boom HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    }
+
+    #[test]
+    fn test_https_redirect() {
+        let location = |port: u16, raw: &str| {
+            let mut router = Router::new();
+            router.wrap(HttpsRedirect::new(port)).get("/", hello);
+            let response = router.route(&request(raw), None);
+            assert_eq!(StatusCode::PERMANENT_REDIRECT, response.status());
+            response.headers().get("Location").map(str::to_string)
+        };
+        assert_eq!(
+            Some("https://example.com/a?b=1"),
+            location(443, "GET /a?b=1 HTTP/1.1\r\nHost: example.com:80\r\n\r\n").as_deref()
+        );
+        assert_eq!(
+            Some("https://[::1]:8443/"),
+            location(8443, "DELETE / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n").as_deref()
+        );
+        assert_eq!(
+            Some("https://example.com/a?b=1"),
+            location(
+                443,
+                "GET http://example.com:80/a?b=1 HTTP/1.1\r\nHost: other.com\r\n\r\n"
+            )
+            .as_deref()
+        );
+        assert_eq!(
+            Some("https://example.com/"),
+            location(443, "OPTIONS * HTTP/1.1\r\nHost: example.com\r\n\r\n").as_deref()
+        );
+
+        let mut router = Router::new();
+        router.wrap(HttpsRedirect::new(443));
+        let response = router.route(&request("GET / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+}
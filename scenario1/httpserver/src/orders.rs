@@ -0,0 +1,467 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::FileCache;
+use crate::handler::HandlerError;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderStatus {
+    pub order_id: i32,
+    pub order_date: String,
+    pub order_status: String,
+}
+
+// The body of a POST or PUT. Without an `order_id` a POST gets the next free
+// one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewOrder {
+    pub order_id: Option<i32>,
+    pub order_date: String,
+    pub order_status: String,
+}
+
+// The body of a PATCH, the fields to change.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OrderUpdate {
+    pub order_date: Option<String>,
+    pub order_status: Option<String>,
+}
+
+// Which orders a listing includes. Dates are inclusive.
+#[derive(Debug, Clone, Default)]
+pub struct OrderFilter {
+    pub status: Option<String>,
+    pub from: Option<Date>,
+    pub to: Option<Date>,
+}
+
+impl OrderFilter {
+    pub fn matches(&self, order: &OrderStatus) -> bool {
+        if let Some(status) = &self.status {
+            if !order.order_status.eq_ignore_ascii_case(status) {
+                return false;
+            }
+        }
+        if self.from.is_none() && self.to.is_none() {
+            return true;
+        }
+        let Some(date) = Date::parse(&order.order_date) else {
+            return false;
+        };
+        self.from.is_none_or(|from| date >= from) && self.to.is_none_or(|to| date <= to)
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// A calendar date. Written like `21 Jan 2020`, the format of `orders.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+impl Date {
+    // Reads `21 Jan 2020` or `2020-01-21`.
+    pub fn parse(s: &str) -> Option<Date> {
+        let s = s.trim();
+        let (year, month, day) = match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [day, month, year] => {
+                let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))?;
+                (year.parse().ok()?, month as u8 + 1, day.parse().ok()?)
+            }
+            _ => {
+                let mut parts = s.splitn(3, '-');
+                let year = parts.next().filter(|y| y.len() == 4)?.parse().ok()?;
+                let month = parts.next().filter(|m| m.len() == 2)?.parse().ok()?;
+                let day = parts.next().filter(|d| d.len() == 2)?.parse().ok()?;
+                (year, month, day)
+            }
+        };
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days = match month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return None,
+        };
+        (1..=days)
+            .contains(&day)
+            .then_some(Date { year, month, day })
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let month = MONTHS[self.month as usize - 1];
+        write!(f, "{} {} {}", self.day, month, self.year)
+    }
+}
+
+// The orders in a JSON file. Every change reads the file, applies the change
+// and writes a temporary file that is then renamed over the original, all
+// while holding a lock shared by the clones of the store. Readers never see
+// a partial file and concurrent changes don't lose each other.
+#[derive(Clone)]
+pub struct OrderStore {
+    path: PathBuf,
+    cache: Option<Arc<FileCache<Vec<OrderStatus>>>>,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl OrderStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        OrderStore {
+            path: path.into(),
+            cache: None,
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    // Keeps the parsed orders in `cache` between changes.
+    pub fn cache(mut self, cache: Arc<FileCache<Vec<OrderStatus>>>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Shared with the cache, so reading doesn't copy every order.
+    pub fn list(&self) -> Result<Arc<Vec<OrderStatus>>, HandlerError> {
+        let load = || {
+            let json_contents = fs::read_to_string(&self.path).map_err(|e| self.io_error(e))?;
+            serde_json::from_str(&json_contents).map_err(|e| {
+                HandlerError::Internal(format!("parsing {}: {}", self.path.display(), e))
+            })
+        };
+        match &self.cache {
+            Some(cache) => {
+                let meta = fs::metadata(&self.path).map_err(|e| self.io_error(e))?;
+                cache.get_or_load(&self.path, &meta, load)
+            }
+            None => load().map(Arc::new),
+        }
+    }
+
+    pub fn get(&self, id: i32) -> Result<OrderStatus, HandlerError> {
+        self.list()?
+            .iter()
+            .find(|order| order.order_id == id)
+            .cloned()
+            .ok_or(HandlerError::NotFound)
+    }
+
+    pub fn create(&self, new: NewOrder) -> Result<OrderStatus, HandlerError> {
+        let (order_date, order_status) = validate(&new.order_date, &new.order_status)?;
+        self.modify(|orders| {
+            let order_id = match new.order_id {
+                Some(id) if id <= 0 => {
+                    return Err(HandlerError::BadRequest(
+                        "order_id must be positive".to_string(),
+                    ))
+                }
+                Some(id) if orders.iter().any(|order| order.order_id == id) => {
+                    return Err(HandlerError::Conflict(format!(
+                        "order {} already exists",
+                        id
+                    )))
+                }
+                Some(id) => id,
+                None => {
+                    let last = orders.iter().map(|order| order.order_id).max();
+                    last.unwrap_or(0).checked_add(1).ok_or_else(|| {
+                        HandlerError::Conflict("no order IDs left after the highest".to_string())
+                    })?
+                }
+            };
+            let order = OrderStatus {
+                order_id,
+                order_date,
+                order_status,
+            };
+            orders.push(order.clone());
+            Ok(order)
+        })
+    }
+
+    // Replaces every field of order `id`.
+    pub fn replace(&self, id: i32, new: NewOrder) -> Result<OrderStatus, HandlerError> {
+        if new.order_id.is_some_and(|order_id| order_id != id) {
+            return Err(HandlerError::BadRequest(
+                "order_id doesn't match the URL".to_string(),
+            ));
+        }
+        let (order_date, order_status) = validate(&new.order_date, &new.order_status)?;
+        self.modify(|orders| {
+            let order = find(orders, id)?;
+            order.order_date = order_date;
+            order.order_status = order_status;
+            Ok(order.clone())
+        })
+    }
+
+    pub fn update(&self, id: i32, update: OrderUpdate) -> Result<OrderStatus, HandlerError> {
+        if update.order_date.is_none() && update.order_status.is_none() {
+            return Err(HandlerError::BadRequest("nothing to update".to_string()));
+        }
+        self.modify(|orders| {
+            let order = find(orders, id)?;
+            let (order_date, order_status) = validate(
+                update.order_date.as_deref().unwrap_or(&order.order_date),
+                update
+                    .order_status
+                    .as_deref()
+                    .unwrap_or(&order.order_status),
+            )?;
+            order.order_date = order_date;
+            order.order_status = order_status;
+            Ok(order.clone())
+        })
+    }
+
+    pub fn delete(&self, id: i32) -> Result<(), HandlerError> {
+        self.modify(|orders| {
+            let index = orders
+                .iter()
+                .position(|order| order.order_id == id)
+                .ok_or(HandlerError::NotFound)?;
+            orders.remove(index);
+            Ok(())
+        })
+    }
+
+    fn modify<T>(
+        &self,
+        change: impl FnOnce(&mut Vec<OrderStatus>) -> Result<T, HandlerError>,
+    ) -> Result<T, HandlerError> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut orders = Arc::unwrap_or_clone(self.list()?);
+        let result = change(&mut orders)?;
+        self.save(&orders)?;
+        Ok(result)
+    }
+
+    fn save(&self, orders: &[OrderStatus]) -> Result<(), HandlerError> {
+        let mut json =
+            serde_json::to_vec_pretty(orders).map_err(|e| HandlerError::Internal(e.to_string()))?;
+        json.push(b'\n');
+
+        let file_name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = self
+            .path
+            .with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+        let write = || -> io::Result<()> {
+            let mut file = File::create(&temp)?;
+            file.write_all(&json)?;
+            file.sync_all()?;
+            fs::rename(&temp, &self.path)
+        };
+        let result = write();
+        // The rename may land within the same timestamp tick as the last
+        // write, so don't rely on the cache noticing.
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&self.path);
+        }
+        result.map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            self.io_error(e)
+        })
+    }
+
+    fn io_error(&self, e: io::Error) -> HandlerError {
+        HandlerError::Internal(format!("{}: {}", self.path.display(), e))
+    }
+}
+
+fn find(orders: &mut [OrderStatus], id: i32) -> Result<&mut OrderStatus, HandlerError> {
+    orders
+        .iter_mut()
+        .find(|order| order.order_id == id)
+        .ok_or(HandlerError::NotFound)
+}
+
+// The date in the file's format and the trimmed status.
+fn validate(order_date: &str, order_status: &str) -> Result<(String, String), HandlerError> {
+    let date = Date::parse(order_date)
+        .ok_or_else(|| HandlerError::BadRequest(format!("invalid order_date {:?}", order_date)))?;
+    let status = order_status.trim();
+    if status.is_empty() || status.len() > 64 {
+        return Err(HandlerError::BadRequest(
+            "order_status must be 1 to 64 characters".to_string(),
+        ));
+    }
+    Ok((date.to_string(), status.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn store() -> (tempfile::TempDir, OrderStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("orders.json");
+        fs::write(
+            &path,
+            r#"[
+                {"order_id": 1, "order_date": "21 Jan 2020", "order_status": "Delivered"},
+                {"order_id": 2, "order_date": "2 Feb 2020", "order_status": "Pending"}
+            ]"#,
+        )
+        .unwrap();
+        (dir, OrderStore::new(path))
+    }
+
+    fn new_order(order_id: Option<i32>, date: &str, status: &str) -> NewOrder {
+        NewOrder {
+            order_id,
+            order_date: date.to_string(),
+            order_status: status.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_date() {
+        let date = Date::parse("2 Feb 2020").unwrap();
+        assert_eq!(Some(date), Date::parse("2020-02-02"));
+        assert_eq!("2 Feb 2020", date.to_string());
+        assert!(Date::parse("21 Jan 2020").unwrap() < date);
+        assert!(Date::parse("29 Feb 2020").is_some());
+        assert!(Date::parse("29 Feb 2021").is_none());
+        assert!(Date::parse("2020-13-01").is_none());
+        assert!(Date::parse("2020-1-01").is_none());
+        assert!(Date::parse("yesterday").is_none());
+    }
+
+    #[test]
+    fn test_crud() {
+        let (dir, store) = store();
+
+        let created = store
+            .create(new_order(None, "2020-03-05", " Shipped "))
+            .unwrap();
+        assert_eq!(3, created.order_id);
+        assert_eq!("5 Mar 2020", created.order_date);
+        assert_eq!("Shipped", created.order_status);
+        assert_eq!(created, store.get(3).unwrap());
+
+        let update = OrderUpdate {
+            order_status: Some("Delivered".to_string()),
+            ..Default::default()
+        };
+        let updated = store.update(2, update).unwrap();
+        assert_eq!(
+            ("2 Feb 2020", "Delivered"),
+            (updated.order_date.as_str(), updated.order_status.as_str())
+        );
+
+        let replaced = store
+            .replace(2, new_order(Some(2), "3 Feb 2020", "Returned"))
+            .unwrap();
+        assert_eq!("Returned", replaced.order_status);
+
+        store.delete(1).unwrap();
+        let ids: Vec<i32> = store.list().unwrap().iter().map(|o| o.order_id).collect();
+        assert_eq!(vec![2, 3], ids);
+
+        // Written back as a whole file, without leftovers.
+        let reread = OrderStore::new(store.path()).list().unwrap();
+        assert_eq!(store.list().unwrap(), reread);
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_errors() {
+        let (_dir, store) = store();
+        let before = fs::read(store.path()).unwrap();
+
+        let conflict = store.create(new_order(Some(2), "1 Mar 2020", "Pending"));
+        assert_eq!(
+            Err(HandlerError::Conflict("order 2 already exists".to_string())),
+            conflict
+        );
+        let bad_date = store.create(new_order(None, "31 Feb 2020", "Pending"));
+        assert!(matches!(bad_date, Err(HandlerError::BadRequest(_))));
+        let bad_status = store.create(new_order(None, "1 Mar 2020", "  "));
+        assert!(matches!(bad_status, Err(HandlerError::BadRequest(_))));
+        let mismatch = store.replace(1, new_order(Some(2), "1 Mar 2020", "Pending"));
+        assert!(matches!(mismatch, Err(HandlerError::BadRequest(_))));
+        let empty = store.update(1, OrderUpdate::default());
+        assert!(matches!(empty, Err(HandlerError::BadRequest(_))));
+
+        assert_eq!(Err(HandlerError::NotFound), store.get(9));
+        assert_eq!(Err(HandlerError::NotFound), store.delete(9));
+        let missing = store.replace(9, new_order(None, "1 Mar 2020", "Pending"));
+        assert_eq!(Err(HandlerError::NotFound), missing);
+
+        assert_eq!(before, fs::read(store.path()).unwrap());
+    }
+
+    #[test]
+    fn test_filter() {
+        let (_dir, store) = store();
+        let orders = store.list().unwrap();
+        let ids = |filter: OrderFilter| -> Vec<i32> {
+            orders
+                .iter()
+                .filter(|order| filter.matches(order))
+                .map(|order| order.order_id)
+                .collect()
+        };
+
+        let pending = OrderFilter {
+            status: Some("pending".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(vec![2], ids(pending));
+        let january = OrderFilter {
+            from: Date::parse("2020-01-01"),
+            to: Date::parse("2020-01-31"),
+            ..Default::default()
+        };
+        assert_eq!(vec![1], ids(january));
+        let since = OrderFilter {
+            from: Date::parse("21 Jan 2020"),
+            ..Default::default()
+        };
+        assert_eq!(vec![1, 2], ids(since));
+    }
+
+    #[test]
+    fn test_concurrent_creates() {
+        let (_dir, store) = store();
+        let cache = Arc::new(FileCache::new(4, 1 << 20));
+        let store = store.cache(cache);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    store
+                        .create(new_order(None, "1 Mar 2020", "Pending"))
+                        .unwrap()
+                        .order_id
+                })
+            })
+            .collect();
+        let mut ids: Vec<i32> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        ids.sort();
+        assert_eq!((3..=10).collect::<Vec<_>>(), ids);
+        assert_eq!(10, store.list().unwrap().len());
+    }
+}
@@ -1,22 +1,721 @@
-use http::httprequest::{HttpRequest, Method, Resource};
+use std::{any::Any, net::SocketAddr, str::FromStr, sync::Arc};
 
-use crate::handler::{Handler, PageNotFoundHandler, StaticPageHandler, WebServiceHandler};
+use http::{
+    httprequest::{HttpRequest, Method},
+    httpresponse::{HttpResponse, StatusCode},
+};
 
-pub struct Router {}
-impl Router {
-    pub fn route(req: HttpRequest, stream: &mut std::net::TcpStream) {
-        match req.method {
-            Method::Get => match &req.resource {
-                Resource::Path(path) => {
-                    let route: Vec<&str> = path.split("/").collect();
-
-                    match route[1] {
-                        "api" => WebServiceHandler::handle(&req).send_response(stream),
-                        _ => StaticPageHandler::handle(&req).send_response(stream),
+use crate::handler::{AppState, Handler, HandlerError, RequestContext};
+use crate::middleware::{Middleware, Next};
+use crate::websocket::{Handshake, WebSocketHandler};
+
+// Values captured by the `{name}` and `{*name}` segments of a route pattern.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The parameter converted to `T`, `None` if it is missing or doesn't parse.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name)?.parse().ok()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+// For calling a handler outside the router, e.g. from another handler.
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Params {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Params {
+            pairs: iter
+                .into_iter()
+                .map(|(n, v)| (n.into(), v.into()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Static(String),
+    // `{name}`, exactly one non-empty segment.
+    Param(String),
+    // `{*name}`, the rest of the path, possibly empty. Only valid last.
+    Wildcard(String),
+}
+
+impl Segment {
+    // Static segments beat parameters, which beat wildcards.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 2,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 0,
+        }
+    }
+
+    fn same_shape(&self, other: &Segment) -> bool {
+        match (self, other) {
+            (Segment::Static(a), Segment::Static(b)) => a == b,
+            (a, b) => a.rank() == b.rank(),
+        }
+    }
+}
+
+struct Route {
+    method: Method,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Box<dyn Handler>,
+    // Takes over the connection once `handler` has answered with 101.
+    socket: Option<Arc<dyn WebSocketHandler>>,
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<Params> {
+        let mut params = Params::default();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(s) => {
+                    if path.get(i) != Some(&s.as_str()) {
+                        return None;
                     }
                 }
+                Segment::Param(name) => match path.get(i) {
+                    Some(value) if !value.is_empty() => {
+                        params.pairs.push((name.clone(), value.to_string()));
+                    }
+                    _ => return None,
+                },
+                Segment::Wildcard(name) => {
+                    let rest = path.get(i..).unwrap_or_default().join("/");
+                    params.pairs.push((name.clone(), rest));
+                    return Some(params);
+                }
+            }
+        }
+        (self.segments.len() == path.len()).then_some(params)
+    }
+
+    fn rank(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+}
+
+// Dispatches requests to the handler registered for their method and path:
+//
+//     let mut router = Router::new();
+//     router.get("/api/shipping/orders/{id}", OrderHandler::new(db));
+//
+// When several patterns match, the most specific one wins segment by
+// segment, regardless of registration order. HEAD falls back to the GET
+// handler, OPTIONS is answered from the registered methods, and requests
+// that match no route get 404, or 405 if only the method is wrong.
+//
+// Middleware added with `wrap` runs around every request, including those
+// answered automatically; a `group` adds middleware for one path prefix.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Box<dyn Middleware>>,
+    groups: Vec<Group>,
+    state: AppState,
+}
+
+// Middleware scoped to the paths under `prefix`.
+struct Group {
+    prefix: String,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Group {
+    fn contains(&self, path: &str) -> bool {
+        path.strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn get<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+    where
+        H: Handler + 'static,
+    {
+        self.add(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+    where
+        H: Handler + 'static,
+    {
+        self.add(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+    where
+        H: Handler + 'static,
+    {
+        self.add(Method::Put, pattern, handler)
+    }
+
+    pub fn patch<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+    where
+        H: Handler + 'static,
+    {
+        self.add(Method::Patch, pattern, handler)
+    }
+
+    pub fn delete<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+    where
+        H: Handler + 'static,
+    {
+        self.add(Method::Delete, pattern, handler)
+    }
+
+    // Registers `handler` for `method` requests matching `pattern`. Panics if
+    // the pattern is malformed or the same route is registered twice.
+    pub fn add<H>(&mut self, method: Method, pattern: &str, handler: H) -> &mut Self
+    where
+        H: Handler + 'static,
+    {
+        let segments = parse_pattern(pattern);
+        let duplicate = self.routes.iter().find(|route| {
+            route.method == method
+                && route.segments.len() == segments.len()
+                && route
+                    .segments
+                    .iter()
+                    .zip(&segments)
+                    .all(|(a, b)| a.same_shape(b))
+        });
+        if let Some(route) = duplicate {
+            panic!(
+                "route {} {} conflicts with {}",
+                method, pattern, route.pattern
+            );
+        }
+
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            segments,
+            handler: Box::new(handler),
+            socket: None,
+        });
+        self
+    }
+
+    // Accepts WebSocket connections at `pattern`: GET requests asking to
+    // upgrade get the opening handshake, the connection is then served by
+    // `handler`. Other requests to `pattern` get 426. In blocking mode each
+    // connection keeps a worker while open, and once
+    // `ServerConfig::max_upgraded` of them do, handshakes get 503.
+    pub fn websocket<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+    where
+        H: WebSocketHandler + 'static,
+    {
+        self.add(Method::Get, pattern, Handshake);
+        if let Some(route) = self.routes.last_mut() {
+            route.socket = Some(Arc::new(handler));
+        }
+        self
+    }
+
+    // Adds `middleware` around every request. The first one added is the
+    // outermost.
+    pub fn wrap<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    // Registers routes under `prefix` with their own middleware, which runs
+    // inside the router-wide middleware for any request below `prefix`:
+    //
+    //     router.group("/api", |api| {
+    //         api.wrap(Cors::new()).get("/shipping/orders", orders);
+    //     });
+    pub fn group<F>(&mut self, prefix: &str, f: F) -> &mut Self
+    where
+        F: FnOnce(&mut RouteGroup),
+    {
+        let prefix = prefix.trim_end_matches('/');
+        assert!(
+            prefix.starts_with('/'),
+            "group prefix {:?} must start with '/'",
+            prefix
+        );
+        self.groups.push(Group {
+            prefix: prefix.to_string(),
+            middleware: Vec::new(),
+        });
+        let index = self.groups.len() - 1;
+        f(&mut RouteGroup {
+            router: self,
+            index,
+        });
+        self
+    }
+
+    // Makes `value` available to every handler through
+    // `RequestContext::state`.
+    pub fn state<T: Any + Send + Sync>(&mut self, value: T) -> &mut Self {
+        self.state.insert(value);
+        self
+    }
+
+    pub fn route(&self, req: &HttpRequest, peer_addr: Option<SocketAddr>) -> HttpResponse {
+        let mut middleware: Vec<&dyn Middleware> =
+            self.middleware.iter().map(|m| m.as_ref()).collect();
+        for group in self.groups.iter().filter(|g| g.contains(req.path())) {
+            middleware.extend(group.middleware.iter().map(|m| m.as_ref()));
+        }
+
+        let ctx = RequestContext::new(Params::default(), peer_addr, &self.state);
+        Next::new(&middleware, &Dispatch(self)).run(req, &ctx)
+    }
+
+    // The WebSocket handler of the route `req` went to, with its context,
+    // for serving the connection after the handshake.
+    pub(crate) fn websocket_handler(
+        &self,
+        req: &HttpRequest,
+        peer_addr: Option<SocketAddr>,
+    ) -> Option<(Arc<dyn WebSocketHandler>, RequestContext<'_>)> {
+        if req.method != Method::Get {
+            return None;
+        }
+        let path = split_path(req.path());
+        let (route, params) = self
+            .routes
+            .iter()
+            .filter(|route| route.method == Method::Get)
+            .filter_map(|route| route.matches(&path).map(|params| (route, params)))
+            .max_by_key(|(route, _)| route.rank())?;
+        let ctx = RequestContext::new(params, peer_addr, &self.state);
+        Some((route.socket.clone()?, ctx))
+    }
+
+    fn dispatch(&self, req: &HttpRequest, peer_addr: Option<SocketAddr>) -> HttpResponse {
+        if let Method::Extension(_) = req.method {
+            if !self.routes.iter().any(|route| route.method == req.method) {
+                return HandlerError::NotImplemented.respond(req);
+            }
+        }
+        if req.method == Method::Options && req.path() == "*" {
+            let response = HttpResponse::new(StatusCode::OK, None, None);
+            return allow_response(response, self.routes.iter());
+        }
+
+        let path = split_path(req.path());
+        let matching: Vec<(&Route, Params)> = self
+            .routes
+            .iter()
+            .filter_map(|route| route.matches(&path).map(|params| (route, params)))
+            .collect();
+        if matching.is_empty() {
+            return HandlerError::NotFound.respond(req);
+        }
+
+        let best = |method: &Method| {
+            matching
+                .iter()
+                .filter(|(route, _)| route.method == *method)
+                .max_by_key(|(route, _)| route.rank())
+        };
+        let found = match best(&req.method) {
+            None if req.method == Method::Head => best(&Method::Get),
+            found => found,
+        };
+        match found {
+            Some((route, params)) => {
+                let ctx = RequestContext::new(params.clone(), peer_addr, &self.state);
+                route
+                    .handler
+                    .handle(req, &ctx)
+                    .unwrap_or_else(|e| e.respond(req))
+            }
+            None if req.method == Method::Options => {
+                let response = HttpResponse::new(StatusCode::OK, None, None);
+                allow_response(response, matching.iter().map(|(route, _)| *route))
+            }
+            None => {
+                let response = HandlerError::MethodNotAllowed.respond(req);
+                allow_response(response, matching.iter().map(|(route, _)| *route))
+            }
+        }
+    }
+}
+
+// Registers routes and middleware under a prefix, see `Router::group`.
+pub struct RouteGroup<'a> {
+    router: &'a mut Router,
+    index: usize,
+}
+
+impl RouteGroup<'_> {
+    // Adds `middleware` for requests below the group's prefix.
+    pub fn wrap<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.router.groups[self.index]
+            .middleware
+            .push(Box::new(middleware));
+        self
+    }
+
+    pub fn get<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.add(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.add(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.add(Method::Put, pattern, handler)
+    }
+
+    pub fn patch<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.add(Method::Patch, pattern, handler)
+    }
+
+    pub fn delete<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.add(Method::Delete, pattern, handler)
+    }
+
+    pub fn add<H: Handler + 'static>(
+        &mut self,
+        method: Method,
+        pattern: &str,
+        handler: H,
+    ) -> &mut Self {
+        let pattern = self.pattern(pattern);
+        self.router.add(method, &pattern, handler);
+        self
+    }
+
+    pub fn websocket<H: WebSocketHandler + 'static>(
+        &mut self,
+        pattern: &str,
+        handler: H,
+    ) -> &mut Self {
+        let pattern = self.pattern(pattern);
+        self.router.websocket(&pattern, handler);
+        self
+    }
+
+    // `pattern` is relative to the prefix; "/" is the prefix itself.
+    fn pattern(&self, pattern: &str) -> String {
+        let prefix = &self.router.groups[self.index].prefix;
+        match pattern {
+            "/" => prefix.clone(),
+            pattern => format!("{}{}", prefix, pattern),
+        }
+    }
+}
+
+// The end of the middleware chain: route matching and the matched handler.
+struct Dispatch<'a>(&'a Router);
+
+impl Handler for Dispatch<'_> {
+    fn handle(
+        &self,
+        req: &HttpRequest,
+        ctx: &RequestContext,
+    ) -> Result<HttpResponse, HandlerError> {
+        Ok(self.0.dispatch(req, ctx.peer_addr()))
+    }
+}
+
+// `response` listing the methods of `routes` in `Allow`, including the HEAD
+// and OPTIONS the router derives.
+fn allow_response<'a>(
+    mut response: HttpResponse,
+    routes: impl Iterator<Item = &'a Route>,
+) -> HttpResponse {
+    let mut methods: Vec<&str> = Vec::new();
+    for route in routes {
+        let derived = if route.method == Method::Get {
+            [Some(Method::Get.as_str()), Some(Method::Head.as_str())]
+        } else {
+            [Some(route.method.as_str()), None]
+        };
+        for method in derived.into_iter().flatten() {
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+    }
+    if !methods.contains(&Method::Options.as_str()) {
+        methods.push(Method::Options.as_str());
+    }
+
+    response.headers_mut().insert("Allow", methods.join(", "));
+    response
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    match path.strip_prefix('/') {
+        Some(path) => path.split('/').collect(),
+        None => Vec::new(),
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let Some(path) = pattern.strip_prefix('/') else {
+        panic!("route pattern {:?} must start with '/'", pattern);
+    };
+
+    let parts: Vec<&str> = path.split('/').collect();
+    let mut segments = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        let segment = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+            Some(name) => match name.strip_prefix('*') {
+                Some(name) if i + 1 == parts.len() => Segment::Wildcard(name.to_string()),
+                Some(_) => panic!("wildcard must be the last segment in {:?}", pattern),
+                None => Segment::Param(name.to_string()),
+            },
+            None => Segment::Static(part.to_string()),
+        };
+        match &segment {
+            Segment::Static(s) if s.contains(['{', '}']) => {
+                panic!("malformed segment {:?} in {:?}", s, pattern)
+            }
+            Segment::Param(name) | Segment::Wildcard(name) => {
+                let taken = segments
+                    .iter()
+                    .any(|s| matches!(s, Segment::Param(n) | Segment::Wildcard(n) if n == name));
+                if name.is_empty() || taken {
+                    panic!("bad parameter name {:?} in {:?}", name, pattern);
+                }
+            }
+            Segment::Static(_) => {}
+        }
+        segments.push(segment);
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::HandlerError;
+
+    fn request(method: &str, target: &str) -> HttpRequest {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, target);
+        HttpRequest::try_from(raw.as_str()).unwrap()
+    }
+
+    fn ok(body: String) -> Result<HttpResponse, HandlerError> {
+        Ok(HttpResponse::new(
+            StatusCode::OK,
+            None,
+            Some(body.into_bytes()),
+        ))
+    }
+
+    // Answers with the matched pattern and its parameters.
+    fn echo(pattern: &'static str) -> impl Handler {
+        move |_: &HttpRequest, ctx: &RequestContext| {
+            let mut body = pattern.to_string();
+            for (name, value) in ctx.params().iter() {
+                body.push_str(&format!(" {}={}", name, value));
+            }
+            ok(body)
+        }
+    }
+
+    fn route(router: &Router, method: &str, target: &str) -> HttpResponse {
+        router.route(&request(method, target), None)
+    }
+
+    fn body(router: &Router, method: &str, target: &str) -> String {
+        let response = route(router, method, target);
+        String::from_utf8(response.body().to_vec()).unwrap()
+    }
+
+    fn test_router() -> Router {
+        let mut router = Router::new();
+        router
+            .get("/{*path}", echo("static"))
+            .get("/api/shipping/orders", echo("orders"))
+            .get("/api/shipping/orders/{id}", echo("order"))
+            .get("/api/shipping/orders/latest", echo("latest"))
+            .post("/api/shipping/orders", echo("create"))
+            .delete("/api/shipping/orders/{id}", echo("remove"));
+        router
+    }
+
+    #[test]
+    fn test_precedence() {
+        let router = test_router();
+        assert_eq!("orders", body(&router, "GET", "/api/shipping/orders"));
+        assert_eq!("order id=7", body(&router, "GET", "/api/shipping/orders/7"));
+        assert_eq!(
+            "latest",
+            body(&router, "GET", "/api/shipping/orders/latest")
+        );
+        assert_eq!("static path=", body(&router, "GET", "/"));
+        assert_eq!(
+            "static path=api/shipping/orders/7/items",
+            body(&router, "GET", "/api/shipping/orders/7/items")
+        );
+        assert_eq!("create", body(&router, "POST", "/api/shipping/orders"));
+    }
+
+    #[test]
+    fn test_params() {
+        let mut router = Router::new();
+        router.get(
+            "/orders/{id}/{*rest}",
+            |_: &HttpRequest, ctx: &RequestContext| {
+                let id: Option<u32> = ctx.params().parse("id");
+                ok(format!("{:?} {:?}", id, ctx.params().get("rest")))
             },
-            _ => PageNotFoundHandler::handle(&req).send_response(stream),
+        );
+        assert_eq!(
+            "Some(42) Some(\"a/b c\")",
+            body(&router, "GET", "/orders/42/a/b%20c")
+        );
+        assert_eq!("None Some(\"\")", body(&router, "GET", "/orders/x"));
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            route(&router, "GET", "/orders/").status()
+        );
+    }
+
+    #[test]
+    fn test_automatic_responses() {
+        let router = test_router();
+
+        let head = route(&router, "HEAD", "/api/shipping/orders/7");
+        assert_eq!(StatusCode::OK, head.status());
+
+        let not_allowed = route(&router, "PUT", "/api/shipping/orders/7");
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, not_allowed.status());
+        assert_eq!(
+            Some("GET, HEAD, DELETE, OPTIONS"),
+            not_allowed.headers().get("Allow")
+        );
+        let error: serde_json::Value = serde_json::from_slice(not_allowed.body()).unwrap();
+        assert_eq!("Method Not Allowed", error["error"]);
+
+        let options = route(&router, "OPTIONS", "/api/shipping/orders");
+        assert_eq!(StatusCode::OK, options.status());
+        assert_eq!(
+            Some("GET, HEAD, POST, OPTIONS"),
+            options.headers().get("Allow")
+        );
+
+        let purge = route(&router, "PURGE", "/");
+        assert_eq!(StatusCode::NOT_IMPLEMENTED, purge.status());
+
+        let mut router = Router::new();
+        router.get("/health", echo("health"));
+        let missing = route(&router, "GET", "/orders");
+        assert_eq!(StatusCode::NOT_FOUND, missing.status());
+    }
+
+    #[test]
+    fn test_context() {
+        struct Greeting(&'static str);
+        struct Greeter {
+            punctuation: char,
         }
+        impl Handler for Greeter {
+            fn handle(
+                &self,
+                _: &HttpRequest,
+                ctx: &RequestContext,
+            ) -> Result<HttpResponse, HandlerError> {
+                let greeting = ctx.state::<Greeting>().ok_or(HandlerError::NotFound)?;
+                let peer = ctx.peer_addr().map(|addr| addr.ip().to_string());
+                let name = ctx.params().get("name").unwrap_or_default();
+                ok(format!(
+                    "{} {}{} from {}",
+                    greeting.0,
+                    name,
+                    self.punctuation,
+                    peer.unwrap_or_default()
+                ))
+            }
+        }
+
+        let mut router = Router::new();
+        router
+            .state(Greeting("hello"))
+            .get("/greet/{name}", Greeter { punctuation: '!' })
+            .get("/fail", |_: &HttpRequest, _: &RequestContext| {
+                Err(HandlerError::BadRequest("no".into()))
+            });
+
+        let peer = "127.0.0.1:4000".parse().ok();
+        let response = router.route(&request("GET", "/greet/ann"), peer);
+        assert_eq!(b"hello ann! from 127.0.0.1", response.body());
+
+        let failed = route(&router, "GET", "/fail");
+        assert_eq!(StatusCode::BAD_REQUEST, failed.status());
+        let error: serde_json::Value = serde_json::from_slice(failed.body()).unwrap();
+        assert_eq!("no", error["message"]);
+    }
+
+    #[test]
+    fn test_websocket_route() {
+        struct Feed;
+        impl WebSocketHandler for Feed {}
+
+        let mut router = Router::new();
+        router.group("/api", |api| {
+            api.websocket("/orders/{id}/live", Feed);
+        });
+        let upgrade = |key: &str| {
+            let raw = format!(
+                "GET /api/orders/7/live HTTP/1.1\r\nHost: localhost\r\n\
+                 Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+                key
+            );
+            HttpRequest::try_from(raw.as_str()).unwrap()
+        };
+
+        let req = upgrade("dGhlIHNhbXBsZSBub25jZQ==");
+        let response = router.route(&req, None);
+        assert_eq!(StatusCode::SWITCHING_PROTOCOLS, response.status());
+        assert_eq!(
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            response.headers().get("Sec-WebSocket-Accept")
+        );
+        let (_, ctx) = router.websocket_handler(&req, None).unwrap();
+        assert_eq!(Some(7), ctx.params().parse::<u32>("id"));
+
+        let bad_key = router.route(&upgrade("short"), None);
+        assert_eq!(StatusCode::BAD_REQUEST, bad_key.status());
+
+        let plain = route(&router, "GET", "/api/orders/7/live");
+        assert_eq!(StatusCode::UPGRADE_REQUIRED, plain.status());
+        assert_eq!(Some("websocket"), plain.headers().get("Upgrade"));
+        assert!(router
+            .websocket_handler(&request("GET", "/api/orders"), None)
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "conflicts with /orders/{id}")]
+    fn test_duplicate_route() {
+        let mut router = Router::new();
+        router
+            .get("/orders/{id}", echo("a"))
+            .get("/orders/{order}", echo("b"));
     }
 }
@@ -0,0 +1,244 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::handler::HandlerError;
+
+// Confines file lookups to a root directory. A request path resolves to a
+// file below the root or is refused:
+//
+// - `..`, absolute paths, drive prefixes and backslashes are forbidden (403),
+// - so is anything whose real path, after following symlinks, leaves the root,
+// - hidden files (any component starting with `.`) are not found (404)
+//   unless enabled,
+// - paths matching a deny pattern or below a directory that does, and files
+//   matching no allow pattern when there are any, are not found (404).
+//
+// Patterns are globs: `*` and `?` stop at `/`, `**` doesn't. A pattern
+// without `/` is matched against the file name, otherwise against the whole
+// path relative to the root.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    hidden_files: bool,
+}
+
+impl Sandbox {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Sandbox {
+            root: root.into(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            hidden_files: false,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn allow<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.allow.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn deny<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.deny.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn hidden_files(mut self, serve: bool) -> Self {
+        self.hidden_files = serve;
+        self
+    }
+
+    // The file system path for `relative`, a `/`-separated path below the
+    // root. The result need not exist; if it does, it is the real path.
+    pub fn resolve(&self, relative: &str) -> Result<PathBuf, HandlerError> {
+        if relative.contains(['\\', '\0']) {
+            return Err(HandlerError::Forbidden);
+        }
+        let mut clean = Vec::new();
+        for component in Path::new(relative).components() {
+            match component {
+                Component::Normal(name) => clean.push(name.to_str().ok_or(HandlerError::NotFound)?),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(HandlerError::Forbidden)
+                }
+            }
+        }
+        let clean = clean.join("/");
+        // Whether it is a file decides the allow list, so check that later.
+        if !self.permits(&clean, true) {
+            return Err(HandlerError::NotFound);
+        }
+
+        let path = self.root.join(&clean);
+        let root = fs::canonicalize(&self.root)?;
+        let real = match fs::canonicalize(&path) {
+            Ok(real) => real,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(path),
+            Err(e) => return Err(e.into()),
+        };
+        // A symlink may point anywhere; judge where it leads as well.
+        let Ok(real_relative) = real.strip_prefix(&root) else {
+            return Err(HandlerError::Forbidden);
+        };
+        let is_dir = real.is_dir();
+        match real_relative.to_str() {
+            Some(real_relative)
+                if self.permits(&clean, is_dir) && self.permits(real_relative, is_dir) =>
+            {
+                Ok(real)
+            }
+            _ => Err(HandlerError::NotFound),
+        }
+    }
+
+    // Whether the `/`-separated `relative` path passes the hidden file rule
+    // and the deny list and, unless it names a directory, the allow list.
+    // Doesn't touch the file system.
+    pub fn permits(&self, relative: &str, is_dir: bool) -> bool {
+        let hidden = relative.split('/').any(|name| name.starts_with('.'));
+        if hidden && !self.hidden_files {
+            return false;
+        }
+        let matches = |path: &str, pattern: &String| {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            let subject = if pattern.contains('/') { path } else { name };
+            glob_match(pattern.as_bytes(), subject.as_bytes())
+        };
+        // Denying a directory denies everything below it.
+        let mut ancestors = relative.match_indices('/').map(|(i, _)| &relative[..i]);
+        let denied = |path: &str| self.deny.iter().any(|pattern| matches(path, pattern));
+        if denied(relative) || ancestors.any(denied) {
+            return false;
+        }
+        is_dir || self.allow.is_empty() || self.allow.iter().any(|p| matches(relative, p))
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            let segment = text.iter().position(|&b| b == b'/').unwrap_or(text.len());
+            (0..=segment).any(|i| glob_match(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(text.first(), Some(&b) if b != b'/') && glob_match(rest, &text[1..])
+        }
+        [p, rest @ ..] => text.first() == Some(p) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*.bak", b"orders.bak"));
+        assert!(!glob_match(b"*.bak", b"orders.json"));
+        assert!(glob_match(b"private/*", b"private/a.txt"));
+        assert!(!glob_match(b"private/*", b"private/x/a.txt"));
+        assert!(glob_match(b"private/**", b"private/x/a.txt"));
+        assert!(glob_match(b"img/??.png", b"img/ab.png"));
+        assert!(!glob_match(b"img/?.png", b"img//.png"));
+    }
+
+    #[test]
+    fn test_permits() {
+        let sandbox = Sandbox::new("/srv").deny(["*.bak", "private/**"]);
+        assert!(sandbox.permits("index.html", false));
+        assert!(sandbox.permits("docs/guide.html", false));
+        assert!(!sandbox.permits("orders.json.bak", false));
+        assert!(!sandbox.permits("docs/old.bak", false));
+        assert!(!sandbox.permits("private/keys/id.pem", false));
+        assert!(!sandbox.permits("private/keys", true));
+        assert!(!sandbox.permits(".env", false));
+        assert!(!sandbox.permits("app/.git", true));
+        assert!(!sandbox.permits("app/.git/config", false));
+        let private = Sandbox::new("/srv").deny(["private"]);
+        assert!(!private.permits("private/keys/id.pem", false));
+        assert!(private.permits("docs/private.txt", false));
+        let hidden = sandbox.clone().hidden_files(true);
+        assert!(hidden.permits(".well-known/security.txt", false));
+
+        let sandbox = Sandbox::new("/srv").allow(["*.html", "*.css"]);
+        assert!(sandbox.permits("docs/guide.html", false));
+        assert!(sandbox.permits("data", true));
+        assert!(!sandbox.permits("data/orders.json", false));
+    }
+
+    #[test]
+    fn test_resolve_rejects_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("public");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("index.html"), "home").unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let sandbox = Sandbox::new(&root);
+
+        let real_root = fs::canonicalize(&root).unwrap();
+        assert_eq!(
+            Ok(real_root.join("index.html")),
+            sandbox.resolve("index.html")
+        );
+        assert_eq!(
+            Ok(real_root.join("index.html")),
+            sandbox.resolve("./index.html")
+        );
+        assert_eq!(
+            Ok(root.join("missing.html")),
+            sandbox.resolve("missing.html")
+        );
+
+        for attack in [
+            "../secret.txt",
+            "docs/../../secret.txt",
+            "/etc/passwd",
+            "..\\secret.txt",
+            "a\0b",
+        ] {
+            assert_eq!(
+                Err(HandlerError::Forbidden),
+                sandbox.resolve(attack),
+                "{:?}",
+                attack
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_follows_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("public");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("page.html"), "page").unwrap();
+        fs::write(root.join("notes.bak"), "notes").unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        symlink(dir.path().join("secret.txt"), root.join("leak.txt")).unwrap();
+        symlink(dir.path(), root.join("up")).unwrap();
+        symlink(root.join("page.html"), root.join("alias.html")).unwrap();
+        symlink(root.join("notes.bak"), root.join("notes.txt")).unwrap();
+        let sandbox = Sandbox::new(&root).deny(["*.bak"]);
+
+        assert_eq!(Err(HandlerError::Forbidden), sandbox.resolve("leak.txt"));
+        assert_eq!(
+            Err(HandlerError::Forbidden),
+            sandbox.resolve("up/secret.txt")
+        );
+        assert_eq!(Err(HandlerError::NotFound), sandbox.resolve("notes.txt"));
+        let page = fs::canonicalize(root.join("page.html")).unwrap();
+        assert_eq!(Ok(page), sandbox.resolve("alias.html"));
+    }
+}
@@ -1,33 +1,291 @@
-use http::httprequest::HttpRequest;
-use std::str;
-use std::{io::Read, net::TcpListener};
+use http::{httpresponse::StatusCode, parser::Limits};
+use std::{
+    env,
+    io::{self, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
+use crate::async_server;
+use crate::connection::{error_response, handle_connection, TlsConfigs, UpgradeSlots};
+use crate::handler;
+use crate::middleware::HttpsRedirect;
 use crate::router::Router;
+use crate::shutdown::ShutdownHandle;
+use crate::threadpool::ThreadPool;
+use crate::tls::TlsConfig;
+
+// How connections are served. Both modes share the router and handlers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    // A bounded pool of threads doing blocking I/O, one connection each.
+    // HTTP/2 and WebSocket connections keep their thread for as long as they
+    // are open, streams being served one after another, so only
+    // `max_upgraded` of them are let in at a time.
+    Blocking,
+    // Tasks on a tokio runtime; suited to many idle keep-alive connections.
+    Async,
+}
+
+// What the accept loop does with a connection when every worker is busy and
+// the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    // Answer 503 Service Unavailable with a `Retry-After` hint and close.
+    Reject { retry_after: Duration },
+    // Stop accepting until a worker frees a slot in the queue.
+    Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub mode: Mode,
+    // `workers`, `queue_depth`, `backpressure` and `max_upgraded` only apply
+    // in blocking mode.
+    pub workers: usize,
+    pub queue_depth: usize,
+    pub backpressure: Backpressure,
+    // How many workers HTTP/2 and WebSocket connections may hold at once;
+    // half of them when `None`. Past it, clients stay on HTTP/1.1 and
+    // WebSocket handshakes get 503.
+    pub max_upgraded: Option<usize>,
+    pub keep_alive_timeout: Duration,
+    // How long a write may stall on a client that doesn't read before the
+    // connection is dropped: any connection in blocking mode, freeing its
+    // worker, and WebSocket connections in async mode.
+    pub write_timeout: Duration,
+    pub max_requests_per_connection: usize,
+    // How long in-flight requests get to finish once shutdown starts.
+    pub drain_timeout: Duration,
+    pub limits: Limits,
+    // Serves HTTPS instead of plain HTTP when set.
+    pub tls: Option<TlsConfig>,
+    // Speaks HTTP/2 to clients that ask for it: through ALPN over TLS, and
+    // through `Upgrade: h2c` or the connection preface over plain TCP. In
+    // blocking mode each HTTP/2 connection is served by a single worker.
+    pub http2: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Blocking,
+            workers: thread::available_parallelism().map_or(4, |n| n.get() * 2),
+            queue_depth: 128,
+            backpressure: Backpressure::Reject {
+                retry_after: Duration::from_secs(1),
+            },
+            max_upgraded: None,
+            keep_alive_timeout: Duration::from_secs(5),
+            write_timeout: Duration::from_secs(10),
+            max_requests_per_connection: 100,
+            drain_timeout: Duration::from_secs(10),
+            limits: Limits::default(),
+            tls: None,
+            http2: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    // Defaults overridden by HTTPSERVER_MODE (`blocking` or `async`),
+    // HTTPSERVER_WORKERS, HTTPSERVER_QUEUE_DEPTH, HTTPSERVER_BACKPRESSURE
+    // (`reject` or `block`), HTTPSERVER_MAX_UPGRADED, HTTPSERVER_HTTP2
+    // (`off` disables it) and by the variables of `TlsConfig::from_env`.
+    pub fn from_env() -> Self {
+        let mut config = ServerConfig::default();
+        if env::var("HTTPSERVER_MODE").is_ok_and(|v| v.eq_ignore_ascii_case("async")) {
+            config.mode = Mode::Async;
+        }
+        if let Some(workers) = env_var("HTTPSERVER_WORKERS").filter(|&n| n > 0) {
+            config.workers = workers;
+        }
+        if let Some(queue_depth) = env_var("HTTPSERVER_QUEUE_DEPTH") {
+            config.queue_depth = queue_depth;
+        }
+        if env::var("HTTPSERVER_BACKPRESSURE").is_ok_and(|v| v.eq_ignore_ascii_case("block")) {
+            config.backpressure = Backpressure::Block;
+        }
+        if let Some(max_upgraded) = env_var("HTTPSERVER_MAX_UPGRADED") {
+            config.max_upgraded = Some(max_upgraded);
+        }
+        if env::var("HTTPSERVER_HTTP2").is_ok_and(|v| v.eq_ignore_ascii_case("off")) {
+            config.http2 = false;
+        }
+        config.tls = TlsConfig::from_env();
+        config
+    }
+}
+
+fn env_var(name: &str) -> Option<usize> {
+    env::var(name).ok().and_then(|value| value.parse().ok())
+}
 
 pub struct Server<'a> {
     socket_addr: &'a str,
+    config: ServerConfig,
+    router: Arc<Router>,
+    shutdown: ShutdownHandle,
 }
 
 impl<'a> Server<'a> {
     pub fn new(socket_addr: &'a str) -> Self {
-        Server { socket_addr }
+        Self::with_config(socket_addr, ServerConfig::from_env())
     }
 
-    pub fn run(&self) {
-        let connection_listener = TcpListener::bind(self.socket_addr).unwrap();
-        println!("Running on {}", self.socket_addr);
+    pub fn with_config(socket_addr: &'a str, config: ServerConfig) -> Self {
+        Server {
+            socket_addr,
+            config,
+            router: Arc::new(handler::routes()),
+            shutdown: ShutdownHandle::new(),
+        }
+    }
 
-        for stream in connection_listener.incoming() {
-            let mut stream = stream.unwrap();
-            let mut read_buffer = [0; 90];
-            stream.read(&mut read_buffer).unwrap();
+    // Serves `router` instead of the built-in routes.
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = Arc::new(router);
+        self
+    }
+
+    // A handle that makes `run` return, usable from any thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Serves connections until shutdown is requested through a handle. Fails
+    // only if the server can't start, e.g. the address is taken; errors on
+    // single connections are logged and the rest carry on.
+    pub fn run(&self) -> io::Result<()> {
+        let tls = match &self.config.tls {
+            Some(tls) => Some(tls.server_config(self.config.http2)?),
+            None => None,
+        };
+        let Some(redirect_addr) = self.config.tls.as_ref().and_then(TlsConfig::redirect_addr)
+        else {
+            return self.serve(tls);
+        };
+
+        let port = self
+            .socket_addr
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok());
+        let mut router = Router::new();
+        router.wrap(HttpsRedirect::new(port.unwrap_or(443)));
+        let redirect = Server {
+            socket_addr: redirect_addr,
+            config: ServerConfig {
+                tls: None,
+                ..self.config.clone()
+            },
+            router: Arc::new(router),
+            shutdown: self.shutdown.clone(),
+        };
+        thread::scope(|scope| {
+            let redirecting = scope.spawn(|| {
+                let result = redirect.run();
+                if let Err(e) = &result {
+                    eprintln!("Redirect listener on {} failed: {}", redirect_addr, e);
+                }
+                result
+            });
+            let served = self.serve(tls);
+            // Stops the redirect listener too if this one never started.
+            self.shutdown.shutdown();
+            let redirected = redirecting
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            served.and(redirected)
+        })
+    }
+
+    fn serve(&self, tls: Option<Arc<rustls::ServerConfig>>) -> io::Result<()> {
+        match self.config.mode {
+            Mode::Blocking => self.run_blocking(tls),
+            Mode::Async => {
+                let router = Arc::clone(&self.router);
+                let config = self.config.clone();
+                let shutdown = self.shutdown.clone();
+                async_server::run(self.socket_addr, router, config, shutdown, tls)
+            }
+        }
+    }
+
+    fn run_blocking(&self, tls: Option<Arc<rustls::ServerConfig>>) -> io::Result<()> {
+        let connection_listener = TcpListener::bind(self.socket_addr)?;
+        if !self
+            .shutdown
+            .register_listener(connection_listener.local_addr()?)
+        {
+            return Ok(());
+        }
+        println!(
+            "Running on {} with {} workers",
+            self.socket_addr, self.config.workers
+        );
+
+        let config = Arc::new(self.config.clone());
+        let pool = {
+            let router = Arc::clone(&self.router);
+            let config = Arc::clone(&config);
+            let shutdown = self.shutdown.clone();
+            let tls = tls.map(TlsConfigs::new);
+            let upgrades = UpgradeSlots::new(config.max_upgraded.unwrap_or(config.workers / 2));
+            ThreadPool::new(
+                config.workers,
+                config.queue_depth,
+                move |stream: TcpStream| {
+                    let tls = tls.as_ref();
+                    let served =
+                        handle_connection(stream, &router, &config, &shutdown, tls, &upgrades);
+                    if let Err(e) = served {
+                        eprintln!("Connection error: {}", e);
+                    }
+                },
+            )
+        };
 
-            let req: HttpRequest = String::from_utf8(read_buffer.to_vec())
-                .unwrap()
-                .as_str()
-                .into();
+        for stream in connection_listener.incoming() {
+            if self.shutdown.is_shutting_down() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // Usually out of file descriptors; give connections a
+                    // moment to close instead of spinning.
+                    eprintln!("Accept error: {}", e);
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let rejected = match config.backpressure {
+                Backpressure::Block => pool.submit_blocking(stream).err(),
+                Backpressure::Reject { .. } => pool.submit(stream).err(),
+            };
+            if let Some(stream) = rejected {
+                reject(stream, config.backpressure);
+            }
+        }
 
-            Router::route(req, &mut stream);
+        drop(connection_listener);
+        println!("Shutting down, draining connections");
+        if !pool.shutdown(config.drain_timeout) {
+            eprintln!("Drain timeout expired with requests still in flight");
         }
+        Ok(())
+    }
+}
+
+fn reject(stream: TcpStream, backpressure: Backpressure) {
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, "server busy");
+    if let Backpressure::Reject { retry_after } = backpressure {
+        let seconds = retry_after.as_secs().max(1).to_string();
+        response.headers_mut().insert("Retry-After", seconds);
     }
+    // The accept loop must not hang on a client that doesn't read.
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+    let _ = response.send_response(&mut BufWriter::new(&stream));
 }
@@ -0,0 +1,78 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::watch;
+
+// Asks a running `Server` to stop. The server stops accepting, closes idle
+// keep-alive connections, gives in-flight requests until the drain timeout
+// to finish and then returns from `run`. Clones control the same server.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    signal: watch::Sender<bool>,
+    // Where blocking accept loops listen, so they can be woken up.
+    listeners: Mutex<Vec<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        ShutdownHandle {
+            inner: Arc::new(Inner {
+                signal: watch::Sender::new(false),
+                listeners: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        if self.inner.signal.send_replace(true) {
+            return;
+        }
+        // `accept` has no timeout; a throwaway connection gets each blocking
+        // accept loop to look at the flag.
+        let listeners = self.inner.listeners.lock().unwrap().clone();
+        for addr in listeners {
+            let _ = TcpStream::connect_timeout(&wake_addr(addr), Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.inner.signal.borrow()
+    }
+
+    // False if shutdown has already started, in which case the listener may
+    // never be woken and must not accept. `shutdown` sets the flag before
+    // reading the list, so a listener registered too late to be in it
+    // always sees the flag.
+    #[must_use]
+    pub(crate) fn register_listener(&self, addr: SocketAddr) -> bool {
+        self.inner.listeners.lock().unwrap().push(addr);
+        !self.is_shutting_down()
+    }
+
+    // Resolves once shutdown has been requested.
+    pub(crate) async fn wait(&self) {
+        let mut signal = self.inner.signal.subscribe();
+        let _ = signal.wait_for(|&shutting_down| shutting_down).await;
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => (Ipv4Addr::LOCALHOST, v4.port()).into(),
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => (Ipv6Addr::LOCALHOST, v6.port()).into(),
+        addr => addr,
+    }
+}
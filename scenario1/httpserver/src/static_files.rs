@@ -0,0 +1,763 @@
+use std::{
+    env,
+    fmt::Write as _,
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{
+    httprequest::HttpRequest,
+    httpresponse::{HttpResponse, StatusCode},
+    uri::percent_encode_path,
+};
+
+use crate::cache::FileCache;
+use crate::compression::{self, Encoding};
+use crate::handler::{Handler, HandlerError, RequestContext};
+use crate::mime;
+use crate::sandbox::Sandbox;
+
+// Serves the files under a directory, `PUBLIC_PATH` by default. Mounted on a
+// `{*path}` route it serves that parameter, otherwise the request path.
+//
+// Files get `ETag` and `Last-Modified` and answer conditional requests with
+// 304, single byte ranges with 206. `/page` falls back to `page.html`,
+// directories serve their index file or, if enabled, a listing. Paths are
+// confined to the root by a `Sandbox`, which also hides dotfiles. A `.br` or
+// `.gz` sibling is sent instead of the file to clients that accept it.
+pub struct StaticPageHandler {
+    sandbox: Sandbox,
+    index_files: Vec<String>,
+    listings: bool,
+    precompressed: bool,
+    cache: Option<Arc<FileCache<Vec<u8>>>>,
+}
+
+impl StaticPageHandler {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        StaticPageHandler {
+            sandbox: Sandbox::new(root),
+            index_files: vec!["index.html".to_string()],
+            listings: false,
+            precompressed: true,
+            cache: None,
+        }
+    }
+
+    // File names tried, in order, when a directory is requested.
+    pub fn index_files<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.index_files = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // Lists the contents of directories without an index file.
+    pub fn listings(mut self, enabled: bool) -> Self {
+        self.listings = enabled;
+        self
+    }
+
+    // Looks for `.br` and `.gz` siblings of the files served.
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    // Keeps the contents of the files served in `cache`, which other
+    // handlers may share.
+    pub fn cache(mut self, cache: Arc<FileCache<Vec<u8>>>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // Only files matching one of these patterns are served, see `Sandbox`.
+    pub fn allow<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.sandbox = self.sandbox.allow(patterns);
+        self
+    }
+
+    // Files and directories matching one of these patterns are not served.
+    pub fn deny<S: Into<String>>(mut self, patterns: impl IntoIterator<Item = S>) -> Self {
+        self.sandbox = self.sandbox.deny(patterns);
+        self
+    }
+
+    // Serves files and directories whose names start with a dot.
+    pub fn hidden_files(mut self, serve: bool) -> Self {
+        self.sandbox = self.sandbox.hidden_files(serve);
+        self
+    }
+
+    fn serve_dir(
+        &self,
+        req: &HttpRequest,
+        relative: &str,
+        dir: &Path,
+    ) -> Result<HttpResponse, HandlerError> {
+        // Relative links in the index only resolve with the trailing slash.
+        if !req.path().ends_with('/') {
+            let mut location = format!("{}/", percent_encode_path(req.path()));
+            if let Some(query) = req.resource.uri().and_then(|uri| uri.query.as_ref()) {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Ok(HttpResponse::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header("Location", location)
+                .build());
+        }
+
+        for name in &self.index_files {
+            let Ok(index) = self.sandbox.resolve(&join(relative, name)) else {
+                continue;
+            };
+            if let Ok(meta) = fs::metadata(&index) {
+                if meta.is_file() {
+                    return self.serve_file(req, &join(relative, name), &index, &meta);
+                }
+            }
+        }
+        if self.listings {
+            return listing(req.path(), relative, dir, &self.sandbox);
+        }
+        self.not_found()
+    }
+
+    fn serve_file(
+        &self,
+        req: &HttpRequest,
+        relative: &str,
+        path: &Path,
+        meta: &Metadata,
+    ) -> Result<HttpResponse, HandlerError> {
+        let content_type = mime::from_path(path);
+        let siblings = self.precompressed_siblings(relative);
+        let available: Vec<Encoding> = siblings.iter().map(|(encoding, ..)| *encoding).collect();
+        let encoding = compression::negotiate(req.headers.get("Accept-Encoding"), &available)
+            .ok_or(HandlerError::NotAcceptable)?;
+        // Everything from here on describes the bytes sent, not the original.
+        let (path, meta) = match siblings.iter().find(|(e, ..)| *e == encoding) {
+            Some((_, sibling, sibling_meta)) => (sibling.as_path(), sibling_meta),
+            None => (path, meta),
+        };
+
+        let len = meta.len();
+        let modified = meta.modified().ok();
+        let etag = entity_tag(len, modified);
+        let last_modified = modified.map(httpdate::fmt_http_date);
+
+        let mut builder = HttpResponse::builder()
+            .header("Content-Type", content_type)
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", etag.as_str());
+        if let Some(last_modified) = &last_modified {
+            builder = builder.header("Last-Modified", last_modified.as_str());
+        }
+        if encoding != Encoding::Identity {
+            builder = builder.header("Content-Encoding", encoding.as_str());
+        }
+        if !siblings.is_empty() {
+            builder = builder.header("Vary", "Accept-Encoding");
+        }
+
+        if !modified_since(req, &etag, modified) {
+            return Ok(builder.status(StatusCode::NOT_MODIFIED).build());
+        }
+
+        let mut range = byte_range(req.headers.get("Range"), len);
+        // A range only applies to the representation the client already has.
+        if let Some(if_range) = req.headers.get("If-Range") {
+            if if_range != etag && Some(if_range) != last_modified.as_deref() {
+                range = ByteRange::Full;
+            }
+        }
+
+        match range {
+            ByteRange::Full => match &self.cache {
+                Some(cache) => {
+                    let contents = cache.get_or_load(path, meta, || fs::read(path))?;
+                    Ok(builder.shared_body(contents))
+                }
+                None => Ok(builder.body(fs::read(path)?)),
+            },
+            // Mostly media too large to cache, so always read from disk.
+            ByteRange::Partial(start, end) => {
+                let contents = read_range(path, start, end)?;
+                Ok(builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                    .body(contents))
+            }
+            ByteRange::Unsatisfiable => Ok(HttpResponse::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{}", len))
+                .build()),
+        }
+    }
+
+    // The precompressed files next to the one at `relative`, best first.
+    fn precompressed_siblings(&self, relative: &str) -> Vec<(Encoding, PathBuf, Metadata)> {
+        if !self.precompressed {
+            return Vec::new();
+        }
+        [Encoding::Brotli, Encoding::Gzip]
+            .into_iter()
+            .filter_map(|encoding| {
+                let sibling = format!("{}{}", relative, encoding.extension()?);
+                let path = self.sandbox.resolve(&sibling).ok()?;
+                let meta = fs::metadata(&path).ok().filter(Metadata::is_file)?;
+                Some((encoding, path, meta))
+            })
+            .collect()
+    }
+
+    // The `404.html` page of the root, if it has one.
+    fn not_found(&self) -> Result<HttpResponse, HandlerError> {
+        let page =
+            fs::read(self.sandbox.root().join("404.html")).map_err(|_| HandlerError::NotFound)?;
+        Ok(HttpResponse::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(page))
+    }
+}
+
+impl Default for StaticPageHandler {
+    fn default() -> Self {
+        let default_path = format!("{}/public", env!("CARGO_MANIFEST_DIR"));
+        Self::new(env::var("PUBLIC_PATH").unwrap_or(default_path))
+    }
+}
+
+impl Handler for StaticPageHandler {
+    fn handle(
+        &self,
+        req: &HttpRequest,
+        ctx: &RequestContext,
+    ) -> Result<HttpResponse, HandlerError> {
+        let relative = match ctx.params().get("path") {
+            Some(path) => path,
+            None => req.path().strip_prefix('/').unwrap_or(req.path()),
+        };
+        let path = match self.sandbox.resolve(relative) {
+            Ok(path) => path,
+            Err(HandlerError::NotFound) => return self.not_found(),
+            Err(e) => return Err(e),
+        };
+
+        match fs::metadata(&path) {
+            Ok(meta) if meta.is_dir() => self.serve_dir(req, relative, &path),
+            Ok(meta) => self.serve_file(req, relative, &path, &meta),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !relative.ends_with('/') => {
+                let relative = format!("{}.html", relative);
+                let html = self.sandbox.resolve(&relative);
+                match html.map(|html| (fs::metadata(&html), html)) {
+                    Ok((Ok(meta), html)) if meta.is_file() => {
+                        self.serve_file(req, &relative, &html, &meta)
+                    }
+                    Err(HandlerError::Forbidden) => Err(HandlerError::Forbidden),
+                    _ => self.not_found(),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.not_found(),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// `name` inside the directory at `relative`, both relative to the root.
+fn join(relative: &str, name: &str) -> String {
+    match relative.trim_end_matches('/') {
+        "" => name.to_string(),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+// Derived from size and modification time, so it changes whenever the file
+// does without reading it.
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> String {
+    let nanos = modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    format!("\"{:x}-{:x}\"", len, nanos)
+}
+
+// False when the conditional headers show the client's copy is current.
+// `If-None-Match` takes precedence over `If-Modified-Since`.
+fn modified_since(req: &HttpRequest, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = req.headers.get("If-None-Match") {
+        let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return !if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag));
+    }
+    let since = req
+        .headers
+        .get("If-Modified-Since")
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    match (since, modified) {
+        // HTTP dates have whole seconds; compare at that precision.
+        (Some(since), Some(modified)) => {
+            SystemTime::from(httpdate::HttpDate::from(modified)) > since
+        }
+        _ => true,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    // First and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Interprets a `Range` header for a body of `len` bytes. Anything but a
+// single well-formed byte range is ignored and the whole body served.
+fn byte_range(header: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let number = |s: &str| -> Option<u64> {
+        let s = s.trim();
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    };
+
+    if start.trim().is_empty() {
+        // `bytes=-N` asks for the last N bytes.
+        return match number(end) {
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if len == 0 => ByteRange::Unsatisfiable,
+            Some(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            None => ByteRange::Full,
+        };
+    }
+    let Some(start) = number(start) else {
+        return ByteRange::Full;
+    };
+    let end = if end.trim().is_empty() {
+        None
+    } else {
+        match number(end) {
+            Some(end) if end >= start => Some(end),
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end.map_or(len - 1, |end| end.min(len - 1)))
+}
+
+fn read_range(path: &Path, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut contents = Vec::with_capacity((end - start + 1) as usize);
+    file.take(end - start + 1).read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+// Lists the entries of `dir` the sandbox would serve.
+fn listing(
+    request_path: &str,
+    relative: &str,
+    dir: &Path,
+    sandbox: &Sandbox,
+) -> Result<HttpResponse, HandlerError> {
+    let mut entries: Vec<(String, bool)> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let is_dir = entry.path().is_dir();
+            (entry.file_name().to_string_lossy().into_owned(), is_dir)
+        })
+        .filter(|(name, _)| sandbox.resolve(&join(relative, name)).is_ok())
+        .collect();
+    entries.sort();
+
+    let title = format!("Index of {}", escape_html(request_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body>\n<h1>{0}</h1>\n<ul>\n",
+        title
+    );
+    if request_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        let _ = writeln!(
+            html,
+            "<li><a href=\"{}{}\">{}{}</a></li>",
+            percent_encode_path(&name),
+            slash,
+            escape_html(&name),
+            slash
+        );
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    Ok(HttpResponse::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(html))
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::AppState;
+    use crate::router::{Params, Router};
+
+    // Serves `public` in a temporary directory that also holds a secret.
+    struct Site {
+        dir: tempfile::TempDir,
+    }
+
+    impl Site {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+            let root = dir.path().join("public");
+            fs::create_dir(&root).unwrap();
+            fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+            fs::write(root.join("404.html"), "missing").unwrap();
+            fs::write(root.join("health.html"), "ok").unwrap();
+            fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0xff]).unwrap();
+            fs::create_dir(root.join("docs")).unwrap();
+            fs::write(root.join("docs/a <b>.txt"), "0123456789").unwrap();
+            Site { dir }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.dir.path().join("public")
+        }
+
+        fn get(&self, handler: &StaticPageHandler, raw_head: &str) -> HttpResponse {
+            let raw = format!("{}\r\n\r\n", raw_head);
+            let req = HttpRequest::try_from(raw.as_str()).unwrap();
+            let state = AppState::default();
+            let ctx = RequestContext::new(Params::default(), None, &state);
+            handler
+                .handle(&req, &ctx)
+                .unwrap_or_else(|e| e.into_response())
+        }
+
+        fn handler(&self) -> StaticPageHandler {
+            StaticPageHandler::new(self.root())
+        }
+    }
+
+    #[test]
+    fn test_files_and_fallbacks() {
+        let site = Site::new();
+        let handler = site.handler();
+
+        let index = site.get(&handler, "GET / HTTP/1.1");
+        assert_eq!(StatusCode::OK, index.status());
+        assert_eq!(b"<h1>home</h1>", index.body());
+
+        let health = site.get(&handler, "GET /health HTTP/1.1");
+        assert_eq!(b"ok", health.body());
+
+        let logo = site.get(&handler, "GET /logo.png HTTP/1.1");
+        assert_eq!(Some("image/png"), logo.headers().get("Content-Type"));
+        assert_eq!(&[0x89, b'P', b'N', b'G', 0, 0xff], logo.body());
+
+        let missing = site.get(&handler, "GET /nope.css HTTP/1.1");
+        assert_eq!(StatusCode::NOT_FOUND, missing.status());
+        assert_eq!(b"missing", missing.body());
+    }
+
+    #[test]
+    fn test_directories() {
+        let site = Site::new();
+
+        let redirect = site.get(&site.handler(), "GET /docs?x=1 HTTP/1.1");
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, redirect.status());
+        assert_eq!(Some("/docs/?x=1"), redirect.headers().get("Location"));
+
+        let hidden = site.get(&site.handler(), "GET /docs/ HTTP/1.1");
+        assert_eq!(StatusCode::NOT_FOUND, hidden.status());
+
+        let listed = site.get(&site.handler().listings(true), "GET /docs/ HTTP/1.1");
+        let html = String::from_utf8(listed.body().to_vec()).unwrap();
+        assert!(html.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+        assert!(html.contains("<a href=\"../\">"));
+
+        let custom = site.handler().index_files(["health.html"]);
+        assert_eq!(b"ok", site.get(&custom, "GET / HTTP/1.1").body());
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let site = Site::new();
+        let handler = site.handler();
+        let first = site.get(&handler, "GET /health HTTP/1.1");
+        let etag = first.headers().get("ETag").unwrap();
+        let last_modified = first.headers().get("Last-Modified").unwrap();
+
+        let cached = format!("GET /health HTTP/1.1\r\nIf-None-Match: \"x\", W/{}", etag);
+        let response = site.get(&handler, &cached);
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+        assert_eq!(Some(etag), response.headers().get("ETag"));
+
+        let cached = format!(
+            "GET /health HTTP/1.1\r\nIf-Modified-Since: {}",
+            last_modified
+        );
+        let response = site.get(&handler, &cached);
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        let stale = "GET /health HTTP/1.1\r\nIf-Modified-Since: Mon, 21 Dec 2020 09:00:00 GMT";
+        assert_eq!(StatusCode::OK, site.get(&handler, stale).status());
+
+        let changed = "GET /health HTTP/1.1\r\nIf-None-Match: \"other\"";
+        assert_eq!(StatusCode::OK, site.get(&handler, changed).status());
+    }
+
+    #[test]
+    fn test_range_requests() {
+        let site = Site::new();
+        let handler = site.handler();
+        let path = "GET /docs/a%20%3Cb%3E.txt HTTP/1.1\r\nRange: ";
+
+        let partial = site.get(&handler, &format!("{}bytes=2-4", path));
+        assert_eq!(StatusCode::PARTIAL_CONTENT, partial.status());
+        assert_eq!(b"234", partial.body());
+        assert_eq!(Some("bytes 2-4/10"), partial.headers().get("Content-Range"));
+
+        let suffix = site.get(&handler, &format!("{}bytes=-3", path));
+        assert_eq!(b"789", suffix.body());
+
+        let unsatisfiable = site.get(&handler, &format!("{}bytes=10-", path));
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, unsatisfiable.status());
+        assert_eq!(
+            Some("bytes */10"),
+            unsatisfiable.headers().get("Content-Range")
+        );
+
+        let stale = format!("{}bytes=2-4\r\nIf-Range: \"old\"", path);
+        let full = site.get(&handler, &stale);
+        assert_eq!(StatusCode::OK, full.status());
+        assert_eq!(b"0123456789", full.body());
+    }
+
+    #[test]
+    fn test_precompressed_siblings() {
+        let site = Site::new();
+        let script = b"console.log('hello');\n".repeat(10);
+        fs::write(site.root().join("app.js"), &script).unwrap();
+        let br = compression::compress(Encoding::Brotli, &script).unwrap();
+        fs::write(site.root().join("app.js.br"), &br).unwrap();
+        let gzip = compression::compress(Encoding::Gzip, &script).unwrap();
+        fs::write(site.root().join("app.js.gz"), &gzip).unwrap();
+        let handler = site.handler();
+        let get = |accept: &str| {
+            let head = format!("GET /app.js HTTP/1.1\r\nAccept-Encoding: {}", accept);
+            site.get(&handler, &head)
+        };
+
+        let response = get("gzip, deflate, br");
+        assert_eq!(Some("br"), response.headers().get("Content-Encoding"));
+        assert_eq!(
+            Some("text/javascript; charset=utf-8"),
+            response.headers().get("Content-Type")
+        );
+        assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+        assert_eq!(br, response.body());
+
+        let response = get("gzip;q=1, br;q=0.5");
+        assert_eq!(Some("gzip"), response.headers().get("Content-Encoding"));
+        assert_eq!(gzip, response.body());
+        let gzip_etag = response.headers().get("ETag").unwrap().to_string();
+
+        let response = get("identity");
+        assert_eq!(None, response.headers().get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers().get("Vary"));
+        assert_eq!(script, response.body());
+        assert_ne!(Some(gzip_etag.as_str()), response.headers().get("ETag"));
+        let refused = get("deflate, identity;q=0");
+        assert_eq!(StatusCode::NOT_ACCEPTABLE, refused.status());
+
+        let disabled = site.handler().precompressed(false);
+        let head = "GET /app.js HTTP/1.1\r\nAccept-Encoding: br";
+        let response = site.get(&disabled, head);
+        assert_eq!(None, response.headers().get("Content-Encoding"));
+        assert_eq!(None, response.headers().get("Vary"));
+
+        let health = site.get(&handler, "GET /health HTTP/1.1\r\nAccept-Encoding: br");
+        assert_eq!(None, health.headers().get("Vary"));
+        assert_eq!(b"ok", health.body());
+    }
+
+    #[test]
+    fn test_cached_contents() {
+        let site = Site::new();
+        let cache = Arc::new(FileCache::new(16, 1024));
+        let handler = site.handler().cache(Arc::clone(&cache));
+
+        let first = site.get(&handler, "GET /health HTTP/1.1");
+        let second = site.get(&handler, "GET /health HTTP/1.1");
+        assert_eq!(b"ok", second.body());
+        assert_eq!((1, 1), (cache.stats().hits, cache.stats().misses));
+        // Hits share the cached contents rather than copying them.
+        assert_eq!(first.body().as_ptr(), second.body().as_ptr());
+
+        fs::write(site.root().join("health.html"), "degraded").unwrap();
+        assert_eq!(
+            b"degraded",
+            site.get(&handler, "GET /health HTTP/1.1").body()
+        );
+        assert_eq!((1, 2), (cache.stats().hits, cache.stats().misses));
+
+        // Ranges are read from disk.
+        let range = site.get(&handler, "GET /health HTTP/1.1\r\nRange: bytes=0-2");
+        assert_eq!(b"deg", range.body());
+        assert_eq!(1, cache.stats().entries);
+    }
+
+    #[test]
+    fn test_traversal_is_rejected() {
+        let site = Site::new();
+        let mut router = Router::new();
+        router.get("/{*path}", site.handler());
+        let get = |target: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            router.route(&HttpRequest::try_from(raw.as_str()).unwrap(), None)
+        };
+
+        // Dot segments are resolved against the root before routing.
+        for target in [
+            "/../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/docs/..%2f..%2fsecret.txt",
+        ] {
+            let response = get(target);
+            assert_eq!(StatusCode::NOT_FOUND, response.status(), "{}", target);
+            assert_ne!(b"secret", response.body());
+        }
+        for target in [
+            "//etc/passwd",
+            "/..%5csecret.txt",
+            "/docs/%5c..%5c..%5csecret.txt",
+        ] {
+            assert_eq!(StatusCode::FORBIDDEN, get(target).status(), "{}", target);
+        }
+
+        // A handler called with an unnormalized parameter is still confined.
+        let state = AppState::default();
+        let req = HttpRequest::try_from("GET / HTTP/1.1\r\n\r\n").unwrap();
+        for path in ["../secret.txt", "docs/../../secret.txt", "/etc/passwd"] {
+            let params = [("path", path)].into_iter().collect();
+            let ctx = RequestContext::new(params, None, &state);
+            let result = site.handler().handle(&req, &ctx);
+            assert_eq!(Some(HandlerError::Forbidden), result.err(), "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escape_is_rejected() {
+        let site = Site::new();
+        std::os::unix::fs::symlink(site.dir.path(), site.root().join("up")).unwrap();
+        std::os::unix::fs::symlink(
+            site.dir.path().join("secret.txt"),
+            site.root().join("docs/leak.txt"),
+        )
+        .unwrap();
+        let handler = site.handler().listings(true);
+
+        let leak = site.get(&handler, "GET /docs/leak.txt HTTP/1.1");
+        assert_eq!(StatusCode::FORBIDDEN, leak.status());
+        let up = site.get(&handler, "GET /up/secret.txt HTTP/1.1");
+        assert_eq!(StatusCode::FORBIDDEN, up.status());
+
+        let listed = site.get(&handler, "GET /docs/ HTTP/1.1");
+        let html = String::from_utf8(listed.body().to_vec()).unwrap();
+        assert!(!html.contains("leak.txt"));
+    }
+
+    #[test]
+    fn test_hidden_and_denied_files() {
+        let site = Site::new();
+        fs::write(site.root().join(".env"), "TOKEN=1").unwrap();
+        fs::create_dir(site.root().join(".git")).unwrap();
+        fs::write(site.root().join(".git/config"), "[core]").unwrap();
+        fs::write(site.root().join("docs/notes.bak"), "draft").unwrap();
+        fs::create_dir(site.root().join("private")).unwrap();
+        fs::write(site.root().join("private/index.html"), "keys").unwrap();
+
+        let handler = site.handler().deny(["*.bak", "private"]).listings(true);
+        for target in [
+            "/.env",
+            "/.git/config",
+            "/%2egit/config",
+            "/docs/notes.bak",
+            "/docs/notes",
+            "/private/",
+            "/private/index.html",
+        ] {
+            let response = site.get(&handler, &format!("GET {} HTTP/1.1", target));
+            assert_eq!(StatusCode::NOT_FOUND, response.status(), "{}", target);
+            assert_eq!(b"missing", response.body(), "{}", target);
+        }
+
+        let root = site.get(&handler.index_files(Vec::<String>::new()), "GET / HTTP/1.1");
+        let html = String::from_utf8(root.body().to_vec()).unwrap();
+        assert!(html.contains("docs/"));
+        assert!(!html.contains(".env") && !html.contains(".git") && !html.contains("private"));
+
+        let dotfiles = site.handler().hidden_files(true);
+        assert_eq!(b"TOKEN=1", site.get(&dotfiles, "GET /.env HTTP/1.1").body());
+
+        let allowed = site.handler().allow(["*.html", "*.png"]);
+        assert_eq!(
+            StatusCode::OK,
+            site.get(&allowed, "GET /logo.png HTTP/1.1").status()
+        );
+        assert_eq!(
+            b"<h1>home</h1>",
+            site.get(&allowed, "GET / HTTP/1.1").body()
+        );
+        let text = site.get(&allowed, "GET /docs/a%20%3Cb%3E.txt HTTP/1.1");
+        assert_eq!(StatusCode::NOT_FOUND, text.status());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        use ByteRange::*;
+        assert_eq!(Full, byte_range(None, 10));
+        assert_eq!(Partial(0, 9), byte_range(Some("bytes=0-"), 10));
+        assert_eq!(Partial(5, 9), byte_range(Some("bytes=5-100"), 10));
+        assert_eq!(Partial(0, 9), byte_range(Some("bytes=-20"), 10));
+        assert_eq!(Unsatisfiable, byte_range(Some("bytes=-0"), 10));
+        assert_eq!(Unsatisfiable, byte_range(Some("bytes=0-"), 0));
+        assert_eq!(Full, byte_range(Some("bytes=0-1,4-5"), 10));
+        assert_eq!(Full, byte_range(Some("bytes=5-2"), 10));
+        assert_eq!(Full, byte_range(Some("bytes=+1-2"), 10));
+        assert_eq!(Full, byte_range(Some("items=0-1"), 10));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// A fixed set of worker threads fed through a bounded queue. Every job is
+// passed to the same handler; a panic inside the handler only loses that
+// job, the worker carries on with the next one.
+pub struct ThreadPool<T: Send + 'static> {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<SyncSender<T>>,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    pub fn new<F>(size: usize, queue_depth: usize, handler: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "a thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let handler = Arc::clone(&handler);
+                thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || work(id, &receiver, &*handler))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    // Queues a job without waiting, handing it back when the queue is full.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        match self.sender().try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Err(job),
+        }
+    }
+
+    // Queues a job, waiting for room in the queue if necessary.
+    pub fn submit_blocking(&self, job: T) -> Result<(), T> {
+        self.sender().send(job).map_err(|e| e.0)
+    }
+
+    // Closes the queue and waits up to `timeout` for the workers to finish
+    // what is queued. Returns false if some were still busy; they are left to
+    // finish on their own.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        drop(self.sender.take());
+        let deadline = Instant::now() + timeout;
+        while self.workers.iter().any(|worker| !worker.is_finished()) {
+            if Instant::now() >= deadline {
+                self.workers.clear();
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    fn sender(&self) -> &SyncSender<T> {
+        self.sender
+            .as_ref()
+            .expect("sender is only taken on shutdown or drop")
+    }
+}
+
+impl<T: Send + 'static> Drop for ThreadPool<T> {
+    // Closing the queue lets the workers finish what is queued and exit.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work<T>(id: usize, receiver: &Mutex<Receiver<T>>, handler: &dyn Fn(T)) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(poisoned) => poisoned.into_inner().recv(),
+        };
+        let Ok(job) = job else {
+            return;
+        };
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(job))).is_err() {
+            eprintln!("Worker {} recovered from a panicking job", id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_panicking_job_keeps_worker_alive() {
+        let (done, finished) = channel();
+        let done = Mutex::new(done);
+        let pool = ThreadPool::new(1, 4, move |job: u32| {
+            if job == 0 {
+                panic!("job failed");
+            }
+            done.lock().unwrap().send(job).unwrap();
+        });
+        pool.submit(0).unwrap();
+        pool.submit(1).unwrap();
+        assert_eq!(Ok(1), finished.recv_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_full_queue_hands_job_back() {
+        let (release, blocked) = channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let (started, running) = channel();
+        let started = Mutex::new(started);
+        let pool = ThreadPool::new(1, 1, move |job: u32| {
+            started.lock().unwrap().send(job).unwrap();
+            let _ = blocked.lock().unwrap().recv();
+        });
+        pool.submit(1).unwrap();
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.submit(2).unwrap();
+        assert_eq!(Err(3), pool.submit(3));
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_queued_jobs() {
+        let (done, finished) = channel();
+        let done = Mutex::new(done);
+        let pool = ThreadPool::new(2, 8, move |job: u32| {
+            thread::sleep(Duration::from_millis(20));
+            done.lock().unwrap().send(job).unwrap();
+        });
+        for job in 0..4 {
+            pool.submit(job).unwrap();
+        }
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(4, finished.try_iter().count());
+    }
+
+    #[test]
+    fn test_shutdown_gives_up_after_timeout() {
+        let pool = ThreadPool::new(1, 1, |_: u32| thread::sleep(Duration::from_secs(1)));
+        pool.submit(0).unwrap();
+        assert!(!pool.shutdown(Duration::from_millis(50)));
+    }
+}
@@ -0,0 +1,615 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use crate::cache::Stamp;
+
+// HTTPS for `Server`: certificates and keys from PEM files, picked by the
+// name the client asks for (SNI) and reloaded when the files change.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    default: Identity,
+    hosts: Vec<(String, Identity)>,
+    redirect_from: Option<String>,
+    reload_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Identity {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TlsConfig {
+    // Serves the chain in `cert`, leaf first, with the private key in `key`
+    // to clients that send no name or one without a certificate of its own.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            default: Identity {
+                cert: cert.into(),
+                key: key.into(),
+            },
+            hosts: Vec::new(),
+            redirect_from: None,
+            reload_interval: Duration::from_secs(1),
+        }
+    }
+
+    // HTTPSERVER_TLS_CERT and HTTPSERVER_TLS_KEY, plus HTTPSERVER_HTTPS_REDIRECT
+    // for the address of a redirecting plain listener. `None` unless both
+    // files are given.
+    pub fn from_env() -> Option<Self> {
+        let cert = env::var_os("HTTPSERVER_TLS_CERT")?;
+        let key = env::var_os("HTTPSERVER_TLS_KEY")?;
+        let mut config = TlsConfig::new(cert, key);
+        if let Ok(addr) = env::var("HTTPSERVER_HTTPS_REDIRECT") {
+            config = config.redirect_from(addr);
+        }
+        Some(config)
+    }
+
+    // Serves `cert` and `key` to clients asking for `host`. A host starting
+    // with `*.` covers a single label, `*.example.com` matches
+    // `api.example.com` but not `example.com`.
+    pub fn host(
+        mut self,
+        host: impl Into<String>,
+        cert: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) -> Self {
+        let identity = Identity {
+            cert: cert.into(),
+            key: key.into(),
+        };
+        self.hosts
+            .push((host.into().to_ascii_lowercase(), identity));
+        self
+    }
+
+    // Also listens for plain HTTP on `addr`, answering every request with a
+    // redirect to the same URL over HTTPS.
+    pub fn redirect_from(mut self, addr: impl Into<String>) -> Self {
+        self.redirect_from = Some(addr.into());
+        self
+    }
+
+    // How often handshakes look at the files for changes. A certificate that
+    // fails to load is logged and the previous one kept.
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    pub fn redirect_addr(&self) -> Option<&str> {
+        self.redirect_from.as_deref()
+    }
+
+    // Loads every certificate, failing if any of them doesn't. With `http2`
+    // ALPN offers h2 ahead of HTTP/1.1.
+    pub(crate) fn server_config(&self, http2: bool) -> io::Result<Arc<rustls::ServerConfig>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let hosts = self
+            .hosts
+            .iter()
+            .map(|(host, identity)| Ok((host.clone(), Slot::new(identity, &provider)?)))
+            .collect::<io::Result<_>>()?;
+        let resolver = CertResolver {
+            default: Slot::new(&self.default, &provider)?,
+            hosts,
+            reload_interval: self.reload_interval,
+            checked: Mutex::new(Instant::now()),
+            provider: Arc::clone(&provider),
+        };
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        if http2 {
+            config.alpn_protocols.insert(0, b"h2".to_vec());
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+#[derive(Debug)]
+struct CertResolver {
+    default: Slot,
+    hosts: Vec<(String, Slot)>,
+    reload_interval: Duration,
+    checked: Mutex<Instant>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl CertResolver {
+    fn slot(&self, server_name: Option<&str>) -> &Slot {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return &self.default;
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        let find = |host: &str| self.hosts.iter().find(|(h, _)| h == host);
+        find(&name)
+            .or_else(|| wildcard.as_deref().and_then(find))
+            .map_or(&self.default, |(_, slot)| slot)
+    }
+
+    // At most once per interval; a handshake that finds another one
+    // checking goes ahead with the current certificates.
+    fn reload_changed(&self) {
+        let Ok(mut checked) = self.checked.try_lock() else {
+            return;
+        };
+        if checked.elapsed() < self.reload_interval {
+            return;
+        }
+        *checked = Instant::now();
+        let hosts = self.hosts.iter().map(|(_, slot)| slot);
+        for slot in std::iter::once(&self.default).chain(hosts) {
+            slot.reload_if_changed(&self.provider);
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.reload_changed();
+        let slot = self.slot(client_hello.server_name());
+        Some(Arc::clone(&slot.loaded.read().unwrap().key))
+    }
+}
+
+// A certificate and key pair as last loaded from its files.
+#[derive(Debug)]
+struct Slot {
+    identity: Identity,
+    loaded: RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    stamps: [Option<Stamp>; 2],
+}
+
+impl Slot {
+    fn new(identity: &Identity, provider: &CryptoProvider) -> io::Result<Self> {
+        let stamps = identity.stamps();
+        let key = Arc::new(identity.load(provider)?);
+        Ok(Slot {
+            identity: identity.clone(),
+            loaded: RwLock::new(Loaded { key, stamps }),
+        })
+    }
+
+    // Files are often replaced one after the other, so a pair that doesn't
+    // load yet is tried again once either file changes once more.
+    fn reload_if_changed(&self, provider: &CryptoProvider) {
+        let stamps = self.identity.stamps();
+        if stamps == self.loaded.read().unwrap().stamps {
+            return;
+        }
+        let reloaded = self.identity.load(provider);
+        let mut loaded = self.loaded.write().unwrap();
+        loaded.stamps = stamps;
+        match reloaded {
+            Ok(key) => {
+                println!("Reloaded certificate {}", self.identity.cert.display());
+                loaded.key = Arc::new(key);
+            }
+            Err(e) => eprintln!("Keeping the previous certificate: {}", e),
+        }
+    }
+}
+
+impl Identity {
+    fn stamps(&self) -> [Option<Stamp>; 2] {
+        let stamp = |path: &Path| fs::metadata(path).ok().map(|meta| Stamp::of(&meta));
+        [stamp(&self.cert), stamp(&self.key)]
+    }
+
+    fn load(&self, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+        let context = |path: &Path| {
+            let path = path.display().to_string();
+            move |e: rustls::pki_types::pem::Error| invalid_data(format!("{}: {}", path, e))
+        };
+        let chain = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(context(&self.cert))?;
+        if chain.is_empty() {
+            let message = format!("{}: no certificates", self.cert.display());
+            return Err(invalid_data(message));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(context(&self.key))?;
+        CertifiedKey::from_der(chain, key, provider).map_err(|e| {
+            invalid_data(format!(
+                "{} and {}: {}",
+                self.cert.display(),
+                self.key.display(),
+                e
+            ))
+        })
+    }
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::RequestContext;
+    use crate::router::Router;
+    use crate::server::{Mode, Server, ServerConfig};
+    use http::httprequest::HttpRequest;
+    use http::httpresponse::{HttpResponse, StatusCode};
+    use rustls::pki_types::ServerName;
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    struct Cert {
+        pem: String,
+        der: CertificateDer<'static>,
+        key: String,
+    }
+
+    fn cert(names: &[&str]) -> Cert {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let certified = rcgen::generate_simple_self_signed(names).unwrap();
+        Cert {
+            pem: certified.cert.pem(),
+            der: certified.cert.der().clone(),
+            key: certified.key_pair.serialize_pem(),
+        }
+    }
+
+    fn write(dir: &Path, name: &str, cert: &Cert) -> (PathBuf, PathBuf) {
+        let paths = (
+            dir.join(format!("{}.crt", name)),
+            dir.join(format!("{}.key", name)),
+        );
+        fs::write(&paths.0, &cert.pem).unwrap();
+        fs::write(&paths.1, &cert.key).unwrap();
+        paths
+    }
+
+    fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    // Runs a server answering "ok" until the returned handle is dropped.
+    struct Running {
+        addr: String,
+        shutdown: crate::shutdown::ShutdownHandle,
+        thread: Option<thread::JoinHandle<io::Result<()>>>,
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.shutdown.shutdown();
+            let _ = self.thread.take().unwrap().join();
+        }
+    }
+
+    fn start(mode: Mode, tls: TlsConfig) -> Running {
+        let addr = free_addr();
+        let config = ServerConfig {
+            mode,
+            workers: 2,
+            drain_timeout: Duration::from_millis(100),
+            tls: Some(tls),
+            ..ServerConfig::default()
+        };
+        let mut router = Router::new();
+        router.get("/{*path}", |_: &HttpRequest, _: &RequestContext| {
+            Ok(HttpResponse::builder().status(StatusCode::OK).body("ok"))
+        });
+        let server_addr = addr.clone();
+        let (started, ready) = std::sync::mpsc::channel();
+        let thread = thread::spawn(move || {
+            let server = Server::with_config(&server_addr, config).router(router);
+            started.send(server.shutdown_handle()).unwrap();
+            server.run()
+        });
+        let shutdown = ready.recv().unwrap();
+        for _ in 0..100 {
+            if TcpStream::connect(&addr).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Running {
+            addr,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    fn connect(
+        addr: &str,
+        name: &str,
+        roots: &[&Cert],
+        alpn: &[&[u8]],
+    ) -> io::Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>> {
+        let mut store = rustls::RootCertStore::empty();
+        for root in roots {
+            store.add(root.der.clone()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        let server_name = ServerName::try_from(name.to_string()).unwrap();
+        let connection = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+        Ok(rustls::StreamOwned::new(
+            connection,
+            TcpStream::connect(addr)?,
+        ))
+    }
+
+    // Fetches `/` over HTTPS as `name`, trusting only `roots`. Returns the
+    // certificate the server presented and the response.
+    fn get(
+        addr: &str,
+        name: &str,
+        roots: &[&Cert],
+    ) -> io::Result<(CertificateDer<'static>, String)> {
+        let mut stream = connect(addr, name, roots, &[])?;
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            name
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let presented = stream.conn.peer_certificates().unwrap()[0].clone();
+        Ok((presented, response))
+    }
+
+    #[test]
+    fn test_sni() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = cert(&["localhost"]);
+        let api = cert(&["api.example.test"]);
+        let shop = cert(&["*.shop.test"]);
+        let (cert_path, key_path) = write(dir.path(), "default", &fallback);
+        let (api_cert, api_key) = write(dir.path(), "api", &api);
+        let (shop_cert, shop_key) = write(dir.path(), "shop", &shop);
+        let tls = TlsConfig::new(cert_path, key_path)
+            .host("API.example.test", api_cert, api_key)
+            .host("*.shop.test", shop_cert, shop_key);
+
+        for mode in [Mode::Blocking, Mode::Async] {
+            let server = start(mode, tls.clone());
+            let roots = [&fallback, &api, &shop];
+
+            let (presented, response) = get(&server.addr, "localhost", &roots).unwrap();
+            assert_eq!(fallback.der, presented);
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert!(response.ends_with("\r\n\r\nok"));
+            let (presented, _) = get(&server.addr, "api.example.test", &roots).unwrap();
+            assert_eq!(api.der, presented);
+            let (presented, _) = get(&server.addr, "eu.shop.test", &roots).unwrap();
+            assert_eq!(shop.der, presented);
+            // Names without their own certificate get the default one, which
+            // the client rejects.
+            assert!(get(&server.addr, "shop.test", &roots).is_err());
+
+            // Plain HTTP on the HTTPS port fails that connection only.
+            let mut plain = TcpStream::connect(&server.addr).unwrap();
+            plain.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            let _ = plain.read(&mut [0; 64]);
+            assert!(get(&server.addr, "localhost", &roots).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_alpn_h2() {
+        let dir = tempfile::tempdir().unwrap();
+        let localhost = cert(&["localhost"]);
+        let (cert_path, key_path) = write(dir.path(), "server", &localhost);
+        let tls = TlsConfig::new(cert_path, key_path);
+
+        for mode in [Mode::Blocking, Mode::Async] {
+            let server = start(mode, tls.clone());
+            let protocols: [&[u8]; 2] = [b"h2", b"http/1.1"];
+            let mut stream = connect(&server.addr, "localhost", &[&localhost], &protocols).unwrap();
+
+            let mut block = Vec::new();
+            let fields = [
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":authority", "localhost"),
+                (":path", "/"),
+            ];
+            http::hpack::Encoder::default().encode(fields, &mut block);
+            let mut request = http::h2::PREFACE.to_vec();
+            request.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+            request.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+            request.extend_from_slice(&[0x1, 0x5, 0, 0, 0, 1]);
+            request.extend_from_slice(&block);
+            stream.write_all(&request).unwrap();
+            assert_eq!(Some(&b"h2"[..]), stream.conn.alpn_protocol());
+
+            // Reads frames until stream 1 ends, keeping its HEADERS and DATA.
+            let mut decoder = http::hpack::Decoder::default();
+            let (mut status, mut body) = (None, Vec::new());
+            loop {
+                let mut head = [0; 9];
+                stream.read_exact(&mut head).unwrap();
+                let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+                let mut payload = vec![0; len];
+                stream.read_exact(&mut payload).unwrap();
+                let (kind, flags, id) = (head[3], head[4], head[8]);
+                if kind == 0x1 && id == 1 {
+                    let fields = decoder.decode(&payload, usize::MAX).unwrap();
+                    status = Some(fields[0].clone());
+                }
+                if kind == 0x0 && id == 1 {
+                    body.extend_from_slice(&payload);
+                }
+                if matches!(kind, 0x0 | 0x1) && flags & 0x1 != 0 {
+                    break;
+                }
+            }
+            assert_eq!(Some((b":status".to_vec(), b"200".to_vec())), status);
+            assert_eq!(b"ok".to_vec(), body);
+
+            // With two workers, blocking mode lets one connection upgrade,
+            // and no longer offers h2 while it stays.
+            if mode == Mode::Blocking {
+                let mut second =
+                    connect(&server.addr, "localhost", &[&localhost], &protocols).unwrap();
+                write!(
+                    second,
+                    "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+                let mut response = String::new();
+                second.read_to_string(&mut response).unwrap();
+                assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+                assert_eq!(Some(&b"http/1.1"[..]), second.conn.alpn_protocol());
+            }
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = cert(&["localhost"]);
+        let (cert_path, key_path) = write(dir.path(), "server", &first);
+        let tls = TlsConfig::new(&cert_path, &key_path).reload_interval(Duration::ZERO);
+        let server = start(Mode::Blocking, tls);
+        let second = cert(&["localhost", "127.0.0.1"]);
+        let roots = [&first, &second];
+
+        assert_eq!(first.der, get(&server.addr, "localhost", &roots).unwrap().0);
+
+        // A half-written pair keeps the old certificate.
+        fs::write(&cert_path, &second.pem).unwrap();
+        assert_eq!(first.der, get(&server.addr, "localhost", &roots).unwrap().0);
+
+        fs::write(&key_path, &second.key).unwrap();
+        assert_eq!(
+            second.der,
+            get(&server.addr, "localhost", &roots).unwrap().0
+        );
+
+        fs::write(&cert_path, "not a certificate").unwrap();
+        assert_eq!(
+            second.der,
+            get(&server.addr, "localhost", &roots).unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = cert(&["localhost"]);
+        let other = cert(&["localhost"]);
+        let (cert_path, key_path) = write(dir.path(), "server", &first);
+        let (_, other_key) = write(dir.path(), "other", &other);
+
+        assert!(TlsConfig::new(&cert_path, &key_path)
+            .server_config(true)
+            .is_ok());
+        let missing = TlsConfig::new(dir.path().join("missing.crt"), &key_path);
+        assert!(missing.server_config(true).is_err());
+        let mismatched = TlsConfig::new(&cert_path, &other_key).server_config(true);
+        assert_eq!(io::ErrorKind::InvalidData, mismatched.unwrap_err().kind());
+        let empty = dir.path().join("empty.crt");
+        fs::write(&empty, "").unwrap();
+        assert!(TlsConfig::new(&empty, &key_path)
+            .server_config(true)
+            .is_err());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = write(dir.path(), "server", &cert(&["localhost"]));
+        let redirect_addr = free_addr();
+        let tls = TlsConfig::new(cert_path, key_path).redirect_from(&redirect_addr);
+        let server = start(Mode::Blocking, tls);
+        let port = server.addr.rsplit_once(':').unwrap().1;
+
+        let mut plain = TcpStream::connect(&redirect_addr).unwrap();
+        plain
+            .write_all(b"POST /api/orders?page=2 HTTP/1.1\r\nHost: example.test:8080\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        plain.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 308 Permanent Redirect"),
+            "{}",
+            response
+        );
+        let location = format!(
+            "\r\nLocation: https://example.test:{}/api/orders?page=2\r\n",
+            port
+        );
+        assert!(response.contains(&location), "{}", response);
+    }
+
+    #[test]
+    fn test_redirect_with_port_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = write(dir.path(), "server", &cert(&["localhost"]));
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        let tls = TlsConfig::new(&cert_path, &key_path).redirect_from(free_addr());
+        let config = ServerConfig {
+            tls: Some(tls),
+            ..ServerConfig::default()
+        };
+
+        // Fails rather than waiting on the redirect listener, whichever of
+        // them gets going first.
+        let (done, result) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let _ = done.send(Server::with_config(&addr, config).run());
+        });
+        let result = result.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(io::ErrorKind::AddrInUse, result.unwrap_err().kind());
+
+        // The same goes for a shutdown requested before either listener is
+        // up, as a Ctrl-C during startup would.
+        let tls = TlsConfig::new(&cert_path, &key_path).redirect_from(free_addr());
+        let config = ServerConfig {
+            tls: Some(tls),
+            ..ServerConfig::default()
+        };
+        let addr = free_addr();
+        let (done, result) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let server = Server::with_config(&addr, config);
+            server.shutdown_handle().shutdown();
+            let _ = done.send(server.run());
+        });
+        let result = result.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,457 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use http::httprequest::HttpRequest;
+use http::httpresponse::HttpResponse;
+use http::websocket::{self, Codec, HandshakeError, Role, GOING_AWAY, POLICY_VIOLATION};
+pub use http::websocket::{CloseFrame, Message};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::handler::{Handler, HandlerError, RequestContext};
+
+// Serves the WebSocket connections of a route, see `Router::websocket`.
+// Callbacks run on the connection's thread; `WebSocket` handles can be
+// kept and used from anywhere to send to their client later.
+pub trait WebSocketHandler: Send + Sync {
+    // The handshake is done and `socket` is ready to send.
+    fn on_open(&self, _socket: &WebSocket, _ctx: &RequestContext) {}
+
+    // A text or binary message, reassembled if it came in fragments.
+    fn on_message(&self, _socket: &WebSocket, _message: Message) {}
+
+    // The connection is gone, closed by either side or lost.
+    fn on_close(&self, _socket: &WebSocket) {}
+}
+
+// How many messages may wait for a client. One that falls this far behind
+// is disconnected rather than buffered for without limit.
+pub const MAX_QUEUED_MESSAGES: usize = 256;
+
+// Sends to one client. Messages are queued and written by the connection,
+// so sending never blocks.
+#[derive(Debug, Clone)]
+pub struct WebSocket {
+    id: u64,
+    sender: Sender<Message>,
+    // Set when the queue was full, which closes the connection.
+    overflowed: Arc<AtomicBool>,
+}
+
+impl WebSocket {
+    // Unique among the sockets of this process.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // False once the connection is gone, or is going because the client
+    // has `MAX_QUEUED_MESSAGES` waiting already.
+    pub fn send(&self, message: Message) -> bool {
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.overflowed.store(true, Ordering::Relaxed);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    pub fn send_text(&self, text: impl Into<String>) -> bool {
+        self.send(Message::Text(text.into()))
+    }
+
+    // Starts the close handshake; nothing more is sent after it.
+    pub fn close(&self, code: u16, reason: &str) -> bool {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_string(),
+        };
+        self.send(Message::Close(Some(frame)))
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.sender.is_closed() && !self.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+// The sockets a message goes out to together, e.g. every browser watching
+// the same data.
+#[derive(Debug, Clone, Default)]
+pub struct Broadcast {
+    sockets: Arc<Mutex<Vec<WebSocket>>>,
+}
+
+impl Broadcast {
+    pub fn subscribe(&self, socket: &WebSocket) {
+        self.sockets.lock().unwrap().push(socket.clone());
+    }
+
+    pub fn unsubscribe(&self, socket: &WebSocket) {
+        self.sockets.lock().unwrap().retain(|s| s.id != socket.id);
+    }
+
+    // Sends `message` to every subscriber, dropping the ones whose
+    // connection is gone or that fell behind. Returns how many it went to.
+    pub fn send(&self, message: Message) -> usize {
+        let mut sockets = self.sockets.lock().unwrap();
+        sockets.retain(|socket| socket.send(message.clone()));
+        sockets.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sockets.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The GET handler of a WebSocket route: answers the opening handshake, the
+// connection switching over once the 101 is sent.
+pub(crate) struct Handshake;
+
+impl Handler for Handshake {
+    fn handle(
+        &self,
+        req: &HttpRequest,
+        _ctx: &RequestContext,
+    ) -> Result<HttpResponse, HandlerError> {
+        match websocket::handshake(req) {
+            Ok(response) => Ok(response),
+            Err(e @ HandshakeError::InvalidKey) => Err(HandlerError::BadRequest(e.to_string())),
+            Err(e) => {
+                let mut response = HandlerError::UpgradeRequired(e.to_string()).respond(req);
+                let headers = response.headers_mut();
+                headers.insert("Upgrade", "websocket");
+                headers.insert("Sec-WebSocket-Version", websocket::VERSION);
+                Ok(response)
+            }
+        }
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// One WebSocket connection after the handshake, without the I/O: what the
+// client sends goes into `received`, what to write comes out of
+// `take_output`. The client gets a ping after going quiet, and the close
+// handshake is completed before the connection is dropped.
+pub(crate) struct Session {
+    codec: Codec,
+    handler: Arc<dyn WebSocketHandler>,
+    socket: WebSocket,
+    outgoing: Receiver<Message>,
+    output: Vec<u8>,
+    close_sent: bool,
+    close_received: bool,
+    ping_sent: bool,
+    // The queue overflowed and the client was told so.
+    overflowed: bool,
+}
+
+impl Session {
+    // `received` is what the client sent right after the handshake.
+    pub(crate) fn open(
+        handler: Arc<dyn WebSocketHandler>,
+        ctx: &RequestContext,
+        max_message_size: usize,
+        received: &[u8],
+    ) -> Self {
+        let (sender, outgoing) = mpsc::channel(MAX_QUEUED_MESSAGES);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let socket = WebSocket {
+            id,
+            sender,
+            overflowed: Arc::new(AtomicBool::new(false)),
+        };
+        let mut session = Session {
+            codec: Codec::new(Role::Server).max_message_size(max_message_size),
+            handler,
+            socket,
+            outgoing,
+            output: Vec::new(),
+            close_sent: false,
+            close_received: false,
+            ping_sent: false,
+            overflowed: false,
+        };
+        session.handler.on_open(&session.socket, ctx);
+        session.received(received);
+        session
+    }
+
+    pub(crate) fn received(&mut self, data: &[u8]) {
+        self.codec.feed(data);
+        self.ping_sent = false;
+        loop {
+            match self.codec.next_message() {
+                Ok(Some(message)) => self.dispatch(message),
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Closing WebSocket connection: {}", e);
+                    self.send(Message::Close(Some(CloseFrame {
+                        code: e.code,
+                        reason: e.reason.to_string(),
+                    })));
+                    // Nothing more the client sends can be read.
+                    self.close_received = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Sends whatever the handler queued since the last call, then closes
+    // the connection if the queue overflowed in the meantime.
+    pub(crate) fn flush_outgoing(&mut self) {
+        while let Ok(message) = self.outgoing.try_recv() {
+            self.send(message);
+        }
+        if self.socket.overflowed.load(Ordering::Relaxed) {
+            self.send(Message::Close(Some(CloseFrame {
+                code: POLICY_VIOLATION,
+                reason: "too many messages queued".to_string(),
+            })));
+            self.overflowed = true;
+        }
+    }
+
+    // The handler queued more than `MAX_QUEUED_MESSAGES`. The connection is
+    // dropped once the close frame saying so is written, or fails to be.
+    pub(crate) fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    // Waits for the handler to queue a message, for the async server, which
+    // then calls `flush_outgoing` for the rest.
+    pub(crate) async fn next_outgoing(&mut self) -> Option<Message> {
+        self.outgoing.recv().await
+    }
+
+    pub(crate) fn send(&mut self, message: Message) {
+        if self.close_sent {
+            return;
+        }
+        self.close_sent = matches!(message, Message::Close(_));
+        self.codec.encode(&message, &mut self.output);
+    }
+
+    pub(crate) fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    // The client has been quiet for the keep-alive timeout. Returns false
+    // once it's been too long, the ping sent the time before unanswered.
+    pub(crate) fn idle(&mut self) -> bool {
+        if self.ping_sent || self.close_sent {
+            return false;
+        }
+        self.send(Message::Ping(Vec::new()));
+        self.ping_sent = true;
+        true
+    }
+
+    pub(crate) fn going_away(&mut self) {
+        self.send(Message::Close(Some(CloseFrame {
+            code: GOING_AWAY,
+            reason: "server shutting down".to_string(),
+        })));
+    }
+
+    // Both sides have sent their close frame.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.close_sent && self.close_received
+    }
+
+    fn dispatch(&mut self, message: Message) {
+        match message {
+            Message::Ping(data) => self.send(Message::Pong(data)),
+            Message::Pong(_) => {}
+            Message::Close(frame) => {
+                self.close_received = true;
+                // Echoes the client's code, as the close handshake expects.
+                let echo = frame.map(|frame| CloseFrame {
+                    code: frame.code,
+                    reason: String::new(),
+                });
+                self.send(Message::Close(echo));
+            }
+            // After a close frame, messages are no longer delivered.
+            message if !self.close_sent => self.handler.on_message(&self.socket, message),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.outgoing.close();
+        self.handler.on_close(&self.socket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::AppState;
+    use crate::router::Params;
+
+    // Echoes text back and closes when told to.
+    struct Echo;
+
+    impl WebSocketHandler for Echo {
+        fn on_open(&self, socket: &WebSocket, _: &RequestContext) {
+            socket.send_text("hello");
+        }
+
+        fn on_message(&self, socket: &WebSocket, message: Message) {
+            match message {
+                Message::Text(text) if text == "bye" => {
+                    socket.close(1000, "done");
+                }
+                message => {
+                    socket.send(message);
+                }
+            }
+        }
+    }
+
+    fn client_frames(messages: &[Message]) -> Vec<u8> {
+        let mut client = Codec::new(Role::Client);
+        let mut out = Vec::new();
+        for message in messages {
+            client.encode(message, &mut out);
+        }
+        out
+    }
+
+    fn server_messages(output: &[u8]) -> Vec<Message> {
+        let mut client = Codec::new(Role::Client);
+        client.feed(output);
+        std::iter::from_fn(|| client.next_message().unwrap()).collect()
+    }
+
+    fn open(handler: impl WebSocketHandler + 'static) -> Session {
+        let state = AppState::default();
+        let ctx = RequestContext::new(Params::default(), None, &state);
+        Session::open(Arc::new(handler), &ctx, 1024, &[])
+    }
+
+    #[test]
+    fn test_session() {
+        let mut session = open(Echo);
+        let input = client_frames(&[
+            Message::Text("one".into()),
+            Message::Ping(b"p".to_vec()),
+            Message::Text("bye".into()),
+        ]);
+        session.received(&input);
+        session.flush_outgoing();
+        let close = Message::Close(Some(CloseFrame {
+            code: 1000,
+            reason: "done".into(),
+        }));
+        assert_eq!(
+            vec![
+                Message::Pong(b"p".to_vec()),
+                Message::Text("hello".into()),
+                Message::Text("one".into()),
+                close,
+            ],
+            server_messages(&session.take_output())
+        );
+        assert!(!session.is_closed());
+        session.received(&client_frames(&[Message::Close(None)]));
+        assert!(session.is_closed());
+        assert!(session.take_output().is_empty());
+    }
+
+    #[test]
+    fn test_session_close_and_errors() {
+        // A client-initiated close is echoed.
+        let mut session = open(Echo);
+        let close = CloseFrame {
+            code: 1001,
+            reason: "leaving".into(),
+        };
+        session.received(&client_frames(&[Message::Close(Some(close))]));
+        assert!(session.is_closed());
+        let echo = CloseFrame {
+            code: 1001,
+            reason: String::new(),
+        };
+        assert_eq!(
+            vec![Message::Close(Some(echo))],
+            server_messages(&session.take_output())
+        );
+
+        // Too big for the limit.
+        let mut session = open(Echo);
+        session.received(&client_frames(&[Message::Binary(vec![0; 2048])]));
+        assert!(session.is_closed());
+        let messages = server_messages(&session.take_output());
+        assert!(matches!(&messages[0], Message::Close(Some(f)) if f.code == 1009));
+
+        // A quiet client gets one ping, then the connection is given up.
+        let mut session = open(Echo);
+        assert!(session.idle());
+        assert!(!session.idle());
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let broadcast = Broadcast::default();
+        let socket = |id, capacity| {
+            let (sender, receiver) = mpsc::channel(capacity);
+            let overflowed = Arc::new(AtomicBool::new(false));
+            let socket = WebSocket {
+                id,
+                sender,
+                overflowed,
+            };
+            (socket, receiver)
+        };
+        let (a, _a) = socket(1, 8);
+        let (b, b_receiver) = socket(2, 8);
+        let (c, _c) = socket(3, 1);
+        broadcast.subscribe(&a);
+        broadcast.subscribe(&b);
+        broadcast.subscribe(&c);
+        assert_eq!(3, broadcast.send(Message::Text("x".into())));
+        drop(b_receiver);
+        // `c` is full and disconnected, `b` is gone.
+        assert_eq!(1, broadcast.send(Message::Text("y".into())));
+        assert!(!c.is_open());
+        broadcast.unsubscribe(&a);
+        assert!(broadcast.is_empty());
+    }
+
+    #[test]
+    fn test_session_overflow() {
+        // Holds on to the socket to send from outside, as a feed would.
+        #[derive(Clone, Default)]
+        struct Keep(Arc<Mutex<Option<WebSocket>>>);
+        impl WebSocketHandler for Keep {
+            fn on_open(&self, socket: &WebSocket, _: &RequestContext) {
+                *self.0.lock().unwrap() = Some(socket.clone());
+            }
+        }
+
+        let keep = Keep::default();
+        let mut session = open(keep.clone());
+        let socket = keep.0.lock().unwrap().clone().unwrap();
+        for _ in 0..MAX_QUEUED_MESSAGES {
+            assert!(socket.send_text("x"));
+        }
+        assert!(!socket.send_text("one too many"));
+        session.flush_outgoing();
+        let messages = server_messages(&session.take_output());
+        assert_eq!(MAX_QUEUED_MESSAGES + 1, messages.len());
+        let close = &messages[MAX_QUEUED_MESSAGES];
+        assert!(matches!(close, Message::Close(Some(f)) if f.code == 1008));
+        assert!(session.overflowed());
+    }
+}
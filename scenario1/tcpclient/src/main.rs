@@ -5,10 +5,10 @@ use std::str;
 fn main() {
     let mut stream = TcpStream::connect("127.0.0.1:3000").unwrap();
 
-    stream.write("Hello".as_bytes()).unwrap();
+    stream.write_all("Hello".as_bytes()).unwrap();
 
     let mut buffer = [0; 5];
-    stream.read(&mut buffer).unwrap();
+    stream.read_exact(&mut buffer).unwrap();
 
     let response = str::from_utf8(&buffer).unwrap();
     println!("Got response from server {:?}", response);
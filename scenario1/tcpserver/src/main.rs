@@ -8,7 +8,7 @@ fn main() {
         let mut stream = stream.unwrap();
         println!("connection established");
         let mut buffer = [0; 1024];
-        stream.read(&mut buffer).unwrap();
-        stream.write(&mut buffer).unwrap();
+        let read = stream.read(&mut buffer).unwrap();
+        stream.write_all(&buffer[..read]).unwrap();
     }
 }